@@ -10,8 +10,9 @@ use safe_transmute::{
     transmute_many, transmute_many_pedantic, transmute_many_permissive, transmute_one,
     transmute_one_pedantic, try_copy,
 };
+use serum_dex::fees::{FeeDiscounts, FeeSchedule};
 use serum_dex::instruction::{MarketInstruction, NewOrderInstruction};
-use serum_dex::matching::{OrderType, Side};
+use serum_dex::matching::{BookFullPolicy, OrderType, Side};
 use serum_dex::state::gen_vault_signer_key;
 use serum_dex::state::Event;
 use serum_dex::state::EventQueueHeader;
@@ -426,6 +427,7 @@ struct MarketPubkeys {
     event_q: Box<Pubkey>,
     bids: Box<Pubkey>,
     asks: Box<Pubkey>,
+    trigger_book: Box<Pubkey>,
     coin_vault: Box<Pubkey>,
     pc_vault: Box<Pubkey>,
     vault_signer_key: Box<Pubkey>,
@@ -484,6 +486,9 @@ fn get_keys_for_market<'a>(
         event_q: Box::new(Pubkey::new(transmute_one_to_bytes(&market_state.event_q))),
         bids: Box::new(Pubkey::new(transmute_one_to_bytes(&market_state.bids))),
         asks: Box::new(Pubkey::new(transmute_one_to_bytes(&market_state.asks))),
+        trigger_book: Box::new(Pubkey::new(transmute_one_to_bytes(
+            &market_state.trigger_book,
+        ))),
         coin_vault: Box::new(Pubkey::new(transmute_one_to_bytes(
             &market_state.coin_vault,
         ))),
@@ -1018,6 +1023,7 @@ fn list_market(
         event_q_key,
         bids_key,
         asks_key,
+        trigger_book_key,
         vault_signer_pk,
         vault_signer_nonce,
     } = listing_keys;
@@ -1039,10 +1045,16 @@ fn list_market(
         &asks_key.pubkey(),
         &req_q_key.pubkey(),
         &event_q_key.pubkey(),
+        &trigger_book_key.pubkey(),
+        &payer.pubkey(),
         coin_lot_size,
         pc_lot_size,
         vault_signer_nonce,
         100,
+        FeeSchedule::default(),
+        FeeDiscounts::default(),
+        BookFullPolicy::EvictWorstPrice,
+        0,
     )?;
     println!(
         "initialize_market_instruction: {:#?}",
@@ -1059,6 +1071,7 @@ fn list_market(
         &event_q_key,
         &bids_key,
         &asks_key,
+        &trigger_book_key,
         &req_q_key,
         &event_q_key,
     ];
@@ -1081,6 +1094,7 @@ fn list_market(
         event_q: Box::new(event_q_key.pubkey()),
         bids: Box::new(bids_key.pubkey()),
         asks: Box::new(asks_key.pubkey()),
+        trigger_book: Box::new(trigger_book_key.pubkey()),
         coin_vault: Box::new(coin_vault.pubkey()),
         pc_vault: Box::new(pc_vault.pubkey()),
         vault_signer_key: Box::new(vault_signer_pk),
@@ -1093,6 +1107,7 @@ struct ListingKeys {
     event_q_key: Keypair,
     bids_key: Keypair,
     asks_key: Keypair,
+    trigger_book_key: Keypair,
     vault_signer_pk: Pubkey,
     vault_signer_nonce: u64,
 }
@@ -1104,11 +1119,14 @@ fn gen_listing_params(
     coin_mint: &Pubkey,
     pc_mint: &Pubkey,
 ) -> Result<(ListingKeys, Vec<Instruction>)> {
-    let (market_key, create_market) = create_dex_account(client, program_id, payer, 368)?;
+    let (market_key, create_market) =
+        create_dex_account(client, program_id, payer, size_of::<MarketState>())?;
     let (req_q_key, create_req_q) = create_dex_account(client, program_id, payer, 640)?;
     let (event_q_key, create_event_q) = create_dex_account(client, program_id, payer, 1 << 20)?;
     let (bids_key, create_bids) = create_dex_account(client, program_id, payer, 1 << 16)?;
     let (asks_key, create_asks) = create_dex_account(client, program_id, payer, 1 << 16)?;
+    let (trigger_book_key, create_trigger_book) =
+        create_dex_account(client, program_id, payer, 1 << 16)?;
     let (vault_signer_nonce, vault_signer_pk) = {
         let mut i = 0;
         loop {
@@ -1125,6 +1143,7 @@ fn gen_listing_params(
         event_q_key,
         bids_key,
         asks_key,
+        trigger_book_key,
         vault_signer_pk,
         vault_signer_nonce,
     };
@@ -1134,6 +1153,7 @@ fn gen_listing_params(
         create_event_q,
         create_bids,
         create_asks,
+        create_trigger_book,
     ];
     Ok((info, instructions))
 }
@@ -1176,6 +1196,8 @@ fn match_orders(
             AccountMeta::new(*state.asks, false),
             AccountMeta::new(*coin_wallet, false),
             AccountMeta::new(*pc_wallet, false),
+            AccountMeta::new_readonly(solana_sdk::sysvar::clock::ID, false),
+            AccountMeta::new(*state.trigger_book, false),
         ],
         data: instruction_data,
     };
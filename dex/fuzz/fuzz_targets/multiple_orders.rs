@@ -375,6 +375,8 @@ fn run_action<'bump>(
                 market_accounts.asks.clone(),
                 market_accounts.coin_vault.clone(),
                 market_accounts.pc_vault.clone(),
+                market_accounts.clock_sysvar.clone(),
+                market_accounts.trigger_book.clone(),
             ],
             &MarketInstruction::MatchOrders(limit).pack(),
         )
@@ -56,6 +56,10 @@ fn fuzz_place_order(data: SingleOrder) {
         ],
         &MarketInstruction::NewOrder(data.instruction.clone()).pack(),
     );
+    let order_rests = match data.instruction.order_type {
+        OrderType::Limit | OrderType::PostOnly => true,
+        OrderType::ImmediateOrCancel | OrderType::FillOrKill => false,
+    };
     if !data.correct_payer_account {
         assert!(place_order_result.is_err());
     } else if data.instruction.side == Side::Ask {
@@ -79,7 +83,7 @@ fn fuzz_place_order(data: SingleOrder) {
         }
     }
 
-    if place_order_result.is_ok() && data.instruction.order_type != OrderType::ImmediateOrCancel {
+    if place_order_result.is_ok() && order_rests {
         let (orders, _) = strip_header::<OpenOrders, u8>(&orders_account, false).unwrap();
         // println!("{:?}", orders);
         if data.instruction.side == Side::Bid {
@@ -105,12 +109,14 @@ fn fuzz_place_order(data: SingleOrder) {
             market_accounts.asks.clone(),
             coin_account.clone(),
             pc_account.clone(),
+            market_accounts.clock_sysvar.clone(),
+            market_accounts.trigger_book.clone(),
         ],
         &MarketInstruction::MatchOrders(5).pack(),
     )
     .unwrap();
 
-    if place_order_result.is_ok() && data.instruction.order_type != OrderType::ImmediateOrCancel {
+    if place_order_result.is_ok() && order_rests {
         let (orders, _) = strip_header::<OpenOrders, u8>(&orders_account, false).unwrap();
         if data.instruction.side == Side::Bid {
             assert_eq!(orders.native_coin_free, 0);
@@ -138,7 +144,7 @@ fn fuzz_place_order(data: SingleOrder) {
     )
     .unwrap();
 
-    if place_order_result.is_ok() && data.instruction.order_type != OrderType::ImmediateOrCancel {
+    if place_order_result.is_ok() && order_rests {
         let (orders, _) = strip_header::<OpenOrders, u8>(&orders_account, false).unwrap();
         // println!("{:?}", orders);
         if data.instruction.side == Side::Bid {
@@ -165,7 +171,7 @@ fn fuzz_place_order(data: SingleOrder) {
         &MarketInstruction::CancelOrderByClientId(data.instruction.client_id).pack(),
     );
 
-    if place_order_result.is_ok() && data.instruction.order_type != OrderType::ImmediateOrCancel {
+    if place_order_result.is_ok() && order_rests {
         assert!(cancel_order_result.is_ok());
     } else {
         assert!(cancel_order_result.is_err())
@@ -181,6 +187,8 @@ fn fuzz_place_order(data: SingleOrder) {
             market_accounts.asks.clone(),
             coin_account.clone(),
             pc_account.clone(),
+            market_accounts.clock_sysvar.clone(),
+            market_accounts.trigger_book.clone(),
         ],
         &MarketInstruction::MatchOrders(5).pack(),
     )
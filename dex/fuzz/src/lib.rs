@@ -4,7 +4,7 @@ use bumpalo::Bump;
 use safe_transmute::to_bytes::{transmute_to_bytes, transmute_to_bytes_mut};
 use solana_sdk::account_info::AccountInfo;
 use solana_sdk::bpf_loader;
-use solana_sdk::clock::Epoch;
+use solana_sdk::clock::{Clock, Epoch};
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::rent::Rent;
 use solana_sdk::system_program;
@@ -15,7 +15,9 @@ use spl_token::state::Account as SplAccount;
 use spl_token::state::Mint;
 
 use serum_dex::error::DexResult;
+use serum_dex::fees::{FeeDiscounts, FeeSchedule};
 use serum_dex::instruction::initialize_market;
+use serum_dex::matching::BookFullPolicy;
 use serum_dex::state::{
     gen_vault_signer_key, strip_header, EventQueue, MarketState, Queue, RequestQueue, State,
 };
@@ -141,6 +143,22 @@ fn new_rent_sysvar_account(lamports: u64, rent: Rent, bump: &Bump) -> AccountInf
     account_info
 }
 
+fn new_clock_sysvar_account(lamports: u64, clock: Clock, bump: &Bump) -> AccountInfo {
+    let data = bump.alloc_slice_fill_copy(size_of::<Clock>(), 0u8);
+    let mut account_info = AccountInfo::new(
+        &sysvar::clock::ID,
+        false,
+        false,
+        bump.alloc(lamports),
+        data,
+        &sysvar::ID,
+        false,
+        Epoch::default(),
+    );
+    clock.to_account_info(&mut account_info).unwrap();
+    account_info
+}
+
 fn new_vault_signer_account<'bump>(
     market: &AccountInfo,
     program_id: &Pubkey,
@@ -172,6 +190,7 @@ pub struct MarketAccounts<'bump> {
     pub event_q: AccountInfo<'bump>,
     pub bids: AccountInfo<'bump>,
     pub asks: AccountInfo<'bump>,
+    pub trigger_book: AccountInfo<'bump>,
     pub coin_vault: AccountInfo<'bump>,
     pub pc_vault: AccountInfo<'bump>,
     pub coin_mint: AccountInfo<'bump>,
@@ -179,6 +198,7 @@ pub struct MarketAccounts<'bump> {
     pub vault_signer: AccountInfo<'bump>,
     pub spl_token_program: AccountInfo<'bump>,
     pub rent_sysvar: AccountInfo<'bump>,
+    pub clock_sysvar: AccountInfo<'bump>,
 }
 
 pub const COIN_LOT_SIZE: u64 = 100_000;
@@ -192,11 +212,14 @@ pub fn setup_market(bump: &Bump) -> MarketAccounts {
     let asks = new_dex_owned_account(1 << 16, program_id, bump);
     let req_q = new_dex_owned_account(640, program_id, bump);
     let event_q = new_dex_owned_account(65536, program_id, bump);
+    let trigger_book = new_dex_owned_account(65536, program_id, bump);
+    let authority = new_sol_account(0, bump);
 
     let coin_mint = new_token_mint(bump);
     let pc_mint = new_token_mint(bump);
 
     let rent_sysvar = new_rent_sysvar_account(100000, Rent::default(), bump);
+    let clock_sysvar = new_clock_sysvar_account(100000, Clock::default(), bump);
 
     let (vault_signer_nonce, vault_signer) = new_vault_signer_account(&market, program_id, bump);
 
@@ -221,10 +244,16 @@ pub fn setup_market(bump: &Bump) -> MarketAccounts {
         asks.key,
         req_q.key,
         event_q.key,
+        trigger_book.key,
+        authority.key,
         coin_lot_size,
         pc_lot_size,
         vault_signer_nonce,
         pc_dust_threshold,
+        FeeSchedule::default(),
+        FeeDiscounts::default(),
+        BookFullPolicy::EvictWorstPrice,
+        0,
     )
     .unwrap();
 
@@ -240,6 +269,8 @@ pub fn setup_market(bump: &Bump) -> MarketAccounts {
             pc_vault.clone(),
             coin_mint.clone(),
             pc_mint.clone(),
+            trigger_book.clone(),
+            authority.clone(),
         ],
         &init_instruction.data,
     )
@@ -251,6 +282,7 @@ pub fn setup_market(bump: &Bump) -> MarketAccounts {
         event_q,
         bids,
         asks,
+        trigger_book,
         coin_vault,
         pc_vault,
         coin_mint,
@@ -258,6 +290,7 @@ pub fn setup_market(bump: &Bump) -> MarketAccounts {
         vault_signer,
         spl_token_program,
         rent_sysvar,
+        clock_sysvar,
     }
 }
 
@@ -1,9 +1,13 @@
 use crate::{
     error::{DexErrorCode, DexResult},
     fees::FeeTier,
+    matching::ExpiryKind,
 };
 use arrayref::{array_refs, mut_array_refs};
-use bytemuck::{cast, cast_mut, cast_ref, cast_slice, cast_slice_mut, Pod, Zeroable};
+use bytemuck::{
+    cast, cast_mut, cast_ref, cast_slice, cast_slice_mut, try_cast_slice, try_from_bytes, Pod,
+    Zeroable,
+};
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use static_assertions::const_assert_eq;
@@ -11,6 +15,7 @@ use std::{
     convert::TryFrom,
     mem::{align_of, size_of},
     num::NonZeroU64,
+    ops::RangeInclusive,
 };
 
 pub type NodeHandle = u32;
@@ -32,7 +37,7 @@ struct InnerNode {
     prefix_len: u32,
     key: u128,
     children: [u32; 2],
-    _padding: [u64; 5],
+    _padding: [u64; 8],
 }
 unsafe impl Zeroable for InnerNode {}
 unsafe impl Pod for InnerNode {}
@@ -51,11 +56,15 @@ pub struct LeafNode {
     tag: u32,
     owner_slot: u8,
     fee_tier: u8,
-    padding: [u8; 2],
+    expiry_kind: u8,
+    padding: [u8; 1],
     key: u128,
     owner: [u64; 4],
     quantity: u64,
     client_order_id: u64,
+    expiry: u64,
+    display_quantity: u64,
+    hidden_quantity: u64,
 }
 unsafe impl Zeroable for LeafNode {}
 unsafe impl Pod for LeafNode {}
@@ -74,11 +83,39 @@ impl LeafNode {
             tag: NodeTag::LeafNode.into(),
             owner_slot,
             fee_tier: fee_tier.into(),
-            padding: [0; 2],
+            expiry_kind: ExpiryKind::Never.into(),
+            padding: [0; 1],
             key: *key,
             owner: *owner,
             quantity,
             client_order_id,
+            expiry: 0,
+            display_quantity: 0,
+            hidden_quantity: 0,
+        }
+    }
+
+    #[inline]
+    pub fn with_expiry(self, expiry_kind: ExpiryKind, expiry: u64) -> Self {
+        LeafNode {
+            expiry_kind: expiry_kind.into(),
+            expiry,
+            ..self
+        }
+    }
+
+    /// Turns the order into an iceberg that only shows `display_quantity`
+    /// at a time. Orders no larger than their display slice stay as they are.
+    #[inline]
+    pub fn with_display_quantity(self, display_quantity: Option<NonZeroU64>) -> Self {
+        match display_quantity {
+            Some(display) if display.get() < self.quantity => LeafNode {
+                quantity: display.get(),
+                display_quantity: display.get(),
+                hidden_quantity: self.quantity - display.get(),
+                ..self
+            },
+            _ => self,
         }
     }
 
@@ -102,9 +139,52 @@ impl LeafNode {
         self.quantity
     }
 
+    /// The part of an iceberg order that is not shown on the book.
+    #[inline]
+    pub fn hidden_quantity(&self) -> u64 {
+        self.hidden_quantity
+    }
+
+    /// Visible and hidden quantity together, i.e. everything that can match.
+    #[inline]
+    pub fn total_quantity(&self) -> u64 {
+        self.quantity + self.hidden_quantity
+    }
+
+    /// Removes `quantity` from the order, eating into the hidden remainder
+    /// once the visible slice is exhausted.
+    #[inline]
+    pub fn take_quantity(&mut self, quantity: u64) {
+        let from_visible = quantity.min(self.quantity);
+        self.quantity -= from_visible;
+        self.hidden_quantity -= quantity - from_visible;
+    }
+
+    /// Shrinks the order to `new_quantity` without touching its key, taking
+    /// from the hidden remainder first so the visible slice keeps its place.
     #[inline]
-    pub fn quantity_mut(&mut self) -> &mut u64 {
-        &mut self.quantity
+    pub fn reduce_to(&mut self, new_quantity: u64) {
+        debug_assert!(new_quantity <= self.total_quantity());
+        let cut = self.total_quantity() - new_quantity;
+        let from_hidden = cut.min(self.hidden_quantity);
+        self.hidden_quantity -= from_hidden;
+        self.quantity -= cut - from_hidden;
+    }
+
+    #[inline]
+    pub fn needs_refill(&self) -> bool {
+        self.quantity == 0 && self.hidden_quantity > 0
+    }
+
+    /// Shows the next slice of an iceberg order under a new order id, which
+    /// puts it behind everything already resting at its price.
+    #[inline]
+    pub fn refill(&mut self, new_order_id: u128) {
+        debug_assert_eq!(new_order_id >> 64, self.key >> 64);
+        let slice = self.display_quantity.min(self.hidden_quantity);
+        self.quantity += slice;
+        self.hidden_quantity -= slice;
+        self.key = new_order_id;
     }
 
     #[inline]
@@ -121,6 +201,43 @@ impl LeafNode {
     pub fn client_order_id(&self) -> u64 {
         self.client_order_id
     }
+
+    #[inline]
+    pub fn expiry_kind(&self) -> ExpiryKind {
+        ExpiryKind::try_from_primitive(self.expiry_kind).unwrap()
+    }
+
+    #[inline]
+    pub fn expiry(&self) -> u64 {
+        self.expiry
+    }
+
+    /// Hash of the parts of the order a book subscriber sees. The slab keeps
+    /// the wrapping sum of these over all of its leaves.
+    pub fn checksum(&self) -> u64 {
+        let words = [
+            self.key as u64,
+            (self.key >> 64) as u64,
+            self.owner[0],
+            self.owner[1],
+            self.owner[2],
+            self.owner[3],
+            self.owner_slot as u64,
+            self.quantity,
+            self.hidden_quantity,
+        ];
+        words
+            .iter()
+            .fold(0x9e37_79b9_7f4a_7c15, |hash, &word| mix64(hash ^ word))
+    }
+}
+
+// the splitmix64 finalizer
+#[inline]
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
 }
 
 #[derive(Copy, Clone)]
@@ -128,7 +245,7 @@ impl LeafNode {
 struct FreeNode {
     tag: u32,
     next: u32,
-    _padding: [u64; 8],
+    _padding: [u64; 11],
 }
 unsafe impl Zeroable for FreeNode {}
 unsafe impl Pod for FreeNode {}
@@ -141,7 +258,7 @@ const fn _const_max(a: usize, b: usize) -> usize {
 const _INNER_NODE_SIZE: usize = size_of::<InnerNode>();
 const _LEAF_NODE_SIZE: usize = size_of::<LeafNode>();
 const _FREE_NODE_SIZE: usize = size_of::<FreeNode>();
-const _NODE_SIZE: usize = 72;
+const _NODE_SIZE: usize = 96;
 
 const _INNER_NODE_ALIGN: usize = align_of::<InnerNode>();
 const _LEAF_NODE_ALIGN: usize = align_of::<LeafNode>();
@@ -160,7 +277,7 @@ const_assert_eq!(_NODE_ALIGN, _FREE_NODE_ALIGN);
 #[repr(C, align(8))]
 pub struct AnyNode {
     tag: u32,
-    padding: [u32; 17],
+    padding: [u32; 23],
 }
 unsafe impl Zeroable for AnyNode {}
 unsafe impl Pod for AnyNode {}
@@ -183,7 +300,6 @@ impl AnyNode {
         }
     }
 
-    #[cfg(test)]
     fn prefix_len(&self) -> u32 {
         match self.case().unwrap() {
             NodeRef::Inner(&InnerNode { prefix_len, .. }) => prefix_len,
@@ -214,6 +330,14 @@ impl AnyNode {
         }
     }
 
+    #[inline]
+    pub fn as_leaf(&self) -> Option<&LeafNode> {
+        match self.case() {
+            Some(NodeRef::Leaf(leaf_ref)) => Some(leaf_ref),
+            _ => None,
+        }
+    }
+
     #[inline]
     pub fn as_leaf_mut(&mut self) -> Option<&mut LeafNode> {
         match self.case_mut() {
@@ -247,6 +371,8 @@ struct SlabHeader {
 
     root_node: u32,
     leaf_count: u64,
+    checksum: u64,
+    _padding: u64,
 }
 unsafe impl Zeroable for SlabHeader {}
 unsafe impl Pod for SlabHeader {}
@@ -291,6 +417,21 @@ impl Slab {
         slab
     }
 
+    /// Views the bytes of a slab read-only, e.g. account data fetched by a
+    /// client or borrowed immutably on-chain. Returns `None` if the bytes are
+    /// too short to hold the header or not aligned for the nodes.
+    #[inline]
+    pub fn from_bytes(bytes: &[u8]) -> Option<&Self> {
+        let len_without_header = bytes.len().checked_sub(SLAB_HEADER_LEN)?;
+        let slop = len_without_header % size_of::<AnyNode>();
+        let truncated_len = bytes.len() - slop;
+        let bytes = &bytes[..truncated_len];
+        let (header_bytes, nodes_bytes) = array_refs![bytes, SLAB_HEADER_LEN; .. ;];
+        try_from_bytes::<SlabHeader>(header_bytes).ok()?;
+        try_cast_slice::<u8, AnyNode>(nodes_bytes).ok()?;
+        Some(unsafe { &*(bytes as *const [u8] as *const Slab) })
+    }
+
     #[inline]
     pub fn assert_minimum_capacity(&self, capacity: u32) -> DexResult {
         if self.nodes().len() <= (capacity as usize) * 2 {
@@ -377,6 +518,8 @@ impl SlabView<AnyNode> for Slab {
 
             root_node: 0,
             leaf_count: 0,
+            checksum: 0,
+            _padding: 0,
         }
     }
 
@@ -489,7 +632,11 @@ impl Slab {
     }
 
     fn find_min_max(&self, find_max: bool) -> Option<NodeHandle> {
-        let mut root: NodeHandle = self.root()?;
+        Some(self.find_min_max_under(self.root()?, find_max))
+    }
+
+    fn find_min_max_under(&self, sub_root: NodeHandle, find_max: bool) -> NodeHandle {
+        let mut root = sub_root;
         loop {
             let root_contents = self.get(root).unwrap();
             match root_contents.case().unwrap() {
@@ -497,9 +644,75 @@ impl Slab {
                     root = children[if find_max { 1 } else { 0 }];
                     continue;
                 }
-                _ => return Some(root),
+                _ => return root,
+            }
+        }
+    }
+
+    /// Finds the leaf with the smallest key at least `search_key`, or the
+    /// largest key at most `search_key` if `descending`.
+    fn find_from(&self, search_key: u128, descending: bool) -> Option<NodeHandle> {
+        let mut node_handle: NodeHandle = self.root()?;
+        // the closest subtree past `search_key` that the walk turned away from
+        let mut fallback: Option<NodeHandle> = None;
+        loop {
+            let node_ref = self.get(node_handle).unwrap();
+            let node_prefix_len = node_ref.prefix_len();
+            let node_key = node_ref.key().unwrap();
+            let common_prefix_len = (search_key ^ node_key).leading_zeros();
+            if common_prefix_len < node_prefix_len {
+                // every key in this subtree is on the same side of search_key
+                let node_bit = node_key & ((1u128 << 127) >> common_prefix_len) != 0;
+                if node_bit != descending {
+                    return Some(self.find_min_max_under(node_handle, descending));
+                }
+                break;
+            }
+            match node_ref.case().unwrap() {
+                NodeRef::Leaf(_) => return Some(node_handle),
+                NodeRef::Inner(inner) => {
+                    let (child, crit_bit) = inner.walk_down(&search_key);
+                    if crit_bit == descending {
+                        fallback = Some(inner.children[!crit_bit as usize]);
+                    }
+                    node_handle = child;
+                }
             }
         }
+        fallback.map(|sub_root| self.find_min_max_under(sub_root, descending))
+    }
+
+    /// Iterates over the leaves in ascending key order, which is price-time
+    /// priority for asks. Allocation-free and double-ended.
+    pub fn iter(&self) -> LeafIter {
+        LeafIter {
+            slab: self,
+            descending: false,
+            lo: 0,
+            hi: std::u128::MAX,
+            exhausted: false,
+        }
+    }
+
+    /// Iterates in ascending key order over the leaves priced within
+    /// `prices`, starting and ending with a walk down the tree rather than a
+    /// scan from either end of the book.
+    pub fn range(&self, prices: RangeInclusive<u64>) -> LeafIter {
+        let (lo_price, hi_price) = prices.into_inner();
+        LeafIter {
+            lo: (lo_price as u128) << 64,
+            hi: ((hi_price as u128) << 64) | std::u64::MAX as u128,
+            ..self.iter()
+        }
+    }
+
+    /// Iterates over the leaves in descending key order, which is price-time
+    /// priority for bids.
+    pub fn iter_desc(&self) -> LeafIter {
+        LeafIter {
+            descending: true,
+            ..self.iter()
+        }
     }
 
     #[inline]
@@ -525,6 +738,7 @@ impl Slab {
                     Ok(handle) => {
                         self.header_mut().root_node = handle;
                         self.header_mut().leaf_count = 1;
+                        self.header_mut().checksum = new_leaf.checksum();
                         return Ok((handle, None));
                     }
                     Err(()) => return Err(SlabTreeError::OutOfSpace),
@@ -539,6 +753,7 @@ impl Slab {
                 if let Some(NodeRef::Leaf(&old_root_as_leaf)) = root_contents.case() {
                     // clobber the existing leaf
                     *self.get_mut(root).unwrap() = *new_leaf.as_ref();
+                    self.replace_checksum(old_root_as_leaf.checksum(), new_leaf.checksum());
                     return Ok((root, Some(old_root_as_leaf)));
                 }
             }
@@ -583,12 +798,12 @@ impl Slab {
             new_root.children[new_leaf_crit_bit as usize] = new_leaf_handle;
             new_root.children[old_root_crit_bit as usize] = moved_root_handle;
             self.header_mut().leaf_count += 1;
+            self.replace_checksum(0, new_leaf.checksum());
             return Ok((new_leaf_handle, None));
         }
     }
 
-    #[cfg(test)]
-    fn find_by_key(&self, search_key: &u128) -> Option<NodeHandle> {
+    pub fn find_by_key(&self, search_key: &u128) -> Option<NodeHandle> {
         let mut node_handle: NodeHandle = self.root()?;
         loop {
             let node_ref = self.get(node_handle).unwrap();
@@ -621,6 +836,7 @@ impl Slab {
                 assert_eq!(header.leaf_count, 1);
                 header.root_node = 0;
                 header.leaf_count = 0;
+                header.checksum = 0;
                 let _old_root = self.remove(parent_h).unwrap();
                 return Some(leaf);
             }
@@ -655,7 +871,43 @@ impl Slab {
         let other_child_node_contents = self.remove(other_child_h).unwrap();
         *self.get_mut(parent_h).unwrap() = other_child_node_contents;
         self.header_mut().leaf_count -= 1;
-        Some(cast(self.remove(child_h).unwrap()))
+        let leaf: LeafNode = cast(self.remove(child_h).unwrap());
+        self.replace_checksum(leaf.checksum(), 0);
+        Some(leaf)
+    }
+
+    /// Applies `f` to the leaf at `h`, keeping the book checksum in step with
+    /// whatever it changes. The key must stay the same.
+    pub fn update_leaf<T>(
+        &mut self,
+        h: NodeHandle,
+        f: impl FnOnce(&mut LeafNode) -> T,
+    ) -> Option<T> {
+        let leaf = self.get_mut(h)?.as_leaf_mut()?;
+        let (old_key, old_checksum) = (leaf.key, leaf.checksum());
+        let result = f(leaf);
+        debug_assert_eq!(leaf.key, old_key);
+        let new_checksum = leaf.checksum();
+        self.replace_checksum(old_checksum, new_checksum);
+        Some(result)
+    }
+
+    /// Order-independent hash of every leaf in the slab, for clients to check
+    /// that their copy of the book is in sync.
+    #[inline]
+    pub fn checksum(&self) -> u64 {
+        self.header().checksum
+    }
+
+    #[inline]
+    pub fn leaf_count(&self) -> u64 {
+        self.header().leaf_count
+    }
+
+    #[inline]
+    fn replace_checksum(&mut self, old: u64, new: u64) {
+        let header = self.header_mut();
+        header.checksum = header.checksum.wrapping_sub(old).wrapping_add(new);
     }
 
     #[inline]
@@ -668,6 +920,21 @@ impl Slab {
         self.remove_by_key(&self.get(self.find_max()?)?.key()?)
     }
 
+    /// Visits the leaves in key order, or in reverse key order if `descending`,
+    /// until `f` returns `false`.
+    pub(crate) fn walk_leaves(&self, descending: bool, mut f: impl FnMut(&LeafNode) -> bool) {
+        let leaves = if descending {
+            self.iter_desc()
+        } else {
+            self.iter()
+        };
+        for leaf in leaves {
+            if !f(leaf) {
+                return;
+            }
+        }
+    }
+
     #[cfg(test)]
     fn traverse(&self) -> Vec<&LeafNode> {
         fn walk_rec<'a>(slab: &'a Slab, sub_root: NodeHandle, buf: &mut Vec<&'a LeafNode>) {
@@ -695,10 +962,16 @@ impl Slab {
 
     #[cfg(test)]
     fn hexdump(&self) {
+        fn dump(bytes: &[u8]) {
+            for (i, line) in bytes.chunks(16).enumerate() {
+                let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{:08x}  {}", i * 16, hex.join(" "));
+            }
+        }
         println!("Header:");
-        hexdump::hexdump(bytemuck::bytes_of(self.header()));
+        dump(bytemuck::bytes_of(self.header()));
         println!("Data:");
-        hexdump::hexdump(cast_slice(self.nodes()));
+        dump(cast_slice(self.nodes()));
     }
 
     #[cfg(test)]
@@ -764,6 +1037,104 @@ impl Slab {
     }
 }
 
+/// An iterator over the leaves of a `Slab` in key order. It finds each leaf
+/// by walking down from the root, so it needs no stack.
+#[derive(Clone)]
+pub struct LeafIter<'a> {
+    slab: &'a Slab,
+    descending: bool,
+    // the keys not yet yielded, inclusive
+    lo: u128,
+    hi: u128,
+    exhausted: bool,
+}
+
+impl<'a> LeafIter<'a> {
+    fn step(&mut self, from_back: bool) -> Option<&'a LeafNode> {
+        if self.exhausted || self.lo > self.hi {
+            return None;
+        }
+        let descending = self.descending != from_back;
+        let search_key = if descending { self.hi } else { self.lo };
+        let leaf = self
+            .slab
+            .find_from(search_key, descending)
+            .and_then(|h| self.slab.get(h))
+            .and_then(|node| node.as_leaf())
+            .filter(|leaf| self.lo <= leaf.key && leaf.key <= self.hi);
+        let leaf = match leaf {
+            Some(leaf) => leaf,
+            None => {
+                self.exhausted = true;
+                return None;
+            }
+        };
+        let next_key = if descending {
+            leaf.key.checked_sub(1).map(|key| self.hi = key)
+        } else {
+            leaf.key.checked_add(1).map(|key| self.lo = key)
+        };
+        self.exhausted = next_key.is_none();
+        Some(leaf)
+    }
+
+    /// Only the leaves of orders placed by the given OpenOrders account.
+    pub fn owned_by(self, owner: &'a [u64; 4]) -> impl DoubleEndedIterator<Item = &'a LeafNode> {
+        self.filter(move |leaf| leaf.owner() == owner)
+    }
+
+    /// Aggregates the visible quantity of the remaining leaves into at most
+    /// `n` price levels, in iteration order.
+    pub fn l2_depth(self, n: usize) -> L2Depth<'a> {
+        L2Depth {
+            leaves: self.peekable(),
+            levels_remaining: n,
+        }
+    }
+}
+
+impl<'a> Iterator for LeafIter<'a> {
+    type Item = &'a LeafNode;
+
+    fn next(&mut self) -> Option<&'a LeafNode> {
+        self.step(false)
+    }
+}
+
+impl<'a> DoubleEndedIterator for LeafIter<'a> {
+    fn next_back(&mut self) -> Option<&'a LeafNode> {
+        self.step(true)
+    }
+}
+
+/// Price levels of a book side as `(price, quantity)`, made by `l2_depth`.
+pub struct L2Depth<'a> {
+    leaves: std::iter::Peekable<LeafIter<'a>>,
+    levels_remaining: usize,
+}
+
+impl<'a> Iterator for L2Depth<'a> {
+    type Item = (NonZeroU64, u64);
+
+    fn next(&mut self) -> Option<(NonZeroU64, u64)> {
+        if self.levels_remaining == 0 {
+            return None;
+        }
+        let first = self.leaves.next()?;
+        let price = first.price();
+        let mut quantity = first.quantity();
+        while let Some(leaf) = self.leaves.peek() {
+            if leaf.price() != price {
+                break;
+            }
+            quantity = quantity.saturating_add(leaf.quantity());
+            self.leaves.next();
+        }
+        self.levels_remaining -= 1;
+        Some((price, quantity))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -940,6 +1311,138 @@ mod tests {
         }
     }
 
+    #[test]
+    fn iceberg_refills_from_hidden_quantity() {
+        let key = (100u128 << 64) | 1;
+        let mut leaf = LeafNode::new(0, &key, &[0; 4], 25, FeeTier::Base, 0)
+            .with_display_quantity(NonZeroU64::new(10));
+        assert_eq!(leaf.quantity(), 10);
+        assert_eq!(leaf.hidden_quantity(), 15);
+
+        // a fill larger than the visible slice eats into the hidden part
+        leaf.take_quantity(12);
+        assert_eq!(leaf.quantity(), 0);
+        assert_eq!(leaf.hidden_quantity(), 13);
+        assert!(leaf.needs_refill());
+
+        leaf.refill((100u128 << 64) | 2);
+        assert_eq!(*leaf.order_id(), (100u128 << 64) | 2);
+        assert_eq!(leaf.quantity(), 10);
+        assert_eq!(leaf.hidden_quantity(), 3);
+
+        leaf.take_quantity(10);
+        leaf.refill((100u128 << 64) | 3);
+        assert_eq!(leaf.quantity(), 3);
+        assert_eq!(leaf.total_quantity(), 3);
+        assert!(!leaf.needs_refill());
+    }
+
+    #[test]
+    fn checksum_tracks_leaves() {
+        let mut aligned_buf = vec![0u64; 10_000];
+        let bytes: &mut [u8] = cast_slice_mut(aligned_buf.as_mut_slice());
+        let slab: &mut Slab = Slab::new(bytes);
+        let recomputed = |slab: &Slab| {
+            slab.iter()
+                .fold(0u64, |sum, leaf| sum.wrapping_add(leaf.checksum()))
+        };
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut keys = vec![];
+        for i in 0..100u64 {
+            let key = ((rng.gen_range(1, 10) as u128) << 64) | i as u128;
+            let leaf = LeafNode::new(0, &key, &[i; 4], i + 1, FeeTier::Base, 0);
+            slab.insert_leaf(&leaf).unwrap();
+            keys.push(key);
+        }
+        assert_eq!(slab.checksum(), recomputed(slab));
+
+        for &key in keys.iter().step_by(3) {
+            let h = slab.find_by_key(&key).unwrap();
+            slab.update_leaf(h, |leaf| leaf.take_quantity(1)).unwrap();
+        }
+        assert_eq!(slab.checksum(), recomputed(slab));
+
+        let before = slab.checksum();
+        let leaf = slab.remove_by_key(&keys[10]).unwrap();
+        assert_ne!(slab.checksum(), before);
+        slab.insert_leaf(&leaf).unwrap();
+        assert_eq!(slab.checksum(), before);
+
+        for key in &keys {
+            slab.remove_by_key(key).unwrap();
+        }
+        assert_eq!(slab.checksum(), 0);
+    }
+
+    #[test]
+    fn iterates_from_both_ends() {
+        use std::collections::BTreeMap;
+
+        let mut aligned_buf = vec![0u64; 10_000];
+        let bytes: &mut [u8] = cast_slice_mut(aligned_buf.as_mut_slice());
+        let slab: &mut Slab = Slab::new(bytes);
+        let mut model: BTreeMap<u128, LeafNode> = BTreeMap::new();
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for i in 0..100u64 {
+            // few prices, so that price levels hold several orders
+            let key = ((rng.gen_range(1, 10) as u128) << 64) | i as u128;
+            let leaf = LeafNode::new(0, &key, &[i % 2; 4], i + 1, FeeTier::Base, 0);
+            slab.insert_leaf(&leaf).unwrap();
+            model.insert(key, leaf);
+        }
+
+        let slab: &Slab = Slab::from_bytes(cast_slice(aligned_buf.as_slice())).unwrap();
+        assert!(slab.iter().eq(model.values()));
+        assert!(slab.iter().rev().eq(model.values().rev()));
+        assert!(slab.iter_desc().eq(model.values().rev()));
+
+        let in_range = |leaf: &&LeafNode| (3..=5).contains(&leaf.price().get());
+        assert!(slab.range(3..=5).eq(model.values().filter(in_range)));
+        let in_range_desc = model.values().rev().filter(in_range);
+        assert!(slab.range(3..=5).rev().eq(in_range_desc));
+        assert_eq!(slab.range(RangeInclusive::new(6, 5)).count(), 0);
+        for key in model.keys() {
+            let leaf = slab.get(slab.find_by_key(key).unwrap()).unwrap();
+            assert_eq!(leaf.as_leaf().unwrap().key, *key);
+        }
+        let owner = [1; 4];
+        let owned = model.values().filter(|leaf| leaf.owner() == &owner);
+        assert!(slab.iter().owned_by(&owner).eq(owned));
+
+        // meeting in the middle yields every leaf exactly once
+        let mut iter = slab.iter();
+        let mut seen = vec![];
+        while let (Some(front), Some(back)) = (iter.next(), iter.next_back()) {
+            seen.push(front.key);
+            seen.push(back.key);
+        }
+        seen.sort();
+        assert_eq!(seen, model.keys().copied().collect::<Vec<_>>());
+
+        let mut model_levels: BTreeMap<u64, u64> = BTreeMap::new();
+        for leaf in model.values() {
+            *model_levels.entry(leaf.price().get()).or_default() += leaf.quantity();
+        }
+        let levels: Vec<(u64, u64)> = slab
+            .iter_desc()
+            .l2_depth(3)
+            .map(|(price, quantity)| (price.get(), quantity))
+            .collect();
+        let model_levels: Vec<(u64, u64)> = model_levels.into_iter().rev().take(3).collect();
+        assert_eq!(levels, model_levels);
+    }
+
+    #[test]
+    fn from_bytes_rejects_short_or_misaligned_bytes() {
+        let aligned_buf = vec![0u64; 1_000];
+        let bytes: &[u8] = cast_slice(aligned_buf.as_slice());
+        assert!(Slab::from_bytes(bytes).is_some());
+        assert!(Slab::from_bytes(&bytes[..SLAB_HEADER_LEN - 1]).is_none());
+        assert!(Slab::from_bytes(&bytes[1..]).is_none());
+    }
+
     #[test]
     #[should_panic]
     fn panics_unaligned() {
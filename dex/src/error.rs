@@ -106,6 +106,28 @@ pub enum DexErrorCode {
     WrongRentSysvarAccount,
     RentNotProvided,
     OrdersNotRentExempt,
+    WrongTriggerBookAccount,
+
+    TriggerBookFull = 60,
+    TriggerBookTooSmall,
+    OrderNotFound,
+    NewOrdersSideMismatch,
+    QuoteSizedOrderNotImmediate,
+    InvalidFeeSchedule,
+    InvalidFeeDiscounts,
+    WrongReferrer,
+    InvalidFeeSplit,
+    WrongFeeReceiver,
+    InvalidCrankReward,
+    MigrationTargetTooSmall,
+    WrongAuthority,
+    MarketIsCancelOnly,
+    MarketIsHalted,
+    OrdersNotEmpty,
+    WouldSelfTrade,
+    ReferrerInUse,
+    MigrationTooLarge,
+    LamportsOverflow,
 
     Unknown = 1000,
 
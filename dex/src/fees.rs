@@ -1,10 +1,16 @@
+use bytemuck::{cast, Pod, Zeroable};
+use enumflags2::BitFlags;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::convert::TryInto;
 
+use crate::instruction::{msrm_token, srm_token};
+
 #[cfg(test)]
 use proptest_derive::Arbitrary;
+#[cfg(test)]
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, IntoPrimitive, TryFromPrimitive, Debug)]
+#[derive(Copy, Clone, IntoPrimitive, TryFromPrimitive, Debug, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg_attr(test, derive(Arbitrary))]
 #[repr(u8)]
 pub enum FeeTier {
@@ -17,6 +23,234 @@ pub enum FeeTier {
     MSRM,
 }
 
+#[derive(Copy, Clone, BitFlags, Debug, Eq, PartialEq)]
+#[repr(u16)]
+pub enum FeeFlag {
+    /// Takers selling coin pay their fee in coin, and the makers they trade
+    /// with are rebated in coin.
+    CoinFeesOnAsks = 0x1,
+}
+
+/// Taker fees and maker rebates in basis points, indexed by `FeeTier`.
+/// Referred takers pass `referrer_share_bps` of their fee on to the referrer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(C)]
+pub struct FeeSchedule {
+    pub taker_bps: [u16; 7],
+    pub maker_rebate_bps: [u16; 7],
+    pub referrer_share_bps: u16,
+    pub fee_flags: u16,
+}
+unsafe impl Zeroable for FeeSchedule {}
+unsafe impl Pod for FeeSchedule {}
+
+impl Default for FeeSchedule {
+    fn default() -> Self {
+        FeeSchedule {
+            taker_bps: [22, 20, 18, 16, 14, 12, 10],
+            maker_rebate_bps: [3, 3, 3, 3, 3, 3, 5],
+            referrer_share_bps: 0,
+            fee_flags: 0,
+        }
+    }
+}
+
+/// The token balances that earn a fee discount. Holding at least
+/// `discount_thresholds[i]` of `discount_mint` earns tier `SRM{i + 2}`, and
+/// holding at least `premium_threshold` of `premium_mint` earns tier `MSRM`.
+/// Optionally, trading at least `volume_thresholds[i]` of native pc in an
+/// epoch earns the tier after `Base` by `i + 1`; all zeros disables this.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(C)]
+pub struct FeeDiscounts {
+    pub discount_mint: [u64; 4],
+    pub premium_mint: [u64; 4],
+    pub discount_thresholds: [u64; 5],
+    pub premium_threshold: u64,
+    pub volume_thresholds: [u64; 6],
+}
+unsafe impl Zeroable for FeeDiscounts {}
+unsafe impl Pod for FeeDiscounts {}
+
+impl Default for FeeDiscounts {
+    fn default() -> Self {
+        let one_srm = 1_000_000;
+        FeeDiscounts {
+            discount_mint: cast(srm_token::ID.to_bytes()),
+            premium_mint: cast(msrm_token::ID.to_bytes()),
+            discount_thresholds: [
+                one_srm * 100,
+                one_srm * 1_000,
+                one_srm * 10_000,
+                one_srm * 100_000,
+                one_srm * 1_000_000,
+            ],
+            premium_threshold: 1,
+            volume_thresholds: [0; 6],
+        }
+    }
+}
+
+impl FeeDiscounts {
+    /// Thresholds must be nonzero and increase with the tier.
+    pub fn is_valid(&self) -> bool {
+        let volume_tiers_valid = !self.has_volume_tiers()
+            || (self.volume_thresholds[0] > 0
+                && self
+                    .volume_thresholds
+                    .windows(2)
+                    .all(|pair| pair[0] < pair[1]));
+        self.premium_threshold > 0
+            && self.discount_thresholds[0] > 0
+            && self
+                .discount_thresholds
+                .windows(2)
+                .all(|pair| pair[0] < pair[1])
+            && volume_tiers_valid
+    }
+
+    pub fn has_volume_tiers(&self) -> bool {
+        self.volume_thresholds != [0; 6]
+    }
+
+    /// The tier earned by trading `native_pc_volume` in the current epoch.
+    pub fn volume_tier(&self, native_pc_volume: u64) -> FeeTier {
+        if !self.has_volume_tiers() {
+            return FeeTier::Base;
+        }
+        let tiers_reached = self
+            .volume_thresholds
+            .iter()
+            .take_while(|&&threshold| native_pc_volume >= threshold)
+            .count();
+        FeeTier::try_from_primitive(tiers_reached as u8).unwrap()
+    }
+
+    /// The tier earned by holding `balance` of `mint`.
+    pub fn fee_tier(&self, mint: &[u64; 4], balance: u64) -> FeeTier {
+        if mint == &self.discount_mint {
+            self.tier_for_balances(balance, 0)
+        } else if mint == &self.premium_mint {
+            self.tier_for_balances(0, balance)
+        } else {
+            FeeTier::Base
+        }
+    }
+
+    fn tier_for_balances(&self, discount_held: u64, premium_held: u64) -> FeeTier {
+        let thresholds = &self.discount_thresholds;
+        match () {
+            () if premium_held >= self.premium_threshold => FeeTier::MSRM,
+            () if discount_held >= thresholds[4] => FeeTier::SRM6,
+            () if discount_held >= thresholds[3] => FeeTier::SRM5,
+            () if discount_held >= thresholds[2] => FeeTier::SRM4,
+            () if discount_held >= thresholds[1] => FeeTier::SRM3,
+            () if discount_held >= thresholds[0] => FeeTier::SRM2,
+            () => FeeTier::Base,
+        }
+    }
+}
+
+pub const MAX_FEE_RECIPIENTS: usize = 4;
+
+/// How swept fees are divided. Recipient `i` is the owner of the token
+/// accounts that receive `shares_bps[i]` of each sweep; whatever is lost to
+/// rounding goes to `dust_recipient`. A split with no shares sends the whole
+/// sweep to a single fee receivable account.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(C)]
+pub struct FeeSplit {
+    pub recipients: [[u64; 4]; MAX_FEE_RECIPIENTS],
+    pub shares_bps: [u16; MAX_FEE_RECIPIENTS],
+    pub dust_recipient: u64,
+}
+unsafe impl Zeroable for FeeSplit {}
+unsafe impl Pod for FeeSplit {}
+
+impl FeeSplit {
+    pub fn is_empty(&self) -> bool {
+        self.shares_bps.iter().all(|&share| share == 0)
+    }
+
+    /// Shares must add up to the whole sweep, and the dust must go to a
+    /// recipient with a share.
+    pub fn is_valid(&self) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let total_bps: u64 = self.shares_bps.iter().map(|&share| share as u64).sum();
+        let dust_recipient = self.dust_recipient as usize;
+        total_bps == 10_000
+            && dust_recipient < MAX_FEE_RECIPIENTS
+            && self.shares_bps[dust_recipient] > 0
+    }
+
+    /// The amount each recipient receives out of `native_amount`. The
+    /// amounts add up to exactly `native_amount`.
+    pub fn split(&self, native_amount: u64) -> [u64; MAX_FEE_RECIPIENTS] {
+        let mut amounts = [0u64; MAX_FEE_RECIPIENTS];
+        for (amount, &share) in amounts.iter_mut().zip(&self.shares_bps) {
+            *amount = (native_amount as u128 * share as u128 / 10_000) as u64;
+        }
+        let dust = native_amount - amounts.iter().sum::<u64>();
+        amounts[self.dust_recipient as usize] += dust;
+        amounts
+    }
+}
+
+/// The most of the accrued pc fees a single crank call can be rewarded with.
+pub const MAX_CRANK_REWARD_FEES_SHARE_BPS: u64 = 100;
+
+/// A reward for cranking the market, paid out of the accrued pc fees. Each
+/// event consumed or unit of matching work earns `native_pc_per_unit`, but a
+/// single call never earns more than `max_native_pc_per_call` or
+/// `max_fees_share_bps` of the fees accrued, and no more than
+/// `max_native_pc_per_epoch` is paid out in a fee epoch. A zero reward
+/// disables it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(C)]
+pub struct CrankReward {
+    pub native_pc_per_unit: u64,
+    pub max_fees_share_bps: u64,
+    pub max_native_pc_per_call: u64,
+    pub max_native_pc_per_epoch: u64,
+}
+unsafe impl Zeroable for CrankReward {}
+unsafe impl Pod for CrankReward {}
+
+impl CrankReward {
+    pub fn is_valid(&self) -> bool {
+        self.max_fees_share_bps <= MAX_CRANK_REWARD_FEES_SHARE_BPS
+    }
+
+    /// The reward for `units` of work, given the fees accrued and how much
+    /// has already been paid out in the current fee epoch.
+    pub fn reward(
+        &self,
+        units: u64,
+        native_pc_fees_accrued: u64,
+        native_pc_paid_in_epoch: u64,
+    ) -> u64 {
+        let cap = native_pc_fees_accrued as u128 * self.max_fees_share_bps as u128 / 10_000;
+        let epoch_budget_left = self
+            .max_native_pc_per_epoch
+            .saturating_sub(native_pc_paid_in_epoch);
+        units
+            .saturating_mul(self.native_pc_per_unit)
+            .min(self.max_native_pc_per_call)
+            .min(cap as u64)
+            .min(epoch_budget_left)
+    }
+}
+
 #[repr(transparent)]
 #[derive(Copy, Clone)]
 struct U64F64(u128);
@@ -67,49 +301,86 @@ const fn rebate_bps(bps: u64) -> U64F64 {
 
 impl FeeTier {
     pub fn from_srm_and_msrm_balances(srm_held: u64, msrm_held: u64) -> FeeTier {
-        let one_srm = 1_000_000;
-        match () {
-            () if msrm_held >= 1 => FeeTier::MSRM,
-            () if srm_held >= one_srm * 1_000_000 => FeeTier::SRM6,
-            () if srm_held >= one_srm * 100_000 => FeeTier::SRM5,
-            () if srm_held >= one_srm * 10_000 => FeeTier::SRM4,
-            () if srm_held >= one_srm * 1_000 => FeeTier::SRM3,
-            () if srm_held >= one_srm * 100 => FeeTier::SRM2,
-            () => FeeTier::Base,
+        FeeDiscounts::default().tier_for_balances(srm_held, msrm_held)
+    }
+}
+
+impl FeeSchedule {
+    /// A schedule is valid if every taker pays less than the whole trade and
+    /// the part of their fee not shared with a referrer is more than any maker
+    /// is rebated, so the market never pays out more than it collects in fees.
+    pub fn is_valid(&self) -> bool {
+        let min_taker_bps = *self.taker_bps.iter().min().unwrap() as u64;
+        let max_taker_bps = *self.taker_bps.iter().max().unwrap();
+        let max_rebate_bps = *self.maker_rebate_bps.iter().max().unwrap() as u64;
+        let referrer_share_bps = self.referrer_share_bps as u64;
+        BitFlags::<FeeFlag>::from_bits(self.fee_flags).is_ok()
+            && max_taker_bps < 10_000
+            && referrer_share_bps <= 10_000
+            && min_taker_bps * (10_000 - referrer_share_bps) > max_rebate_bps * 10_000
+    }
+
+    pub fn coin_fees_on_asks(&self) -> bool {
+        BitFlags::<FeeFlag>::from_bits_truncate(self.fee_flags).contains(FeeFlag::CoinFeesOnAsks)
+    }
+
+    /// The coin an ask locks on top of `native_coin_qty` to cover a taker fee
+    /// paid in coin.
+    pub fn native_coin_fee_reserve(&self, tier: FeeTier, native_coin_qty: u64) -> u64 {
+        if self.coin_fees_on_asks() {
+            self.taker_fee(tier, native_coin_qty)
+        } else {
+            0
+        }
+    }
+
+    /// The pc fee of a taker selling coin for `pc_qty`.
+    pub fn ask_taker_pc_fee(&self, tier: FeeTier, pc_qty: u64) -> u64 {
+        if self.coin_fees_on_asks() {
+            0
+        } else {
+            self.taker_fee(tier, pc_qty)
         }
     }
 
-    pub fn maker_rebate(self, pc_qty: u64) -> u64 {
-        use FeeTier::*;
-        let rate: U64F64 = match self {
-            MSRM => rebate_bps(5),
-            Base | SRM2 | SRM3 | SRM4 | SRM5 | SRM6 => rebate_bps(3),
+    /// The smallest pc quantity a taker selling coin has to trade to be left
+    /// with at least `native_pc_qty` after `ask_taker_pc_fee`. Since the fee
+    /// is rounded up, that is the smallest `x` with `x * (1 - rate) >= qty`.
+    pub fn ask_taker_gross_pc_qty(&self, tier: FeeTier, native_pc_qty: u64) -> u128 {
+        let rate = if self.coin_fees_on_asks() {
+            U64F64(0)
+        } else {
+            self.taker_rate(tier)
         };
+        // the taker rate is below one, so neither side overflows
+        let numerator = U64F64::from_int(native_pc_qty).0;
+        let denominator = U64F64::ONE.0 - rate.0;
+        (numerator + (denominator - 1)) / denominator
+    }
+
+    /// The part of a referred taker's fee that goes to the referrer.
+    pub fn referrer_rebate(&self, native_taker_fee: u64) -> u64 {
+        (native_taker_fee as u128 * self.referrer_share_bps as u128 / 10_000) as u64
+    }
+
+    pub fn maker_rebate(&self, tier: FeeTier, pc_qty: u64) -> u64 {
+        let rate = rebate_bps(self.maker_rebate_bps[u8::from(tier) as usize] as u64);
         rate.mul_u64(pc_qty).floor()
     }
 
     #[inline(always)]
-    fn taker_rate(self) -> U64F64 {
-        use FeeTier::*;
-        match self {
-            Base => fee_bps(22),
-            SRM2 => fee_bps(20),
-            SRM3 => fee_bps(18),
-            SRM4 => fee_bps(16),
-            SRM5 => fee_bps(14),
-            SRM6 => fee_bps(12),
-            MSRM => fee_bps(10),
-        }
+    fn taker_rate(&self, tier: FeeTier) -> U64F64 {
+        fee_bps(self.taker_bps[u8::from(tier) as usize] as u64)
     }
 
-    pub fn taker_fee(self, pc_qty: u64) -> u64 {
-        let rate = self.taker_rate();
+    pub fn taker_fee(&self, tier: FeeTier, pc_qty: u64) -> u64 {
+        let rate = self.taker_rate(tier);
         let exact_fee: U64F64 = rate.mul_u64(pc_qty);
         exact_fee.floor() + ((exact_fee.frac_part() != 0) as u64)
     }
 
-    pub fn remove_taker_fee(self, pc_qty_incl_fee: u64) -> u64 {
-        let rate = self.taker_rate();
+    pub fn remove_taker_fee(&self, tier: FeeTier, pc_qty_incl_fee: u64) -> u64 {
+        let rate = self.taker_rate(tier);
         U64F64::from_int(pc_qty_incl_fee)
             .div(U64F64::ONE.add(rate))
             .try_into()
@@ -122,16 +393,156 @@ mod tests {
     use super::*;
     use proptest::prelude::*;
 
+    // rebates are kept small next to the taker fees so most schedules pass
+    // `is_valid`, but which ones do is left to it
+    fn valid_fee_schedule() -> impl Strategy<Value = FeeSchedule> {
+        (
+            prop::array::uniform7(0..10_000u16),
+            prop::array::uniform7(0..100u16),
+            0..=10_000u16,
+            0..=BitFlags::<FeeFlag>::all().bits(),
+        )
+            .prop_map(
+                |(taker_bps, maker_rebate_bps, referrer_share_bps, fee_flags)| FeeSchedule {
+                    taker_bps,
+                    maker_rebate_bps,
+                    referrer_share_bps,
+                    fee_flags,
+                },
+            )
+            .prop_filter("invalid fee schedule", FeeSchedule::is_valid)
+    }
+
+    #[test]
+    fn default_schedule_is_valid() {
+        assert!(FeeSchedule::default().is_valid());
+    }
+
+    #[test]
+    fn fee_split_sends_dust_to_dust_recipient() {
+        let split = FeeSplit {
+            recipients: [[1; 4], [2; 4], [3; 4], [0; 4]],
+            shares_bps: [3_333, 3_333, 3_334, 0],
+            dust_recipient: 2,
+        };
+        assert!(split.is_valid());
+        assert_eq!(split.split(100), [33, 33, 34, 0]);
+        assert_eq!(split.split(10), [3, 3, 4, 0]);
+        let no_dust_share = FeeSplit {
+            dust_recipient: 3,
+            ..split
+        };
+        assert!(!no_dust_share.is_valid());
+    }
+
+    proptest! {
+        #[test]
+        fn fee_split_adds_up(
+            shares in prop::array::uniform3(0..=3_000u16),
+            native_amount: u64,
+        ) {
+            let last_share = 10_000 - shares.iter().sum::<u16>();
+            let split = FeeSplit {
+                recipients: [[0; 4]; MAX_FEE_RECIPIENTS],
+                shares_bps: [shares[0], shares[1], shares[2], last_share],
+                dust_recipient: 3,
+            };
+            assert!(split.is_valid());
+            let amounts = split.split(native_amount);
+            assert_eq!(amounts.iter().map(|&a| a as u128).sum::<u128>(), native_amount as u128);
+        }
+    }
+
+    #[test]
+    fn crank_reward_is_capped() {
+        let crank_reward = CrankReward {
+            native_pc_per_unit: 10,
+            max_fees_share_bps: 100,
+            max_native_pc_per_call: 5_000,
+            max_native_pc_per_epoch: 20_000,
+        };
+        assert!(crank_reward.is_valid());
+        assert_eq!(crank_reward.reward(5, 1_000_000, 0), 50);
+        assert_eq!(crank_reward.reward(std::u64::MAX, 1_000_000, 0), 5_000);
+        assert_eq!(crank_reward.reward(std::u64::MAX, 100_000, 0), 1_000);
+        assert_eq!(crank_reward.reward(5, 0, 0), 0);
+        assert_eq!(crank_reward.reward(std::u64::MAX, 1_000_000, 18_000), 2_000);
+        assert_eq!(crank_reward.reward(5, 1_000_000, 20_000), 0);
+        let too_generous = CrankReward {
+            max_fees_share_bps: MAX_CRANK_REWARD_FEES_SHARE_BPS + 1,
+            ..crank_reward
+        };
+        assert!(!too_generous.is_valid());
+    }
+
+    #[test]
+    fn coin_fees_move_ask_fee_to_coin() {
+        let schedule = FeeSchedule {
+            fee_flags: FeeFlag::CoinFeesOnAsks as u16,
+            ..FeeSchedule::default()
+        };
+        assert!(schedule.is_valid());
+        assert_eq!(schedule.ask_taker_pc_fee(FeeTier::Base, 1_000_000), 0);
+        assert_eq!(
+            schedule.native_coin_fee_reserve(FeeTier::Base, 1_000_000),
+            schedule.taker_fee(FeeTier::Base, 1_000_000)
+        );
+        let default = FeeSchedule::default();
+        assert_eq!(default.native_coin_fee_reserve(FeeTier::Base, 1_000_000), 0);
+        let unknown_flag = FeeSchedule {
+            fee_flags: 0x8000,
+            ..default
+        };
+        assert!(!unknown_flag.is_valid());
+    }
+
+    #[test]
+    fn default_discounts_use_srm_and_msrm() {
+        let discounts = FeeDiscounts::default();
+        assert!(discounts.is_valid());
+        let srm: [u64; 4] = cast(srm_token::ID.to_bytes());
+        let msrm: [u64; 4] = cast(msrm_token::ID.to_bytes());
+        assert_eq!(u8::from(discounts.fee_tier(&srm, 99_999_999)), 0);
+        assert_eq!(u8::from(discounts.fee_tier(&srm, 100_000_000)), 1);
+        assert_eq!(u8::from(discounts.fee_tier(&msrm, 1)), 6);
+        assert_eq!(u8::from(discounts.fee_tier(&[0; 4], std::u64::MAX)), 0);
+        assert_eq!(discounts.volume_tier(std::u64::MAX), FeeTier::Base);
+    }
+
+    #[test]
+    fn volume_tiers() {
+        let discounts = FeeDiscounts {
+            volume_thresholds: [10, 20, 30, 40, 50, 60],
+            ..FeeDiscounts::default()
+        };
+        assert!(discounts.is_valid());
+        assert_eq!(discounts.volume_tier(9), FeeTier::Base);
+        assert_eq!(discounts.volume_tier(10), FeeTier::SRM2);
+        assert_eq!(discounts.volume_tier(59), FeeTier::SRM6);
+        assert_eq!(discounts.volume_tier(std::u64::MAX), FeeTier::MSRM);
+        let unordered = FeeDiscounts {
+            volume_thresholds: [10, 20, 20, 40, 50, 60],
+            ..discounts
+        };
+        assert!(!unordered.is_valid());
+    }
+
     proptest! {
         #[test]
-        fn positive_net_fees(tt: FeeTier, mt: FeeTier, qty in 1..=std::u64::MAX) {
-            let fee = tt.taker_fee(qty);
-            let rebate = mt.maker_rebate(qty);
+        fn positive_net_fees(schedule in valid_fee_schedule(), tt: FeeTier, mt: FeeTier, qty in 1..=std::u64::MAX) {
+            assert!(schedule.is_valid());
+            let taker_fee = schedule.taker_fee(tt, qty);
+            let fee = taker_fee - schedule.referrer_rebate(taker_fee);
+            let rebate = schedule.maker_rebate(mt, qty);
             assert!(fee > rebate);
+            let min_kept_bps = *schedule.taker_bps.iter().min().unwrap() as u128
+                * (10_000 - schedule.referrer_share_bps as u128)
+                / 10_000;
+            let min_spread_bps = min_kept_bps - *schedule.maker_rebate_bps.iter().max().unwrap() as u128;
             let net_bps_u64f64 = (fee - rebate) as u128 * 10_000;
-            let four_bps = (qty as u128) * 4;
+            let spread_bps = (qty as u128) * min_spread_bps.saturating_sub(1);
             let dust_qty_u64f64 = 1 << 32;
-            assert!(net_bps_u64f64 + dust_qty_u64f64 > four_bps, "{:x}, {:x}, {:x}", qty, net_bps_u64f64, four_bps);
+            assert!(net_bps_u64f64 + dust_qty_u64f64 > spread_bps, "{:x}, {:x}, {:x}", qty, net_bps_u64f64, spread_bps);
         }
 
         #[test]
@@ -145,9 +556,9 @@ mod tests {
         }
 
         #[test]
-        fn market_order_cannot_cheat(tier: FeeTier, qty: u64) {
-            let qty_without_fees = tier.remove_taker_fee(qty);
-            let required_fee = tier.taker_fee(qty_without_fees) as i128;
+        fn market_order_cannot_cheat(schedule in valid_fee_schedule(), tier: FeeTier, qty: u64) {
+            let qty_without_fees = schedule.remove_taker_fee(tier, qty);
+            let required_fee = schedule.taker_fee(tier, qty_without_fees) as i128;
             let actual_fee = qty as i128 - qty_without_fees as i128;
             assert!([required_fee + 1, required_fee].contains(&actual_fee),
                     "actual_fee = {}, required_fee = {}",
@@ -155,9 +566,9 @@ mod tests {
         }
 
         #[test]
-        fn test_add_remove_fees(tier: FeeTier, qty in 1..=(std::u64::MAX >> 1)) {
-            let qty_with_fees = qty + tier.taker_fee(qty);
-            let qty2 = tier.remove_taker_fee(qty_with_fees);
+        fn test_add_remove_fees(schedule in valid_fee_schedule(), tier: FeeTier, qty in 1..=(std::u64::MAX >> 1)) {
+            let qty_with_fees = qty + schedule.taker_fee(tier, qty);
+            let qty2 = schedule.remove_taker_fee(tier, qty_with_fees);
             assert!([-1, 0, 1].contains(&(qty as i128 - qty2 as i128)))
         }
     }
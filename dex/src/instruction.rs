@@ -1,6 +1,9 @@
 use crate::error::DexError;
-use crate::matching::{OrderType, Side};
-use bytemuck::{bytes_of, cast};
+use crate::fees::{CrankReward, FeeDiscounts, FeeSchedule, FeeSplit};
+use crate::matching::{
+    BookFullPolicy, ExpiryKind, MarketMode, OrderType, SelfTradeBehavior, Side, TriggerCondition,
+};
+use bytemuck::{bytes_of, bytes_of_mut, cast, Zeroable};
 #[cfg(test)]
 use serde::{Deserialize, Serialize};
 use solana_sdk::{
@@ -9,7 +12,7 @@ use solana_sdk::{
 };
 
 use arrayref::{array_ref, array_refs};
-use std::num::NonZeroU64;
+use std::{mem::size_of, num::NonZeroU64};
 
 #[cfg(test)]
 use proptest::prelude::*;
@@ -26,16 +29,6 @@ pub mod msrm_token {
     declare_id!("MSRMcoVyrFxnSgo5uXwone5SKcGhT1KEJMFEkMEWf9L");
 }
 
-pub(crate) mod disable_authority {
-    use solana_sdk::declare_id;
-    declare_id!("5ZVJgwWxMsqXxRMYHXqMwH2hd4myX5Ef4Au2iUsuNQ7V");
-}
-
-pub(crate) mod fee_sweeper {
-    use solana_sdk::declare_id;
-    declare_id!("DeqYsmBd9BnrbgUwQjVH4sQWK71dEgE6eoZFw3Rp4ftE");
-}
-
 #[derive(PartialEq, Eq, Debug, Clone)]
 #[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
 #[cfg_attr(test, proptest(no_params))]
@@ -51,9 +44,22 @@ pub struct InitializeMarketInstruction {
     // If these assumptions are problematic, rejigger the lot sizes.
     pub coin_lot_size: u64,
     pub pc_lot_size: u64,
+    // The base tier's taker fee. Either 0 or equal to `fee_schedule.taker_bps[0]`,
+    // which is what the market stores.
     pub fee_rate_bps: u16,
     pub vault_signer_nonce: u64,
     pub pc_dust_threshold: u64,
+    // Taker fees and maker rebates charged on this market. Omitted by older
+    // clients, which get the default schedule.
+    pub fee_schedule: FeeSchedule,
+    // The token holdings that earn a fee discount. May be omitted together
+    // with the fee schedule, in which case SRM and MSRM are used.
+    pub fee_discounts: FeeDiscounts,
+    // What happens to an order that finds its side of the book full. May be
+    // omitted together with the threshold, in which case the least
+    // aggressive resting order is evicted.
+    pub book_full_policy: BookFullPolicy,
+    pub book_full_notional_threshold: u64,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -74,6 +80,153 @@ pub struct NewOrderInstruction {
     pub client_id: u64,
 }
 
+/// Maximum number of orders a single `NewOrders` instruction may place.
+pub const MAX_NEW_ORDERS: usize = 20;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+pub struct NewOrderInstructionV2 {
+    pub side: Side,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub limit_price: NonZeroU64,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub max_qty: NonZeroU64,
+    pub order_type: OrderType,
+    pub client_id: u64,
+    pub self_trade_behavior: SelfTradeBehavior,
+    pub expiry_kind: ExpiryKind,
+    // Last slot or unix timestamp, depending on `expiry_kind`, at which the
+    // order may still match. Ignored if the order never expires.
+    pub expiry: u64,
+    // Size of the visible slice of an iceberg order. Zero shows the whole
+    // order on the book.
+    pub display_qty: u64,
+    // Sizes an immediate-or-cancel or fill-or-kill order in the price
+    // currency: a bid spends exactly this much, fees included, and an ask
+    // sells until it has received at least this much after fees. `max_qty`
+    // still caps the coin traded and `limit_price` is the worst price
+    // accepted. Zero sizes the order by `max_qty` alone.
+    pub native_pc_qty: u64,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+pub struct NewStopOrderInstruction {
+    pub order: NewOrderInstructionV2,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub trigger_price: NonZeroU64,
+    pub trigger_condition: TriggerCondition,
+}
+
+impl NewOrderInstructionV2 {
+    fn unpack(data: &[u8; 64]) -> Option<Self> {
+        let (
+            v1_data_arr,
+            &stb_arr,
+            &expiry_kind_arr,
+            &expiry_arr,
+            &display_qty_arr,
+            &native_pc_qty_arr,
+        ) = array_refs![data, 32, 4, 4, 8, 8, 8];
+        let self_trade_behavior = match u32::from_le_bytes(stb_arr) {
+            0 => SelfTradeBehavior::DecrementTake,
+            1 => SelfTradeBehavior::CancelProvide,
+            2 => SelfTradeBehavior::AbortTransaction,
+            _ => return None,
+        };
+        let expiry_kind = match u32::from_le_bytes(expiry_kind_arr) {
+            0 => ExpiryKind::Never,
+            1 => ExpiryKind::Slot,
+            2 => ExpiryKind::UnixTimestamp,
+            _ => return None,
+        };
+        Some(NewOrderInstructionV2 {
+            expiry_kind,
+            expiry: u64::from_le_bytes(expiry_arr),
+            display_qty: u64::from_le_bytes(display_qty_arr),
+            native_pc_qty: u64::from_le_bytes(native_pc_qty_arr),
+            ..NewOrderInstruction::unpack(v1_data_arr)?
+                .add_self_trade_behavior(self_trade_behavior)
+        })
+    }
+
+    fn pack_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.side as u32).to_le_bytes());
+        buf.extend_from_slice(&self.limit_price.get().to_le_bytes());
+        buf.extend_from_slice(&self.max_qty.get().to_le_bytes());
+        buf.extend_from_slice(&(self.order_type as u32).to_le_bytes());
+        buf.extend_from_slice(&self.client_id.to_le_bytes());
+        buf.extend_from_slice(&(self.self_trade_behavior as u32).to_le_bytes());
+        buf.extend_from_slice(&(self.expiry_kind as u32).to_le_bytes());
+        buf.extend_from_slice(&self.expiry.to_le_bytes());
+        buf.extend_from_slice(&self.display_qty.to_le_bytes());
+        buf.extend_from_slice(&self.native_pc_qty.to_le_bytes());
+    }
+}
+
+impl NewOrderInstruction {
+    pub fn add_self_trade_behavior(
+        self,
+        self_trade_behavior: SelfTradeBehavior,
+    ) -> NewOrderInstructionV2 {
+        let NewOrderInstruction {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+        } = self;
+        NewOrderInstructionV2 {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+            self_trade_behavior,
+            expiry_kind: ExpiryKind::Never,
+            expiry: 0,
+            display_qty: 0,
+            native_pc_qty: 0,
+        }
+    }
+
+    fn unpack(data: &[u8; 32]) -> Option<Self> {
+        let (&side_arr, &price_arr, &max_qty_arr, &otype_arr, &client_id_bytes) =
+            array_refs![data, 4, 8, 8, 4, 8];
+        let side = match u32::from_le_bytes(side_arr) {
+            0 => Side::Bid,
+            1 => Side::Ask,
+            _ => return None,
+        };
+        let limit_price = NonZeroU64::new(u64::from_le_bytes(price_arr))?;
+        let max_qty = NonZeroU64::new(u64::from_le_bytes(max_qty_arr))?;
+        let order_type = match u32::from_le_bytes(otype_arr) {
+            0 => OrderType::Limit,
+            1 => OrderType::ImmediateOrCancel,
+            2 => OrderType::PostOnly,
+            3 => OrderType::FillOrKill,
+            _ => return None,
+        };
+        let client_id = u64::from_le_bytes(client_id_bytes);
+        Some(NewOrderInstruction {
+            side,
+            limit_price,
+            max_qty,
+            order_type,
+            client_id,
+        })
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 #[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
 #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
@@ -84,6 +237,49 @@ pub struct CancelOrderInstruction {
     pub owner_slot: u8,
 }
 
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+pub struct ReduceOrderInstruction {
+    pub side: Side,
+    pub order_id: u128,
+    #[cfg_attr(
+        test,
+        proptest(strategy = "(1u64..=std::u64::MAX).prop_map(|x| NonZeroU64::new(x).unwrap())")
+    )]
+    pub new_qty: NonZeroU64,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct CancelAllOrdersInstruction {
+    // Only cancel orders on this side of the book, or on both if `None`.
+    pub side: Option<Side>,
+    // Maximum number of orders to cancel.
+    pub limit: u16,
+}
+
+/// Identifies the order a `ReplaceOrder` cancels.
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub enum ReplaceTarget {
+    OrderId(u128),
+    ClientId(u64),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+pub struct ReplaceOrderInstruction {
+    pub order: NewOrderInstructionV2,
+    // If the replaced order has already been filled or cancelled by the time
+    // the request is matched, place the new order anyway instead of
+    // dropping it.
+    pub place_if_missing: bool,
+    pub target: ReplaceTarget,
+}
+
 #[derive(PartialEq, Eq, Debug, Clone)]
 #[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
 #[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
@@ -97,6 +293,8 @@ pub enum MarketInstruction {
     /// 6. `[writable]` spl-token account for the price currency
     /// 7. `[]` coin currency Mint
     /// 8. `[]` price currency Mint
+    /// 9. `[writable]` zeroed out trigger book
+    /// 10. `[]` the market authority, which administers the market
     InitializeMarket(InitializeMarketInstruction),
     /// 0. `[writable]` the market
     /// 1. `[writable]` the OpenOrders account to use
@@ -108,6 +306,17 @@ pub enum MarketInstruction {
     /// 7. `[]` spl token program
     /// 8. `[]` the rent sysvar
     /// 9. `[writable]` (optional) the (M)SRM account used for fee discounts
+    /// 10. `[]` (optional) the referrer's pc wallet, which earns a share of
+    ///     the taker fee. It can't belong to the OpenOrders owner, so without
+    ///     a discount account it is passed as account 9. A different referrer
+    ///     than the OpenOrders account's current one is rejected while it has
+    ///     open orders or referral rebates that haven't been settled.
+    ///
+    /// A market with volume fee tiers also takes the clock sysvar as a `[]`
+    /// account after the optional accounts.
+    ///
+    /// An order with `SelfTradeBehavior::AbortTransaction` must also pass
+    /// bids and then asks, as `[]` accounts after all of the above.
     NewOrder(NewOrderInstruction),
     /// 0. `[writable]` market
     /// 1. `[writable]` req_q
@@ -116,6 +325,12 @@ pub enum MarketInstruction {
     /// 4. `[writable]` asks
     /// 5. `[writable]` coin fee receivable account
     /// 6. `[writable]` pc fee receivable account
+    /// 7. `[]` the clock sysvar
+    /// 8. `[writable]` the trigger book
+    /// 9. `[writable]` (optional) pc vault, to pay the crank reward
+    /// 10. `[]` (optional) vault signer
+    /// 11. `[]` (optional) spl token program
+    /// 12. `[writable]` (optional) the pc wallet receiving the crank reward
     MatchOrders(u16),
     /// ... `[writable]` OpenOrders
     /// accounts.len() - 4 `[writable]` market
@@ -137,22 +352,177 @@ pub enum MarketInstruction {
     /// 6. `[writable]` pc wallet
     /// 7. `[]` vault signer
     /// 8. `[]` spl token program
+    /// 9. `[writable]` the referrer's pc wallet, to pay out the referral
+    ///    rebates accrued by this OpenOrders account. Only optional while
+    ///    there are none.
     SettleFunds,
     /// 0. `[]` market
     /// 1. `[writable]` OpenOrders
     /// 2. `[writable]` the request queue
     /// 3. `[signer]` the OpenOrders owner
     CancelOrderByClientId(u64),
+    /// Halts the market, the same as `SetMarketMode(Halted)`.
+    ///
     /// 0. `[writable]` market
-    /// 1. `[signer]` disable authority
+    /// 1. `[signer]` market authority
     DisableMarket,
+    /// Sweeps the fees accrued in the given vault's currency. If the market
+    /// has a fee split, account 3 and accounts 6 onwards are the fee
+    /// receivable accounts of the recipients with a share, in order.
+    ///
     /// 0. `[writable]` market
-    /// 1. `[writable]` pc vault
-    /// 2. `[signer]` fee sweeping authority
-    /// 3. `[writable]` fee receivable account
+    /// 1. `[writable]` coin or pc vault
+    /// 2. `[signer]` market authority
+    /// 3. `[writable]` fee receivable account in the vault's currency
     /// 4. `[]` vault signer
     /// 5. `[]` spl token program
+    /// 6. ... `[writable]` with a fee split, the fee receivable accounts of
+    ///    the remaining recipients
     SweepFees,
+    /// Same accounts as `NewOrder`.
+    NewOrderV2(NewOrderInstructionV2),
+    /// 0. `[writable]` market
+    /// 1. `[writable]` event queue
+    /// 2. `[writable]` bids
+    /// 3. `[writable]` asks
+    /// 4. `[]` the clock sysvar
+    PruneExpiredOrders(u16),
+    /// Locks funds like `NewOrder`, but parks the order in the trigger book
+    /// until the last traded price meets the trigger condition.
+    /// A stop order that aborts on a self-trade is only checked once it fires,
+    /// and is cancelled in full if it would self-trade then.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[writable]` the trigger book
+    /// 10. `[writable]` (optional) the (M)SRM account used for fee discounts
+    /// 11. `[]` (optional) the referrer's pc wallet, as for `NewOrder`
+    /// accounts.len() - 1 `[]` the clock sysvar, if the market has volume fee tiers
+    NewStopOrder(NewStopOrderInstruction),
+    /// Shrinks a resting order to `new_qty` lots without losing its place
+    /// in the queue.
+    ///
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[writable]` the request queue
+    /// 3. `[signer]` the OpenOrders owner
+    ReduceOrder(ReduceOrderInstruction),
+    /// Cancels a resting order and places a new one in a single request.
+    /// Funds still locked by the cancelled order are carried over to the
+    /// new order if both are on the same side.
+    ///
+    /// 0. `[writable]` the market
+    /// 1. `[writable]` the OpenOrders account to use
+    /// 2. `[writable]` the request queue
+    /// 3. `[writable]` the (coin or price currency) account paying for the order
+    /// 4. `[signer]` owner of the OpenOrders account
+    /// 5. `[writable]` coin vault
+    /// 6. `[writable]` pc vault
+    /// 7. `[]` spl token program
+    /// 8. `[]` the rent sysvar
+    /// 9. `[]` bids
+    /// 10. `[]` asks
+    /// 11. `[writable]` (optional) the (M)SRM account used for fee discounts
+    /// 12. `[]` (optional) the referrer's pc wallet, as for `NewOrder`
+    /// accounts.len() - 1 `[]` the clock sysvar, if the market has volume fee tiers
+    ReplaceOrder(ReplaceOrderInstruction),
+    /// Cancels up to `limit` of the OpenOrders account's orders. Orders on
+    /// the book or in the trigger book are taken out right away and their
+    /// `Out` events can be consumed immediately; orders still waiting in the
+    /// request queue get a cancel request queued behind them.
+    ///
+    /// 0. `[]` market
+    /// 1. `[writable]` OpenOrders
+    /// 2. `[signer]` the OpenOrders owner
+    /// 3. `[writable]` the request queue
+    /// 4. `[writable]` the event queue
+    /// 5. `[writable]` bids
+    /// 6. `[writable]` asks
+    /// 7. `[writable]` the trigger book
+    /// 8. `[]` the clock sysvar
+    CancelAllOrders(CancelAllOrdersInstruction),
+    /// Places up to `MAX_NEW_ORDERS` orders with a single deposit, each with
+    /// its own self trade behavior, expiry and display quantity. The orders
+    /// must all be on the same side, since they are paid for from the one
+    /// payer account. Same accounts as `NewOrder`.
+    NewOrders(
+        #[cfg_attr(
+            test,
+            proptest(
+                strategy = "proptest::collection::vec(any::<NewOrderInstructionV2>(), 1..=MAX_NEW_ORDERS)"
+            )
+        )]
+        Vec<NewOrderInstructionV2>,
+    ),
+    /// Sets how `SweepFees` divides the fees among recipients.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` market authority
+    SetFeeSplit(FeeSplit),
+    /// Sets the reward paid to callers of `MatchOrders` and
+    /// `ConsumeEventsWithReward` who pass the accounts to receive it.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` market authority
+    SetCrankReward(CrankReward),
+    /// Moves the market's order book and queues to new, larger accounts,
+    /// carrying over every resting order and queued request and event. The
+    /// new accounts must be as uninitialized as for `InitializeMarket` and
+    /// at least as large as the ones they replace, which are left disabled.
+    /// At most `MAX_MIGRATED_ITEMS` orders, requests and events are carried
+    /// over, so a busy market should first be put in cancel-only mode and
+    /// cranked, or have orders cancelled, until it fits.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[writable]` bids
+    /// 2. `[writable]` asks
+    /// 3. `[writable]` request queue
+    /// 4. `[writable]` event queue
+    /// 5. `[writable]` new bids
+    /// 6. `[writable]` new asks
+    /// 7. `[writable]` new request queue
+    /// 8. `[writable]` new event queue
+    /// 9. `[signer]` market authority
+    MigrateBooks,
+    /// Offers the market authority to a new key, which takes over once it
+    /// sends `AcceptAuthority`. Without account 2 the authority is renounced
+    /// right away, and the market can no longer be administered.
+    ///
+    /// 0. `[writable]` market
+    /// 1. `[signer]` market authority
+    /// 2. `[]` (optional) the new market authority
+    SetAuthority,
+    /// 0. `[writable]` market
+    /// 1. `[signer]` the new market authority offered by `SetAuthority`
+    AcceptAuthority,
+    /// 0. `[writable]` market
+    /// 1. `[signer]` market authority
+    SetMarketMode(MarketMode),
+    /// Closes an OpenOrders account with no orders, funds or unsettled
+    /// referral rebates left in it and sends its lamports to the destination.
+    ///
+    /// 0. `[writable]` OpenOrders
+    /// 1. `[signer]` the OpenOrders owner
+    /// 2. `[writable]` the destination for the reclaimed lamports
+    /// 3. `[]` market
+    CloseOpenOrders,
+    /// Same as `ConsumeEvents`, but pays the crank reward to the caller. The
+    /// accounts of `ConsumeEvents` are followed by the same four accounts as
+    /// accounts 9 to 12 of `MatchOrders`.
+    ///
+    /// ... the accounts of `ConsumeEvents`
+    /// accounts.len() - 4 `[writable]` pc vault
+    /// accounts.len() - 3 `[]` vault signer
+    /// accounts.len() - 2 `[]` spl token program
+    /// accounts.len() - 1 `[writable]` the pc wallet receiving the crank reward
+    ConsumeEventsWithReward(u16),
 }
 
 impl MarketInstruction {
@@ -172,6 +542,15 @@ impl MarketInstruction {
                 buf.extend_from_slice(&inst.fee_rate_bps.to_le_bytes());
                 buf.extend_from_slice(&inst.vault_signer_nonce.to_le_bytes());
                 buf.extend_from_slice(&inst.pc_dust_threshold.to_le_bytes());
+                let schedule = &inst.fee_schedule;
+                for bps in schedule.taker_bps.iter().chain(&schedule.maker_rebate_bps) {
+                    buf.extend_from_slice(&bps.to_le_bytes());
+                }
+                buf.extend_from_slice(&schedule.referrer_share_bps.to_le_bytes());
+                buf.extend_from_slice(&schedule.fee_flags.to_le_bytes());
+                buf.extend_from_slice(bytes_of(&inst.fee_discounts));
+                buf.extend_from_slice(&(inst.book_full_policy as u32).to_le_bytes());
+                buf.extend_from_slice(&inst.book_full_notional_threshold.to_le_bytes());
             }
             MarketInstruction::NewOrder(ref inst) => {
                 buf.extend_from_slice(&[0, 1, 0, 0, 0]);
@@ -205,12 +584,85 @@ impl MarketInstruction {
             }
             MarketInstruction::DisableMarket => buf.extend_from_slice(&[0, 7, 0, 0, 0]),
             MarketInstruction::SweepFees => buf.extend_from_slice(&[0, 8, 0, 0, 0]),
+            MarketInstruction::NewOrderV2(ref inst) => {
+                buf.extend_from_slice(&[0, 9, 0, 0, 0]);
+                inst.pack_into(&mut buf);
+            }
+            MarketInstruction::PruneExpiredOrders(limit) => {
+                buf.extend_from_slice(&[0, 10, 0, 0, 0]);
+                buf.extend_from_slice(&limit.to_le_bytes());
+            }
+            MarketInstruction::NewStopOrder(ref inst) => {
+                buf.extend_from_slice(&[0, 11, 0, 0, 0]);
+                inst.order.pack_into(&mut buf);
+                buf.extend_from_slice(&inst.trigger_price.get().to_le_bytes());
+                buf.extend_from_slice(&(inst.trigger_condition as u32).to_le_bytes());
+            }
+            MarketInstruction::ReduceOrder(ref inst) => {
+                buf.extend_from_slice(&[0, 12, 0, 0, 0]);
+                buf.extend_from_slice(&(inst.side as u32).to_le_bytes());
+                buf.extend_from_slice(&inst.order_id.to_le_bytes());
+                buf.extend_from_slice(&inst.new_qty.get().to_le_bytes());
+            }
+            MarketInstruction::ReplaceOrder(ref inst) => {
+                buf.extend_from_slice(&[0, 13, 0, 0, 0]);
+                inst.order.pack_into(&mut buf);
+                buf.push(inst.place_if_missing as u8);
+                match inst.target {
+                    ReplaceTarget::OrderId(order_id) => {
+                        buf.extend_from_slice(&0u32.to_le_bytes());
+                        buf.extend_from_slice(&order_id.to_le_bytes());
+                    }
+                    ReplaceTarget::ClientId(client_id) => {
+                        buf.extend_from_slice(&1u32.to_le_bytes());
+                        buf.extend_from_slice(&client_id.to_le_bytes());
+                    }
+                }
+            }
+            MarketInstruction::CancelAllOrders(ref inst) => {
+                buf.extend_from_slice(&[0, 14, 0, 0, 0]);
+                match inst.side {
+                    None => buf.push(0),
+                    Some(side) => {
+                        buf.push(1);
+                        buf.extend_from_slice(&(side as u32).to_le_bytes());
+                    }
+                }
+                buf.extend_from_slice(&inst.limit.to_le_bytes());
+            }
+            MarketInstruction::NewOrders(ref orders) => {
+                buf.extend_from_slice(&[0, 15, 0, 0, 0]);
+                buf.extend_from_slice(&(orders.len() as u64).to_le_bytes());
+                for inst in orders {
+                    inst.pack_into(&mut buf);
+                }
+            }
+            MarketInstruction::SetFeeSplit(ref fee_split) => {
+                buf.extend_from_slice(&[0, 16, 0, 0, 0]);
+                buf.extend_from_slice(bytes_of(fee_split));
+            }
+            MarketInstruction::SetCrankReward(ref crank_reward) => {
+                buf.extend_from_slice(&[0, 17, 0, 0, 0]);
+                buf.extend_from_slice(bytes_of(crank_reward));
+            }
+            MarketInstruction::MigrateBooks => buf.extend_from_slice(&[0, 18, 0, 0, 0]),
+            MarketInstruction::SetAuthority => buf.extend_from_slice(&[0, 19, 0, 0, 0]),
+            MarketInstruction::AcceptAuthority => buf.extend_from_slice(&[0, 20, 0, 0, 0]),
+            MarketInstruction::SetMarketMode(mode) => {
+                buf.extend_from_slice(&[0, 21, 0, 0, 0]);
+                buf.extend_from_slice(&(u8::from(*mode) as u32).to_le_bytes());
+            }
+            MarketInstruction::CloseOpenOrders => buf.extend_from_slice(&[0, 22, 0, 0, 0]),
+            MarketInstruction::ConsumeEventsWithReward(limit) => {
+                buf.extend_from_slice(&[0, 23, 0, 0, 0]);
+                buf.extend_from_slice(&limit.to_le_bytes());
+            }
         };
         buf
     }
 
     pub fn unpack(versioned_bytes: &[u8]) -> Option<Self> {
-        if versioned_bytes.len() < 5 || versioned_bytes.len() > 58 {
+        if versioned_bytes.len() < 5 || versioned_bytes.len() > 13 + 64 * MAX_NEW_ORDERS {
             return None;
         }
         let (&[version], &discrim, data) = array_refs![versioned_bytes, 1, 4; ..;];
@@ -218,45 +670,63 @@ impl MarketInstruction {
             return None;
         }
         Some(match u32::from_le_bytes(discrim) {
-            0 if data.len() == 34 => MarketInstruction::InitializeMarket({
+            0 if [34, 66, 226, 238].contains(&data.len()) => MarketInstruction::InitializeMarket({
                 let data_array = array_ref![data, 0, 34];
                 let fields = array_refs![data_array, 8, 8, 2, 8, 8];
+                let mut fee_schedule = FeeSchedule::default();
+                let mut fee_discounts = FeeDiscounts::default();
+                if data.len() >= 66 {
+                    let schedule_data = array_ref![data, 34, 32];
+                    let (taker_data, maker_data, referrer_share_data, fee_flags_data) =
+                        array_refs![schedule_data, 14, 14, 2, 2];
+                    for (bps, bytes) in fee_schedule
+                        .taker_bps
+                        .iter_mut()
+                        .zip(taker_data.chunks_exact(2))
+                    {
+                        *bps = u16::from_le_bytes(*array_ref![bytes, 0, 2]);
+                    }
+                    for (bps, bytes) in fee_schedule
+                        .maker_rebate_bps
+                        .iter_mut()
+                        .zip(maker_data.chunks_exact(2))
+                    {
+                        *bps = u16::from_le_bytes(*array_ref![bytes, 0, 2]);
+                    }
+                    fee_schedule.referrer_share_bps = u16::from_le_bytes(*referrer_share_data);
+                    fee_schedule.fee_flags = u16::from_le_bytes(*fee_flags_data);
+                }
+                if data.len() >= 226 {
+                    bytes_of_mut(&mut fee_discounts).copy_from_slice(&data[66..226]);
+                }
+                let mut book_full_policy = BookFullPolicy::EvictWorstPrice;
+                let mut book_full_notional_threshold = 0;
+                if data.len() == 238 {
+                    book_full_policy = match u32::from_le_bytes(*array_ref![data, 226, 4]) {
+                        0 => BookFullPolicy::EvictWorstPrice,
+                        1 => BookFullPolicy::RejectIncoming,
+                        2 => BookFullPolicy::EvictSmallest,
+                        _ => return None,
+                    };
+                    book_full_notional_threshold = u64::from_le_bytes(*array_ref![data, 230, 8]);
+                }
                 InitializeMarketInstruction {
                     coin_lot_size: u64::from_le_bytes(*fields.0),
                     pc_lot_size: u64::from_le_bytes(*fields.1),
                     fee_rate_bps: u16::from_le_bytes(*fields.2),
                     vault_signer_nonce: u64::from_le_bytes(*fields.3),
                     pc_dust_threshold: u64::from_le_bytes(*fields.4),
+                    fee_schedule,
+                    fee_discounts,
+                    book_full_policy,
+                    book_full_notional_threshold,
                 }
             }),
             1 if (data.len() == 24 || data.len() == 32) => MarketInstruction::NewOrder({
-                let (&side_arr, &price_arr, &max_qty_arr, &otype_arr, client_id_bytes) =
-                    array_refs![data, 4, 8, 8, 4; .. ;];
-                let client_id = match client_id_bytes.len() {
-                    0 => 0,
-                    8 => u64::from_le_bytes(*array_ref![client_id_bytes, 0, 8]),
-                    _ => unreachable!(),
-                };
-                let side = match u32::from_le_bytes(side_arr) {
-                    0 => Side::Bid,
-                    1 => Side::Ask,
-                    _ => return None,
-                };
-                let limit_price = NonZeroU64::new(u64::from_le_bytes(price_arr))?;
-                let max_qty = NonZeroU64::new(u64::from_le_bytes(max_qty_arr))?;
-                let order_type = match u32::from_le_bytes(otype_arr) {
-                    0 => OrderType::Limit,
-                    1 => OrderType::ImmediateOrCancel,
-                    2 => OrderType::PostOnly,
-                    _ => return None,
-                };
-                NewOrderInstruction {
-                    side,
-                    limit_price,
-                    max_qty,
-                    order_type,
-                    client_id,
-                }
+                // orders without a client id are zero-extended
+                let mut data_array = [0u8; 32];
+                data_array[..data.len()].copy_from_slice(data);
+                NewOrderInstruction::unpack(&data_array)?
             }),
             2 if data.len() == 2 => {
                 let limit = array_ref![data, 0, 2];
@@ -291,6 +761,127 @@ impl MarketInstruction {
             }
             7 => MarketInstruction::DisableMarket,
             8 => MarketInstruction::SweepFees,
+            9 if data.len() == 64 => {
+                MarketInstruction::NewOrderV2(NewOrderInstructionV2::unpack(array_ref![
+                    data, 0, 64
+                ])?)
+            }
+            10 if data.len() == 2 => {
+                let limit = array_ref![data, 0, 2];
+                MarketInstruction::PruneExpiredOrders(u16::from_le_bytes(*limit))
+            }
+            11 if data.len() == 76 => MarketInstruction::NewStopOrder({
+                let data_array = array_ref![data, 0, 76];
+                let (order_arr, &trigger_price_arr, &condition_arr) =
+                    array_refs![data_array, 64, 8, 4];
+                let trigger_condition = match u32::from_le_bytes(condition_arr) {
+                    0 => TriggerCondition::AtOrBelow,
+                    1 => TriggerCondition::AtOrAbove,
+                    _ => return None,
+                };
+                NewStopOrderInstruction {
+                    order: NewOrderInstructionV2::unpack(order_arr)?,
+                    trigger_price: NonZeroU64::new(u64::from_le_bytes(trigger_price_arr))?,
+                    trigger_condition,
+                }
+            }),
+            12 if data.len() == 28 => MarketInstruction::ReduceOrder({
+                let data_array = array_ref![data, 0, 28];
+                let (&side_arr, &order_id_arr, &new_qty_arr) = array_refs![data_array, 4, 16, 8];
+                let side = match u32::from_le_bytes(side_arr) {
+                    0 => Side::Bid,
+                    1 => Side::Ask,
+                    _ => return None,
+                };
+                ReduceOrderInstruction {
+                    side,
+                    order_id: u128::from_le_bytes(order_id_arr),
+                    new_qty: NonZeroU64::new(u64::from_le_bytes(new_qty_arr))?,
+                }
+            }),
+            13 if (data.len() == 77 || data.len() == 85) => MarketInstruction::ReplaceOrder({
+                let (order_arr, &[place_if_missing], &target_discrim, target_arr) =
+                    array_refs![data, 64, 1, 4; ..;];
+                let place_if_missing = match place_if_missing {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                };
+                let target = match (u32::from_le_bytes(target_discrim), target_arr.len()) {
+                    (0, 16) => {
+                        ReplaceTarget::OrderId(u128::from_le_bytes(*array_ref![target_arr, 0, 16]))
+                    }
+                    (1, 8) => {
+                        ReplaceTarget::ClientId(u64::from_le_bytes(*array_ref![target_arr, 0, 8]))
+                    }
+                    _ => return None,
+                };
+                ReplaceOrderInstruction {
+                    order: NewOrderInstructionV2::unpack(order_arr)?,
+                    place_if_missing,
+                    target,
+                }
+            }),
+            14 if (data.len() == 3 || data.len() == 7) => MarketInstruction::CancelAllOrders({
+                let (&[has_side], side_arr, &limit_arr) = array_refs![data, 1; ..; 2];
+                let side = match (has_side, side_arr.len()) {
+                    (0, 0) => None,
+                    (1, 4) => match u32::from_le_bytes(*array_ref![side_arr, 0, 4]) {
+                        0 => Some(Side::Bid),
+                        1 => Some(Side::Ask),
+                        _ => return None,
+                    },
+                    _ => return None,
+                };
+                CancelAllOrdersInstruction {
+                    side,
+                    limit: u16::from_le_bytes(limit_arr),
+                }
+            }),
+            15 if data.len() >= 8 => MarketInstruction::NewOrders({
+                let (&count_arr, orders_data) = array_refs![data, 8; ..;];
+                let count = u64::from_le_bytes(count_arr);
+                if count == 0
+                    || count > MAX_NEW_ORDERS as u64
+                    || orders_data.len() as u64 != 64 * count
+                {
+                    return None;
+                }
+                let mut orders = Vec::with_capacity(count as usize);
+                for order_data in orders_data.chunks_exact(64) {
+                    orders.push(NewOrderInstructionV2::unpack(array_ref![
+                        order_data, 0, 64
+                    ])?);
+                }
+                orders
+            }),
+            16 if data.len() == size_of::<FeeSplit>() => MarketInstruction::SetFeeSplit({
+                let mut fee_split = FeeSplit::zeroed();
+                bytes_of_mut(&mut fee_split).copy_from_slice(data);
+                fee_split
+            }),
+            17 if data.len() == size_of::<CrankReward>() => MarketInstruction::SetCrankReward({
+                let mut crank_reward = CrankReward::zeroed();
+                bytes_of_mut(&mut crank_reward).copy_from_slice(data);
+                crank_reward
+            }),
+            18 => MarketInstruction::MigrateBooks,
+            19 => MarketInstruction::SetAuthority,
+            20 => MarketInstruction::AcceptAuthority,
+            21 if data.len() == 4 => MarketInstruction::SetMarketMode({
+                match u32::from_le_bytes(*array_ref![data, 0, 4]) {
+                    0 => MarketMode::Active,
+                    1 => MarketMode::PostOnly,
+                    2 => MarketMode::CancelOnly,
+                    3 => MarketMode::Halted,
+                    _ => return None,
+                }
+            }),
+            22 => MarketInstruction::CloseOpenOrders,
+            23 if data.len() == 2 => {
+                let limit = array_ref![data, 0, 2];
+                MarketInstruction::ConsumeEventsWithReward(u16::from_le_bytes(*limit))
+            }
             _ => return None,
         })
     }
@@ -306,6 +897,7 @@ impl MarketInstruction {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn initialize_market(
     market: &Pubkey,
     program_id: &Pubkey,
@@ -318,17 +910,27 @@ pub fn initialize_market(
     asks_pk: &Pubkey,
     req_q_pk: &Pubkey,
     event_q_pk: &Pubkey,
+    trigger_book_pk: &Pubkey,
+    authority_pk: &Pubkey,
     coin_lot_size: u64,
     pc_lot_size: u64,
     vault_signer_nonce: u64,
     pc_dust_threshold: u64,
+    fee_schedule: FeeSchedule,
+    fee_discounts: FeeDiscounts,
+    book_full_policy: BookFullPolicy,
+    book_full_notional_threshold: u64,
 ) -> Result<solana_sdk::instruction::Instruction, DexError> {
     let data = MarketInstruction::InitializeMarket(InitializeMarketInstruction {
         coin_lot_size,
         pc_lot_size,
-        fee_rate_bps: 0,
+        fee_rate_bps: fee_schedule.taker_bps[0],
         vault_signer_nonce,
         pc_dust_threshold,
+        fee_schedule,
+        fee_discounts,
+        book_full_policy,
+        book_full_notional_threshold,
     })
     .pack();
 
@@ -338,6 +940,8 @@ pub fn initialize_market(
     let asks = AccountMeta::new(*asks_pk, false);
     let req_q = AccountMeta::new(*req_q_pk, false);
     let event_q = AccountMeta::new(*event_q_pk, false);
+    let trigger_book = AccountMeta::new(*trigger_book_pk, false);
+    let authority = AccountMeta::new_readonly(*authority_pk, false);
 
     let coin_vault = AccountMeta::new(*coin_vault_pk, false);
     let pc_vault = AccountMeta::new(*pc_vault_pk, false);
@@ -357,6 +961,8 @@ pub fn initialize_market(
         coin_mint,
         pc_mint,
         //srm_mint,
+        trigger_book,
+        authority,
     ];
 
     Ok(Instruction {
@@ -435,6 +1041,43 @@ mod fuzzing {
         }
     }
 
+    impl arbitrary::Arbitrary for NewOrderInstructionV2 {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            let v1: NewOrderInstruction = u.arbitrary()?;
+            Ok(NewOrderInstructionV2 {
+                expiry_kind: u.arbitrary()?,
+                expiry: u.arbitrary()?,
+                display_qty: u.arbitrary()?,
+                native_pc_qty: u.arbitrary()?,
+                ..v1.add_self_trade_behavior(u.arbitrary()?)
+            })
+        }
+    }
+
+    impl arbitrary::Arbitrary for NewStopOrderInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            Ok(NewStopOrderInstruction {
+                order: u.arbitrary()?,
+                trigger_price: <u64 as arbitrary::Arbitrary>::arbitrary(u)?
+                    .try_into()
+                    .map_err(|_| arbitrary::Error::IncorrectFormat)?,
+                trigger_condition: u.arbitrary()?,
+            })
+        }
+    }
+
+    impl arbitrary::Arbitrary for ReduceOrderInstruction {
+        fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
+            Ok(ReduceOrderInstruction {
+                side: u.arbitrary()?,
+                order_id: u.arbitrary()?,
+                new_qty: <u64 as arbitrary::Arbitrary>::arbitrary(u)?
+                    .try_into()
+                    .map_err(|_| arbitrary::Error::IncorrectFormat)?,
+            })
+        }
+    }
+
     impl arbitrary::Arbitrary for NewOrderInstruction {
         fn arbitrary(u: &mut Unstructured<'_>) -> Result<Self, arbitrary::Error> {
             <NewOrderInstructionU64 as arbitrary::Arbitrary>::arbitrary(u)?
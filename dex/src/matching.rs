@@ -1,3 +1,4 @@
+use std::convert::TryInto;
 use std::num::NonZeroU64;
 
 use num_enum::{IntoPrimitive, TryFromPrimitive};
@@ -5,6 +6,7 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 use proptest_derive::Arbitrary;
 #[cfg(test)]
 use serde::{Deserialize, Serialize};
+use solana_sdk::clock::Clock;
 #[cfg(feature = "program")]
 use solana_sdk::info;
 
@@ -13,8 +15,11 @@ use crate::error::{DexErrorCode, DexResult, SourceFileId};
 use crate::{
     critbit::{LeafNode, NodeHandle, Slab, SlabView},
     error::DexError,
-    fees::FeeTier,
-    state::{Event, EventQueue, EventView, MarketState, Request, RequestQueue, RequestView},
+    fees::{FeeSchedule, FeeTier},
+    state::{
+        Event, EventQueue, EventView, MarketState, OpenOrders, ReplacedOrder, Request,
+        RequestQueue, RequestView, TriggerBook,
+    },
 };
 
 #[cfg(not(feature = "program"))]
@@ -40,17 +45,188 @@ pub enum OrderType {
     Limit = 0,
     ImmediateOrCancel = 1,
     PostOnly = 2,
+    /// Either fills completely against the current book within the limit of
+    /// the `MatchOrders` call that reaches it, or does nothing.
+    FillOrKill = 3,
+}
+
+/// What to do when an incoming order would match against a resting order
+/// owned by the same OpenOrders account.
+#[derive(Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum SelfTradeBehavior {
+    /// Reduce both orders by the overlapping quantity without trading.
+    DecrementTake = 0,
+    /// Cancel the resting order and keep matching the incoming one.
+    CancelProvide = 1,
+    /// Fail the instruction placing the order with `WouldSelfTrade` if it
+    /// would cross one of the owner's resting orders, which requires passing
+    /// the bids and asks. If the book has changed by the time the order is
+    /// matched, it is cancelled in full instead, since failing `MatchOrders`
+    /// would only wedge the request queue.
+    AbortTransaction = 2,
+}
+
+/// What to do with an order that should rest on a side of the book that has
+/// no room left.
+#[derive(Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum BookFullPolicy {
+    /// Evict the least aggressive order on that side to make room.
+    EvictWorstPrice = 0,
+    /// Cancel whatever is left of the incoming order instead of posting it.
+    RejectIncoming = 1,
+    /// Evict the resting order with the smallest notional value among the
+    /// `EVICT_SMALLEST_SCAN_DEPTH` least aggressive orders on that side if it
+    /// is below the market's threshold, and otherwise cancel the incoming
+    /// order.
+    EvictSmallest = 2,
+}
+
+/// How many of the least aggressive orders `BookFullPolicy::EvictSmallest`
+/// looks at, so that a full book does not cost a walk of the whole slab.
+pub const EVICT_SMALLEST_SCAN_DEPTH: usize = 32;
+
+/// What a market currently allows, as set by its authority.
+#[derive(Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum MarketMode {
+    /// Orders are placed and matched as usual.
+    Active = 0,
+    /// New orders may rest on the book but never take, so nothing trades.
+    PostOnly = 1,
+    /// No new orders. Queued ones are cancelled when they come up for
+    /// matching, and cancels and settlement still work.
+    CancelOnly = 2,
+    /// Nothing is matched. Queued events can still be consumed and funds
+    /// settled.
+    Halted = 3,
+}
+
+/// How the `expiry` of an order is measured.
+#[derive(Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum ExpiryKind {
+    /// The order rests until it is filled, cancelled or evicted.
+    Never = 0,
+    /// The order expires once the current slot is past `expiry`.
+    Slot = 1,
+    /// The order expires once the cluster's unix timestamp is past `expiry`.
+    UnixTimestamp = 2,
+}
+
+impl ExpiryKind {
+    pub fn is_expired(self, expiry: u64, clock: &Clock) -> bool {
+        match self {
+            ExpiryKind::Never => false,
+            ExpiryKind::Slot => clock.slot > expiry,
+            ExpiryKind::UnixTimestamp => {
+                clock.unix_timestamp > 0 && clock.unix_timestamp as u64 > expiry
+            }
+        }
+    }
+}
+
+/// Which way the last traded price has to move for a stop order to fire.
+#[derive(Eq, PartialEq, Copy, Clone, TryFromPrimitive, IntoPrimitive, Debug)]
+#[cfg_attr(test, derive(Arbitrary, Serialize, Deserialize))]
+#[cfg_attr(feature = "fuzz", derive(arbitrary::Arbitrary))]
+#[repr(u8)]
+pub enum TriggerCondition {
+    /// Fires once the market trades at or below the trigger price.
+    AtOrBelow = 0,
+    /// Fires once the market trades at or above the trigger price.
+    AtOrAbove = 1,
+}
+
+impl TriggerCondition {
+    pub fn is_met(self, trigger_price: u64, last_traded_price: u64) -> bool {
+        // nothing has traded yet
+        if last_traded_price == 0 {
+            return false;
+        }
+        match self {
+            TriggerCondition::AtOrBelow => last_traded_price <= trigger_price,
+            TriggerCondition::AtOrAbove => last_traded_price >= trigger_price,
+        }
+    }
 }
 
 fn extract_price_from_order_id(order_id: &u128) -> u64 {
     (order_id >> 64) as u64
 }
 
+fn order_expired(order: &LeafNode, clock: &Clock) -> bool {
+    order.expiry_kind().is_expired(order.expiry(), clock)
+}
+
+/// Whether an order from `owner` would run into one of the owner's own
+/// resting orders on `opposite` before `max_coin_qty` lots of other orders
+/// fill it. Resting orders for which `skip` returns true are passed over.
+pub(crate) fn would_self_trade(
+    opposite: &Slab,
+    side: Side,
+    limit_price: u64,
+    max_coin_qty: u64,
+    owner: &[u64; 4],
+    skip: impl Fn(&LeafNode) -> bool,
+) -> bool {
+    let (prices, descending) = match side {
+        Side::Bid => (0..=limit_price, false),
+        Side::Ask => (limit_price..=std::u64::MAX, true),
+    };
+    let mut crossing = opposite.range(prices);
+    let mut coin_qty_remaining = max_coin_qty;
+    while coin_qty_remaining > 0 {
+        let next = if descending {
+            crossing.next_back()
+        } else {
+            crossing.next()
+        };
+        let resting = match next {
+            Some(resting) => resting,
+            None => break,
+        };
+        if skip(resting) {
+            continue;
+        }
+        if resting.owner() == owner {
+            return true;
+        }
+        coin_qty_remaining = coin_qty_remaining.saturating_sub(resting.total_quantity());
+    }
+    false
+}
+
+/// The number of coin lots an ask has to sell at `price` to receive at least
+/// `native_pc_qty` after the taker fee.
+fn lots_to_receive(
+    native_pc_qty: u64,
+    price: u64,
+    pc_lot_size: u64,
+    fee_schedule: &FeeSchedule,
+    fee_tier: FeeTier,
+) -> DexResult<u64> {
+    let native_pc_per_lot = price.checked_mul(pc_lot_size).ok_or(assertion_error!())? as u128;
+    let native_pc_qty_traded = fee_schedule.ask_taker_gross_pc_qty(fee_tier, native_pc_qty);
+    let lots = (native_pc_qty_traded + (native_pc_per_lot - 1)) / native_pc_per_lot;
+    Ok(lots.try_into().map_err(|_| assertion_error!())?)
+}
+
 pub struct OrderBookState<'a> {
     // first byte of a key is 0xaa or 0xbb, disambiguating bids and asks
     pub bids: &'a mut Slab,
     pub asks: &'a mut Slab,
     pub market_state: &'a mut MarketState,
+    pub clock: &'a Clock,
 }
 
 impl<'ob> OrderBookState<'ob> {
@@ -68,22 +244,51 @@ impl<'ob> OrderBookState<'ob> {
         }
     }
 
+    /// Matches requests until `limit` is used up or the request queue is
+    /// empty, and returns how much of `limit` was used.
     pub fn process_requests(
         &mut self,
         req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
+        trigger_book: &mut TriggerBook,
         limit: u16,
-    ) -> Result<(), DexError> {
+    ) -> Result<u16, DexError> {
+        let mode = self.market_state.mode();
+        if mode == MarketMode::Halted {
+            return Ok(0);
+        }
         let mut limit_remaining = limit;
+        let mut scanned_at_price = None;
         while limit_remaining > 0 {
+            // stop orders can only fire after a trade moved the price
+            let last_traded_price = self.market_state.last_traded_price;
+            if mode == MarketMode::Active && scanned_at_price != Some(last_traded_price) {
+                scanned_at_price = Some(last_traded_price);
+                Self::fire_stop_orders(last_traded_price, trigger_book, req_q, &mut limit_remaining);
+                if limit_remaining == 0 {
+                    break;
+                }
+            }
+
             info!("Processing request");
-            let request = match req_q.peek_front_mut() {
-                Some(r) => r,
+            // copied out, since refilling an iceberg order draws a new
+            // sequence number from the queue
+            let request = match req_q.peek_front() {
+                Some(r) => *r,
                 None => break,
             };
-            match self.process_orderbook_request(request, event_q, &mut limit_remaining)? {
+            let first_event = event_q.len();
+            let remaining_request = self.process_orderbook_request(
+                &request,
+                req_q,
+                event_q,
+                trigger_book,
+                &mut limit_remaining,
+            )?;
+            self.stamp_book_checksums(event_q, first_event);
+            match remaining_request {
                 Some(remaining_request) => {
-                    *request = remaining_request;
+                    *req_q.peek_front_mut().unwrap() = remaining_request;
                 }
                 None => {
                     info!("popping request from queue...");
@@ -92,13 +297,137 @@ impl<'ob> OrderBookState<'ob> {
             };
         }
 
+        Ok(limit - limit_remaining)
+    }
+
+    /// Records the checksum of the book each event's order sits on in every
+    /// event pushed since the queue held `first_event` events, so subscribers
+    /// can check their copy of the book once they have applied them. Anything
+    /// here that pushes events has to call this once it is done with the book.
+    fn stamp_book_checksums(&self, event_q: &mut EventQueue, first_event: u64) {
+        let bids_checksum = self.bids.checksum();
+        let asks_checksum = self.asks.checksum();
+        for event in event_q.iter_mut().skip(first_event as usize) {
+            let checksum = match event.side() {
+                Side::Bid => bids_checksum,
+                Side::Ask => asks_checksum,
+            };
+            event.set_book_checksum(checksum);
+        }
+    }
+
+    /// Moves every stop order whose trigger condition is met by
+    /// `last_traded_price` onto the request queue, where it is matched like
+    /// any other new order. The funds for it were locked when it was placed.
+    fn fire_stop_orders(
+        last_traded_price: u64,
+        trigger_book: &mut TriggerBook,
+        req_q: &mut RequestQueue,
+        limit: &mut u16,
+    ) {
+        let mut index = 0;
+        while index < trigger_book.len() && *limit > 0 && !req_q.full() {
+            if !trigger_book.get(index).unwrap().is_triggered(last_traded_price) {
+                index += 1;
+                continue;
+            }
+            let stop_order = trigger_book.swap_remove(index);
+            req_q.push_back(*stop_order.request()).unwrap();
+            *limit -= 1;
+        }
+    }
+
+    /// Evicts expired orders from the top of both sides of the book, stopping
+    /// at the first live order on each side or after `limit` evictions.
+    pub fn prune_expired_orders(&mut self, event_q: &mut EventQueue, limit: u16) -> DexResult {
+        let clock = self.clock;
+        let first_event = event_q.len();
+        let mut limit_remaining = limit;
+        for &side in &[Side::Bid, Side::Ask] {
+            while limit_remaining > 0 {
+                let best_h = match self.find_bbo(side) {
+                    Some(h) => h,
+                    None => break,
+                };
+                let best_ref = self
+                    .orders_mut(side)
+                    .get_mut(best_h)
+                    .unwrap()
+                    .as_leaf_mut()
+                    .unwrap();
+                if !order_expired(best_ref, clock) {
+                    break;
+                }
+                let order_id = *best_ref.order_id();
+                self.evict_expired_order(side, &order_id, event_q)?;
+                limit_remaining -= 1;
+            }
+        }
+        self.stamp_book_checksums(event_q, first_event);
+        Ok(())
+    }
+
+    fn evict_expired_order(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        let order = self.orders_mut(side).remove_by_key(order_id).unwrap();
+        let native_qty_unlocked = match side {
+            Side::Bid => {
+                order.total_quantity() * order.price().get() * self.market_state.pc_lot_size
+            }
+            Side::Ask => order.total_quantity() * self.market_state.coin_lot_size,
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                order_id,
+                owner: order.owner(),
+                owner_slot: order.owner_slot(),
+                client_order_id: NonZeroU64::new(order.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(())
+    }
+
+    /// Shows the next slice of an iceberg order whose visible quantity ran
+    /// out. The slice is re-keyed with a fresh sequence number, so it queues
+    /// behind the orders already resting at its price.
+    fn refill_iceberg(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+        req_q: &mut RequestQueue,
+        event_q: &mut EventQueue,
+    ) -> DexResult {
+        let orders = self.orders_mut(side);
+        let mut order = orders.remove_by_key(order_id).unwrap();
+        let new_order_id = req_q.gen_order_id(order.price().get(), side);
+        order.refill(new_order_id);
+        orders.insert_leaf(&order).unwrap();
+        event_q
+            .push_back(Event::new(EventView::Refill {
+                side,
+                order_id,
+                new_order_id,
+                owner: order.owner(),
+                owner_slot: order.owner_slot(),
+                client_order_id: NonZeroU64::new(order.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
         Ok(())
     }
 
     fn process_orderbook_request(
         &mut self,
         request: &Request,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
+        trigger_book: &mut TriggerBook,
         limit: &mut u16,
     ) -> DexResult<Option<Request>> {
         info!("OrderBookState::process_orderbook_request");
@@ -113,35 +442,65 @@ impl<'ob> OrderBookState<'ob> {
                 max_coin_qty,
                 native_pc_qty_locked,
                 client_order_id,
-            } => self
-                .new_order(
-                    NewOrderParams {
-                        side,
-                        order_type,
-                        order_id,
-                        owner,
-                        owner_slot,
-                        fee_tier,
-                        max_coin_qty,
-                        native_pc_qty_locked,
-                        client_order_id: client_order_id.map_or(0, NonZeroU64::get),
-                    },
-                    event_q,
-                    limit,
-                )?
-                .map(|remaining| {
-                    Request::new(RequestView::NewOrder {
-                        side,
-                        order_type,
-                        order_id,
-                        owner_slot,
-                        fee_tier,
-                        owner,
-                        max_coin_qty: remaining.coin_qty_remaining,
-                        native_pc_qty_locked: remaining.native_pc_qty_remaining,
-                        client_order_id,
+                self_trade_behavior,
+                expiry_kind,
+                expiry,
+                display_qty,
+                replaces,
+                native_pc_qty_to_receive,
+                referrer,
+            } => {
+                let mut params = NewOrderParams {
+                    side,
+                    order_type,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    fee_tier,
+                    max_coin_qty,
+                    native_pc_qty_locked,
+                    client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    self_trade_behavior,
+                    expiry_kind,
+                    expiry,
+                    display_qty,
+                    native_pc_qty_to_receive,
+                    referred: referrer.is_some(),
+                };
+                if let Some(ref replaced) = replaces {
+                    match self.cancel_replaced_order(replaced, &params, event_q)? {
+                        Some((max_coin_qty, native_pc_qty_locked)) => {
+                            params.max_coin_qty = max_coin_qty;
+                            params.native_pc_qty_locked = native_pc_qty_locked;
+                        }
+                        None => {
+                            *limit -= 1;
+                            return Ok(None);
+                        }
+                    }
+                }
+                self.new_order(params, req_q, event_q, limit)?
+                    .map(|remaining| {
+                        Request::new(RequestView::NewOrder {
+                            side,
+                            order_type,
+                            order_id,
+                            owner_slot,
+                            fee_tier,
+                            owner,
+                            max_coin_qty: remaining.coin_qty_remaining,
+                            native_pc_qty_locked: remaining.native_pc_qty_remaining,
+                            client_order_id,
+                            self_trade_behavior,
+                            expiry_kind,
+                            expiry,
+                            display_qty,
+                            replaces: None,
+                            native_pc_qty_to_receive: remaining.native_pc_qty_to_receive,
+                            referrer,
+                        })
                     })
-                }),
+            }
             RequestView::CancelOrder {
                 side,
                 order_id,
@@ -151,7 +510,8 @@ impl<'ob> OrderBookState<'ob> {
                 cancel_id: _,
             } => {
                 *limit -= 1;
-                self.cancel_order(
+                let stop_order_cancelled = Self::cancel_stop_order(
+                    trigger_book,
                     side,
                     order_id,
                     expected_owner,
@@ -159,6 +519,34 @@ impl<'ob> OrderBookState<'ob> {
                     client_order_id,
                     event_q,
                 )?;
+                if !stop_order_cancelled {
+                    self.cancel_order(
+                        side,
+                        order_id,
+                        expected_owner,
+                        expected_owner_slot,
+                        client_order_id,
+                        event_q,
+                    )?;
+                }
+                None
+            }
+            RequestView::ReduceOrder {
+                side,
+                order_id,
+                new_qty,
+                expected_owner_slot,
+                expected_owner,
+            } => {
+                *limit -= 1;
+                self.reduce_order(
+                    side,
+                    order_id,
+                    new_qty,
+                    expected_owner,
+                    expected_owner_slot,
+                    event_q,
+                )?;
                 None
             }
         })
@@ -175,11 +563,18 @@ struct NewOrderParams<'a> {
     max_coin_qty: NonZeroU64,
     native_pc_qty_locked: Option<NonZeroU64>,
     client_order_id: u64,
+    self_trade_behavior: SelfTradeBehavior,
+    expiry_kind: ExpiryKind,
+    expiry: u64,
+    display_qty: Option<NonZeroU64>,
+    native_pc_qty_to_receive: Option<NonZeroU64>,
+    referred: bool,
 }
 
 struct OrderRemaining {
     coin_qty_remaining: NonZeroU64,
     native_pc_qty_remaining: Option<NonZeroU64>,
+    native_pc_qty_to_receive: Option<NonZeroU64>,
 }
 
 impl<'ob> OrderBookState<'ob> {
@@ -188,6 +583,7 @@ impl<'ob> OrderBookState<'ob> {
 
         params: NewOrderParams,
 
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
         limit: &mut u16,
     ) -> DexResult<Option<OrderRemaining>> {
@@ -202,13 +598,53 @@ impl<'ob> OrderBookState<'ob> {
             mut max_coin_qty,
             mut native_pc_qty_locked,
             client_order_id,
+            self_trade_behavior,
+            expiry_kind,
+            expiry,
+            display_qty,
+            mut native_pc_qty_to_receive,
+            referred,
         } = params;
         let (post_only, post_allowed) = match order_type {
             OrderType::Limit => (false, true),
             OrderType::ImmediateOrCancel => (false, false),
             OrderType::PostOnly => (true, true),
+            OrderType::FillOrKill => (false, false),
         };
+        let mode = self.market_state.mode();
+        let post_only = post_only || mode == MarketMode::PostOnly;
         let limit_price = extract_price_from_order_id(order_id);
+        // orders that expired while queued, or were queued before the market
+        // went cancel-only, never touch the book, and neither do orders that
+        // abort on a self-trade and would now run into one
+        let killed = mode == MarketMode::CancelOnly
+            || expiry_kind.is_expired(expiry, self.clock)
+            || (self_trade_behavior == SelfTradeBehavior::AbortTransaction
+                && self.would_self_trade(side, limit_price, owner, max_coin_qty))
+            || (order_type == OrderType::FillOrKill
+                && !self.can_fill_completely(&params, limit_price, *limit));
+        if killed {
+            *limit -= 1;
+            let native_qty_unlocked = match side {
+                Side::Bid => native_pc_qty_locked.unwrap().get(),
+                Side::Ask => self.native_ask_qty_locked(fee_tier, max_coin_qty.get()),
+            };
+            event_q
+                .push_back(Event::new(EventView::Out {
+                    side,
+                    native_qty_unlocked,
+                    native_qty_still_locked: 0,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    client_order_id: NonZeroU64::new(client_order_id),
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+            return Ok(None);
+        }
+        // a fill-or-kill order passed its dry run against the book as it is
+        // now within what is left of `limit`, so it is matched to the end
+        // before anything else can change the book
         while *limit > 0 {
             *limit -= 1;
             let remaining_order = match side {
@@ -224,7 +660,13 @@ impl<'ob> OrderBookState<'ob> {
                         post_only,
                         post_allowed,
                         client_order_id,
+                        self_trade_behavior,
+                        expiry_kind,
+                        expiry,
+                        display_qty,
+                        referred,
                     },
+                    req_q,
                     event_q,
                 ),
                 Side::Ask => {
@@ -240,7 +682,14 @@ impl<'ob> OrderBookState<'ob> {
                             post_only,
                             post_allowed,
                             client_order_id,
+                            self_trade_behavior,
+                            expiry_kind,
+                            expiry,
+                            display_qty,
+                            native_pc_qty_to_receive,
+                            referred,
                         },
+                        req_q,
                         event_q,
                     )
                 }
@@ -252,12 +701,118 @@ impl<'ob> OrderBookState<'ob> {
                 Some(remaining_order) => {
                     max_coin_qty = remaining_order.coin_qty_remaining;
                     native_pc_qty_locked = remaining_order.native_pc_qty_remaining;
+                    native_pc_qty_to_receive = remaining_order.native_pc_qty_to_receive;
                 }
                 None => break,
             };
         }
         Ok(None)
     }
+
+    fn would_self_trade(
+        &self,
+        side: Side,
+        limit_price: u64,
+        owner: &[u64; 4],
+        max_coin_qty: NonZeroU64,
+    ) -> bool {
+        let opposite = match side {
+            Side::Bid => &*self.asks,
+            Side::Ask => &*self.bids,
+        };
+        let clock = self.clock;
+        would_self_trade(
+            opposite,
+            side,
+            limit_price,
+            max_coin_qty.get(),
+            owner,
+            |resting| order_expired(resting, clock),
+        )
+    }
+
+    /// Walks the opposite side of the book without touching it and reports
+    /// whether an immediate-or-cancel order would be filled in full within
+    /// `limit` matching steps, each of which takes one resting order.
+    fn can_fill_completely(&self, order: &NewOrderParams, limit_price: u64, limit: u16) -> bool {
+        let NewOrderParams {
+            side,
+            owner,
+            fee_tier,
+            max_coin_qty,
+            native_pc_qty_locked,
+            native_pc_qty_to_receive,
+            self_trade_behavior,
+            ..
+        } = *order;
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let fee_schedule = self.market_state.fee_schedule;
+        let mut coin_qty_remaining = max_coin_qty.get();
+        let mut pc_qty_remaining = native_pc_qty_locked.map_or(std::u64::MAX, |locked| {
+            fee_schedule.remove_taker_fee(fee_tier, locked.get()) / pc_lot_size
+        });
+        let mut native_pc_qty_to_receive = native_pc_qty_to_receive.map(NonZeroU64::get);
+        let clock = self.clock;
+        let mut filled = false;
+        let mut steps_remaining = limit;
+        let (book, descending) = match side {
+            Side::Bid => (&*self.asks, false),
+            Side::Ask => (&*self.bids, true),
+        };
+        book.walk_leaves(descending, |resting| {
+            let price = resting.price().get();
+            let crossed = match side {
+                Side::Bid => price <= limit_price,
+                Side::Ask => price >= limit_price,
+            };
+            if !crossed || steps_remaining == 0 {
+                return false;
+            }
+            steps_remaining -= 1;
+            if order_expired(resting, clock) {
+                // evicted rather than matched
+                return true;
+            }
+            if resting.owner() == owner {
+                // a self-trade either shrinks the taker without filling it or
+                // stops matching, unless the resting order just gets cancelled
+                return self_trade_behavior == SelfTradeBehavior::CancelProvide;
+            }
+            let mut trade_qty = resting
+                .total_quantity()
+                .min(coin_qty_remaining)
+                .min(pc_qty_remaining / price);
+            if let Some(to_receive) = native_pc_qty_to_receive {
+                let lots = match lots_to_receive(
+                    to_receive,
+                    price,
+                    pc_lot_size,
+                    &fee_schedule,
+                    fee_tier,
+                ) {
+                    Ok(lots) => lots,
+                    // stops the walk, so the order is killed
+                    Err(_) => return false,
+                };
+                trade_qty = trade_qty.min(lots);
+                let native_pc_qty = trade_qty * price * pc_lot_size;
+                native_pc_qty_to_receive = Some(to_receive.saturating_sub(
+                    native_pc_qty - fee_schedule.ask_taker_pc_fee(fee_tier, native_pc_qty),
+                ));
+            }
+            coin_qty_remaining -= trade_qty;
+            pc_qty_remaining -= trade_qty * price;
+            filled = match native_pc_qty_to_receive {
+                // an ask sized in pc must receive all of it before its coin
+                // runs out
+                Some(to_receive) => to_receive == 0,
+                // a bid is also done once its locked pc can't buy another lot
+                None => coin_qty_remaining == 0 || trade_qty == 0,
+            };
+            !filled && coin_qty_remaining > 0
+        });
+        filled
+    }
 }
 
 struct NewAskParams<'a> {
@@ -270,12 +825,22 @@ struct NewAskParams<'a> {
     post_only: bool,
     post_allowed: bool,
     client_order_id: u64,
+    self_trade_behavior: SelfTradeBehavior,
+    expiry_kind: ExpiryKind,
+    expiry: u64,
+    display_qty: Option<NonZeroU64>,
+    native_pc_qty_to_receive: Option<NonZeroU64>,
+    referred: bool,
 }
 
 impl<'ob> OrderBookState<'ob> {
+    // each call takes at most one matching step, in a `loop` that is only
+    // there to be broken out of with whether the order is done
+    #[allow(clippy::never_loop)]
     fn new_ask(
         &mut self,
         params: NewAskParams,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
     ) -> DexResult<Option<OrderRemaining>> {
         info!("OrderBookState::new_ask");
@@ -289,14 +854,28 @@ impl<'ob> OrderBookState<'ob> {
             post_only,
             post_allowed,
             client_order_id,
+            self_trade_behavior,
+            expiry_kind,
+            expiry,
+            display_qty,
+            native_pc_qty_to_receive,
+            referred,
         } = params;
         let mut unfilled_qty = max_qty.get();
         let mut accum_fill_price = 0;
 
         let pc_lot_size = self.market_state.pc_lot_size;
         let coin_lot_size = self.market_state.coin_lot_size;
+        let fee_schedule = self.market_state.fee_schedule;
+        let clock = self.clock;
+
+        // with fees in coin, the ask locked a reserve on top of its lots
+        let coin_fees = fee_schedule.coin_fees_on_asks();
+        let coin_fee_reserve =
+            |lots: u64| fee_schedule.native_coin_fee_reserve(fee_tier, lots * coin_lot_size);
 
         let mut accum_maker_rebates = 0;
+        let mut last_traded_price = None;
         let crossed;
         let done = loop {
             let best_bid_h = match self.find_bbo(Side::Bid) {
@@ -317,97 +896,258 @@ impl<'ob> OrderBookState<'ob> {
             let trade_price = best_bid_ref.price();
             crossed = limit_price <= trade_price;
 
+            if order_expired(best_bid_ref, clock) {
+                let best_bid_id = *best_bid_ref.order_id();
+                self.evict_expired_order(Side::Bid, &best_bid_id, event_q)?;
+                break false;
+            }
+
             if !crossed || post_only {
                 break true;
             }
 
-            let bid_size = best_bid_ref.quantity();
-            let trade_qty = bid_size.min(unfilled_qty);
+            let bid_size = best_bid_ref.total_quantity();
+            let mut trade_qty = bid_size.min(unfilled_qty);
+            if let Some(to_receive) = native_pc_qty_to_receive {
+                trade_qty = trade_qty.min(lots_to_receive(
+                    to_receive.get(),
+                    trade_price.get(),
+                    pc_lot_size,
+                    &fee_schedule,
+                    fee_tier,
+                )?);
+            }
 
             if trade_qty == 0 {
                 break true;
             }
 
+            if best_bid_ref.owner() == owner {
+                let cancelled_provide_qty;
+                let cancelled_take_qty;
+                match self_trade_behavior {
+                    SelfTradeBehavior::DecrementTake => {
+                        cancelled_provide_qty = trade_qty;
+                        cancelled_take_qty = trade_qty;
+                    }
+                    SelfTradeBehavior::CancelProvide => {
+                        cancelled_provide_qty = bid_size;
+                        cancelled_take_qty = 0;
+                    }
+                    // ruled out before matching starts, see `new_order`
+                    SelfTradeBehavior::AbortTransaction => break true,
+                };
+
+                let remaining_provide_qty = bid_size - cancelled_provide_qty;
+                let best_bid_id = *best_bid_ref.order_id();
+                let native_pc_per_lot = trade_price.get() * pc_lot_size;
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side: Side::Bid,
+                        native_qty_unlocked: cancelled_provide_qty * native_pc_per_lot,
+                        native_qty_still_locked: remaining_provide_qty * native_pc_per_lot,
+                        order_id: &best_bid_id,
+                        owner: best_bid_ref.owner(),
+                        owner_slot: best_bid_ref.owner_slot(),
+                        client_order_id: NonZeroU64::new(best_bid_ref.client_order_id()),
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+                if remaining_provide_qty == 0 {
+                    self.orders_mut(Side::Bid)
+                        .remove_by_key(&best_bid_id)
+                        .unwrap();
+                } else {
+                    let needs_refill = self
+                        .orders_mut(Side::Bid)
+                        .update_leaf(best_bid_h, |leaf| {
+                            leaf.take_quantity(cancelled_provide_qty);
+                            leaf.needs_refill()
+                        })
+                        .unwrap();
+                    if needs_refill {
+                        self.refill_iceberg(Side::Bid, &best_bid_id, req_q, event_q)?;
+                    }
+                }
+
+                if cancelled_take_qty > 0 {
+                    let native_reserve_unlocked = coin_fee_reserve(unfilled_qty)
+                        - coin_fee_reserve(unfilled_qty - cancelled_take_qty);
+                    unfilled_qty -= cancelled_take_qty;
+                    event_q
+                        .push_back(Event::new(EventView::Out {
+                            side: Side::Ask,
+                            native_qty_unlocked: cancelled_take_qty * coin_lot_size
+                                + native_reserve_unlocked,
+                            native_qty_still_locked: unfilled_qty * coin_lot_size
+                                + coin_fee_reserve(unfilled_qty),
+                            order_id,
+                            owner,
+                            owner_slot,
+                            client_order_id: NonZeroU64::new(client_order_id),
+                        }))
+                        .map_err(|_| DexErrorCode::EventQueueFull)?;
+                    if unfilled_qty == 0 {
+                        return Ok(None);
+                    }
+                }
+
+                break false;
+            }
+
             let maker_fee_tier = best_bid_ref.fee_tier();
             let native_maker_pc_qty = trade_qty * trade_price.get() * pc_lot_size;
-            let native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+            let native_maker_coin_qty = trade_qty * coin_lot_size;
+            let native_maker_rebate;
+            let native_maker_qty_paid;
+            let native_maker_qty_received;
+            if coin_fees {
+                native_maker_rebate =
+                    fee_schedule.maker_rebate(maker_fee_tier, native_maker_coin_qty);
+                native_maker_qty_paid = native_maker_pc_qty;
+                native_maker_qty_received = native_maker_coin_qty + native_maker_rebate;
+            } else {
+                native_maker_rebate =
+                    fee_schedule.maker_rebate(maker_fee_tier, native_maker_pc_qty);
+                native_maker_qty_paid = native_maker_pc_qty - native_maker_rebate;
+                native_maker_qty_received = native_maker_coin_qty;
+            }
             accum_maker_rebates += native_maker_rebate;
 
             let maker_fill = Event::new(EventView::Fill {
                 side: Side::Bid,
                 maker: true,
-                native_qty_paid: native_maker_pc_qty - native_maker_rebate,
-                native_qty_received: trade_qty * coin_lot_size,
+                native_qty_paid: native_maker_qty_paid,
+                native_qty_received: native_maker_qty_received,
                 native_fee_or_rebate: native_maker_rebate,
                 order_id: best_bid_ref.order_id(),
                 owner: best_bid_ref.owner(),
                 owner_slot: best_bid_ref.owner_slot(),
                 fee_tier: maker_fee_tier,
                 client_order_id: NonZeroU64::new(best_bid_ref.client_order_id()),
+                referred: false,
             });
             event_q
                 .push_back(maker_fill)
                 .map_err(|_| DexErrorCode::EventQueueFull)?;
 
-            *best_bid_ref.quantity_mut() -= trade_qty;
+            let best_bid = self
+                .orders_mut(Side::Bid)
+                .update_leaf(best_bid_h, |leaf| {
+                    leaf.take_quantity(trade_qty);
+                    *leaf
+                })
+                .unwrap();
             unfilled_qty -= trade_qty;
             accum_fill_price += trade_qty * trade_price.get();
+            last_traded_price = Some(trade_price);
 
-            if best_bid_ref.quantity() == 0 {
-                let best_bid_id = *best_bid_ref.order_id();
+            if best_bid.total_quantity() == 0 {
+                let best_bid_id = *best_bid.order_id();
                 event_q
                     .push_back(Event::new(EventView::Out {
                         side: Side::Bid,
                         native_qty_unlocked: 0,
                         native_qty_still_locked: 0,
                         order_id: &best_bid_id,
-                        owner: best_bid_ref.owner(),
-                        owner_slot: best_bid_ref.owner_slot(),
-                        client_order_id: NonZeroU64::new(best_bid_ref.client_order_id()),
+                        owner: best_bid.owner(),
+                        owner_slot: best_bid.owner_slot(),
+                        client_order_id: NonZeroU64::new(best_bid.client_order_id()),
                     }))
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
                 self.orders_mut(Side::Bid)
                     .remove_by_key(&best_bid_id)
                     .unwrap();
+            } else if best_bid.needs_refill() {
+                let best_bid_id = *best_bid.order_id();
+                self.refill_iceberg(Side::Bid, &best_bid_id, req_q, event_q)?;
             }
 
             break false;
         };
 
         let native_taker_pc_qty = accum_fill_price * pc_lot_size;
-        let native_taker_fee = fee_tier.taker_fee(native_taker_pc_qty);
+        let native_taker_fee = fee_schedule.ask_taker_pc_fee(fee_tier, native_taker_pc_qty);
+        // charging the difference in reserves adds up to the fee on all the
+        // coin sold and leaves exactly the reserve for what is unfilled
+        let native_taker_coin_fee =
+            coin_fee_reserve(max_qty.get()) - coin_fee_reserve(unfilled_qty);
         if native_taker_pc_qty > 0 {
             let taker_fill = Event::new(EventView::Fill {
                 side: Side::Ask,
                 maker: false,
-                native_qty_paid: (max_qty.get() - unfilled_qty) * coin_lot_size,
+                native_qty_paid: (max_qty.get() - unfilled_qty) * coin_lot_size
+                    + native_taker_coin_fee,
                 native_qty_received: native_taker_pc_qty - native_taker_fee,
-                native_fee_or_rebate: native_taker_fee,
+                native_fee_or_rebate: native_taker_fee + native_taker_coin_fee,
                 order_id,
                 owner,
                 owner_slot,
                 fee_tier,
                 client_order_id: NonZeroU64::new(client_order_id),
+                // fees paid in coin are not shared with referrers
+                referred: referred && !coin_fees,
             });
             event_q
                 .push_back(taker_fill)
                 .map_err(|_| DexErrorCode::EventQueueFull)?;
         }
 
-        let net_fees = native_taker_fee - accum_maker_rebates;
-        self.market_state.pc_fees_accrued += net_fees;
-        self.market_state.pc_deposits_total -= net_fees;
+        // an ask sized in pc is done once it has received enough
+        let sized_in_pc = native_pc_qty_to_receive.is_some();
+        let native_pc_qty_to_receive = native_pc_qty_to_receive.and_then(|to_receive| {
+            NonZeroU64::new(
+                to_receive
+                    .get()
+                    .saturating_sub(native_taker_pc_qty - native_taker_fee),
+            )
+        });
+        let done = done || (sized_in_pc && native_pc_qty_to_receive.is_none());
+
+        if coin_fees {
+            let net_fees = native_taker_coin_fee - accum_maker_rebates;
+            self.market_state.coin_fees_accrued += net_fees;
+            self.market_state.coin_deposits_total -= net_fees;
+        } else {
+            // the referrer's share stays in the vault until the taker settles
+            let native_referrer_rebate = if referred {
+                fee_schedule.referrer_rebate(native_taker_fee)
+            } else {
+                0
+            };
+            let net_fees = native_taker_fee - native_referrer_rebate - accum_maker_rebates;
+            self.market_state.pc_fees_accrued += net_fees;
+            self.market_state.pc_deposits_total -= net_fees;
+        }
+        if let Some(price) = last_traded_price {
+            self.market_state.last_traded_price = price.get();
+        }
 
         if !done {
             if let Some(coin_qty_remaining) = NonZeroU64::new(unfilled_qty) {
                 return Ok(Some(OrderRemaining {
                     coin_qty_remaining,
                     native_pc_qty_remaining: None,
+                    native_pc_qty_to_receive,
                 }));
             }
         }
 
         if post_allowed && !crossed && unfilled_qty > 0 {
+            // resting orders never take, so their fee reserve is released
+            let native_reserve_unlocked = coin_fee_reserve(unfilled_qty);
+            if native_reserve_unlocked > 0 {
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side: Side::Ask,
+                        native_qty_unlocked: native_reserve_unlocked,
+                        native_qty_still_locked: unfilled_qty * coin_lot_size,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id: NonZeroU64::new(client_order_id),
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+            }
             let offers = self.orders_mut(Side::Ask);
             let new_order = LeafNode::new(
                 owner_slot,
@@ -416,32 +1156,35 @@ impl<'ob> OrderBookState<'ob> {
                 unfilled_qty,
                 fee_tier,
                 client_order_id,
-            );
+            )
+            .with_expiry(expiry_kind, expiry)
+            .with_display_quantity(display_qty);
             let insert_result = offers.insert_leaf(&new_order);
             if let Err(SlabTreeError::OutOfSpace) = insert_result {
-                // boot out the least aggressive offer
-                info!("offers full! booting...");
-                let order = offers.remove_max().unwrap();
-                let out = Event::new(EventView::Out {
-                    side: Side::Ask,
-                    native_qty_unlocked: order.quantity() * coin_lot_size,
-                    native_qty_still_locked: 0,
-                    order_id: order.order_id(),
-                    owner: order.owner(),
-                    owner_slot: order.owner_slot(),
-                    client_order_id: NonZeroU64::new(order.client_order_id()),
-                });
-                event_q
-                    .push_back(out)
-                    .map_err(|_| DexErrorCode::EventQueueFull)?;
-                offers.insert_leaf(&new_order).unwrap();
+                info!("offers full!");
+                if self.make_room(Side::Ask, event_q)? {
+                    self.asks.insert_leaf(&new_order).unwrap();
+                } else {
+                    let out = Event::new(EventView::Out {
+                        side: Side::Ask,
+                        native_qty_unlocked: unfilled_qty * coin_lot_size,
+                        native_qty_still_locked: 0,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id: NonZeroU64::new(client_order_id),
+                    });
+                    event_q
+                        .push_back(out.book_full())
+                        .map_err(|_| DexErrorCode::EventQueueFull)?;
+                }
             } else {
                 insert_result.unwrap();
             }
         } else {
             let out = Event::new(EventView::Out {
                 side: Side::Ask,
-                native_qty_unlocked: unfilled_qty * coin_lot_size,
+                native_qty_unlocked: unfilled_qty * coin_lot_size + coin_fee_reserve(unfilled_qty),
                 native_qty_still_locked: 0,
                 order_id,
                 owner,
@@ -468,12 +1211,20 @@ struct NewBidParams<'a> {
     post_only: bool,
     post_allowed: bool,
     client_order_id: u64,
+    self_trade_behavior: SelfTradeBehavior,
+    expiry_kind: ExpiryKind,
+    expiry: u64,
+    display_qty: Option<NonZeroU64>,
+    referred: bool,
 }
 
 impl<'ob> OrderBookState<'ob> {
+    // see `new_ask`
+    #[allow(clippy::never_loop)]
     fn new_bid(
         &mut self,
         params: NewBidParams,
+        req_q: &mut RequestQueue,
         event_q: &mut EventQueue,
     ) -> DexResult<Option<OrderRemaining>> {
         info!("OrderBookState::new_bid");
@@ -488,6 +1239,11 @@ impl<'ob> OrderBookState<'ob> {
             post_only,
             post_allowed,
             client_order_id,
+            self_trade_behavior,
+            expiry_kind,
+            expiry,
+            display_qty,
+            referred,
         } = params;
         if post_allowed {
             check_assert!(limit_price.is_some())?;
@@ -495,12 +1251,16 @@ impl<'ob> OrderBookState<'ob> {
 
         let pc_lot_size = self.market_state.pc_lot_size;
         let coin_lot_size = self.market_state.coin_lot_size;
+        let fee_schedule = self.market_state.fee_schedule;
+        let clock = self.clock;
 
-        let max_pc_qty = fee_tier.remove_taker_fee(native_pc_qty_locked.get()) / pc_lot_size;
+        let max_pc_qty =
+            fee_schedule.remove_taker_fee(fee_tier, native_pc_qty_locked.get()) / pc_lot_size;
 
         let mut coin_qty_remaining = max_coin_qty.get();
         let mut pc_qty_remaining = max_pc_qty;
         let mut accum_maker_rebates = 0;
+        let mut last_traded_price = None;
 
         let crossed;
         let done = loop {
@@ -523,11 +1283,18 @@ impl<'ob> OrderBookState<'ob> {
             crossed = limit_price
                 .map(|limit_price| limit_price >= trade_price)
                 .unwrap_or(true);
+
+            if order_expired(best_offer_ref, clock) {
+                let best_offer_id = *best_offer_ref.order_id();
+                self.evict_expired_order(Side::Ask, &best_offer_id, event_q)?;
+                break false;
+            }
+
             if !crossed || post_only {
                 break true;
             }
 
-            let offer_size = best_offer_ref.quantity();
+            let offer_size = best_offer_ref.total_quantity();
             let trade_qty = offer_size
                 .min(coin_qty_remaining)
                 .min(pc_qty_remaining / best_offer_ref.price().get());
@@ -536,9 +1303,62 @@ impl<'ob> OrderBookState<'ob> {
                 break true;
             }
 
+            if best_offer_ref.owner() == owner {
+                let cancelled_provide_qty;
+                let cancelled_take_qty;
+                match self_trade_behavior {
+                    SelfTradeBehavior::DecrementTake => {
+                        cancelled_provide_qty = trade_qty;
+                        cancelled_take_qty = trade_qty;
+                    }
+                    SelfTradeBehavior::CancelProvide => {
+                        cancelled_provide_qty = offer_size;
+                        cancelled_take_qty = 0;
+                    }
+                    // ruled out before matching starts, see `new_order`
+                    SelfTradeBehavior::AbortTransaction => break true,
+                };
+
+                let remaining_provide_qty = offer_size - cancelled_provide_qty;
+                let best_offer_id = *best_offer_ref.order_id();
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side: Side::Ask,
+                        native_qty_unlocked: cancelled_provide_qty * coin_lot_size,
+                        native_qty_still_locked: remaining_provide_qty * coin_lot_size,
+                        order_id: &best_offer_id,
+                        owner: best_offer_ref.owner(),
+                        owner_slot: best_offer_ref.owner_slot(),
+                        client_order_id: NonZeroU64::new(best_offer_ref.client_order_id()),
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+                if remaining_provide_qty == 0 {
+                    self.orders_mut(Side::Ask)
+                        .remove_by_key(&best_offer_id)
+                        .unwrap();
+                } else {
+                    let needs_refill = self
+                        .orders_mut(Side::Ask)
+                        .update_leaf(best_offer_h, |leaf| {
+                            leaf.take_quantity(cancelled_provide_qty);
+                            leaf.needs_refill()
+                        })
+                        .unwrap();
+                    if needs_refill {
+                        self.refill_iceberg(Side::Ask, &best_offer_id, req_q, event_q)?;
+                    }
+                }
+
+                // the pc backing the decremented quantity stays locked
+                // until the final `Out` for this order releases it
+                coin_qty_remaining -= cancelled_take_qty;
+                break false;
+            }
+
             let maker_fee_tier = best_offer_ref.fee_tier();
             let native_maker_pc_qty = trade_qty * trade_price.get() * pc_lot_size;
-            let native_maker_rebate = maker_fee_tier.maker_rebate(native_maker_pc_qty);
+            let native_maker_rebate =
+                fee_schedule.maker_rebate(maker_fee_tier, native_maker_pc_qty);
             accum_maker_rebates += native_maker_rebate;
 
             let maker_fill = Event::new(EventView::Fill {
@@ -552,38 +1372,49 @@ impl<'ob> OrderBookState<'ob> {
                 owner_slot: best_offer_ref.owner_slot(),
                 fee_tier: maker_fee_tier,
                 client_order_id: NonZeroU64::new(best_offer_ref.client_order_id()),
+                referred: false,
             });
             event_q
                 .push_back(maker_fill)
                 .map_err(|_| DexErrorCode::EventQueueFull)?;
 
-            *best_offer_ref.quantity_mut() -= trade_qty;
+            let best_offer = self
+                .orders_mut(Side::Ask)
+                .update_leaf(best_offer_h, |leaf| {
+                    leaf.take_quantity(trade_qty);
+                    *leaf
+                })
+                .unwrap();
             coin_qty_remaining -= trade_qty;
             pc_qty_remaining -= trade_qty * trade_price.get();
+            last_traded_price = Some(trade_price);
 
-            if best_offer_ref.quantity() == 0 {
-                let best_offer_id = *best_offer_ref.order_id();
+            if best_offer.total_quantity() == 0 {
+                let best_offer_id = *best_offer.order_id();
                 event_q
                     .push_back(Event::new(EventView::Out {
                         side: Side::Ask,
                         native_qty_unlocked: 0,
                         native_qty_still_locked: 0,
                         order_id: &best_offer_id,
-                        owner: best_offer_ref.owner(),
-                        owner_slot: best_offer_ref.owner_slot(),
-                        client_order_id: NonZeroU64::new(best_offer_ref.client_order_id()),
+                        owner: best_offer.owner(),
+                        owner_slot: best_offer.owner_slot(),
+                        client_order_id: NonZeroU64::new(best_offer.client_order_id()),
                     }))
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
                 self.orders_mut(Side::Ask)
                     .remove_by_key(&best_offer_id)
                     .unwrap();
+            } else if best_offer.needs_refill() {
+                let best_offer_id = *best_offer.order_id();
+                self.refill_iceberg(Side::Ask, &best_offer_id, req_q, event_q)?;
             }
 
             break false;
         };
 
         let native_accum_fill_price = (max_pc_qty - pc_qty_remaining) * pc_lot_size;
-        let native_taker_fee = fee_tier.taker_fee(native_accum_fill_price);
+        let native_taker_fee = fee_schedule.taker_fee(fee_tier, native_accum_fill_price);
         let native_pc_qty_remaining =
             native_pc_qty_locked.get() - native_accum_fill_price - native_taker_fee;
 
@@ -599,14 +1430,23 @@ impl<'ob> OrderBookState<'ob> {
                 owner_slot,
                 fee_tier,
                 client_order_id: NonZeroU64::new(client_order_id),
+                referred,
             });
             event_q
                 .push_back(taker_fill)
                 .map_err(|_| DexErrorCode::EventQueueFull)?;
         }
 
-        let net_fees = native_taker_fee - accum_maker_rebates;
+        let native_referrer_rebate = if referred {
+            fee_schedule.referrer_rebate(native_taker_fee)
+        } else {
+            0
+        };
+        let net_fees = native_taker_fee - native_referrer_rebate - accum_maker_rebates;
         self.market_state.pc_fees_accrued += net_fees;
+        if let Some(price) = last_traded_price {
+            self.market_state.last_traded_price = price.get();
+        }
         self.market_state.pc_deposits_total -= net_fees;
 
         if !done {
@@ -615,6 +1455,7 @@ impl<'ob> OrderBookState<'ob> {
                     return Ok(Some(OrderRemaining {
                         coin_qty_remaining,
                         native_pc_qty_remaining: Some(native_pc_qty_remaining),
+                        native_pc_qty_to_receive: None,
                     }));
                 }
             }
@@ -655,25 +1496,28 @@ impl<'ob> OrderBookState<'ob> {
                 coin_qty_to_post,
                 fee_tier,
                 client_order_id,
-            );
+            )
+            .with_expiry(expiry_kind, expiry)
+            .with_display_quantity(display_qty);
             let insert_result = bids.insert_leaf(&new_leaf);
             if let Err(SlabTreeError::OutOfSpace) = insert_result {
-                // boot out the least aggressive bid
-                info!("bids full! booting...");
-                let order = bids.remove_min().unwrap();
-                let out = Event::new(EventView::Out {
-                    side: Side::Bid,
-                    native_qty_unlocked: order.quantity() * order.price().get() * pc_lot_size,
-                    native_qty_still_locked: 0,
-                    order_id: order.order_id(),
-                    owner: order.owner(),
-                    owner_slot: order.owner_slot(),
-                    client_order_id: NonZeroU64::new(order.client_order_id()),
-                });
-                event_q
-                    .push_back(out)
-                    .map_err(|_| DexErrorCode::EventQueueFull)?;
-                bids.insert_leaf(&new_leaf).unwrap();
+                info!("bids full!");
+                if self.make_room(Side::Bid, event_q)? {
+                    self.bids.insert_leaf(&new_leaf).unwrap();
+                } else {
+                    let out = Event::new(EventView::Out {
+                        side: Side::Bid,
+                        native_qty_unlocked: pc_qty_to_keep_locked * pc_lot_size,
+                        native_qty_still_locked: 0,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id: NonZeroU64::new(client_order_id),
+                    });
+                    event_q
+                        .push_back(out.book_full())
+                        .map_err(|_| DexErrorCode::EventQueueFull)?;
+                }
             } else {
                 insert_result.unwrap();
             }
@@ -682,6 +1526,141 @@ impl<'ob> OrderBookState<'ob> {
         Ok(None)
     }
 
+    /// Evicts an order from the full `side` of the book as the market's
+    /// `BookFullPolicy` says. Returns false if the incoming order should be
+    /// cancelled instead.
+    fn make_room(&mut self, side: Side, event_q: &mut EventQueue) -> DexResult<bool> {
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let native_locked = |order: &LeafNode| match side {
+            Side::Bid => order.total_quantity() * order.price().get() * pc_lot_size,
+            Side::Ask => order.total_quantity() * coin_lot_size,
+        };
+        let order = match self.market_state.book_full_policy() {
+            BookFullPolicy::EvictWorstPrice => match side {
+                Side::Bid => self.bids.remove_min(),
+                Side::Ask => self.asks.remove_max(),
+            }
+            .unwrap(),
+            BookFullPolicy::RejectIncoming => return Ok(false),
+            BookFullPolicy::EvictSmallest => {
+                let threshold = self.market_state.book_full_notional_threshold;
+                let notional = |order: &LeafNode| {
+                    order.total_quantity() * order.price().get() * pc_lot_size
+                };
+                // scanned from the least aggressive end, so that ties evict
+                // the order furthest from the top of the book
+                let smallest = match side {
+                    Side::Bid => self
+                        .bids
+                        .iter()
+                        .take(EVICT_SMALLEST_SCAN_DEPTH)
+                        .min_by_key(|order| notional(order)),
+                    Side::Ask => self
+                        .asks
+                        .iter_desc()
+                        .take(EVICT_SMALLEST_SCAN_DEPTH)
+                        .min_by_key(|order| notional(order)),
+                };
+                match smallest {
+                    Some(order) if notional(order) < threshold => {
+                        let order_id = *order.order_id();
+                        self.orders_mut(side).remove_by_key(&order_id).unwrap()
+                    }
+                    _ => return Ok(false),
+                }
+            }
+        };
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked: native_locked(&order),
+                native_qty_still_locked: 0,
+                order_id: order.order_id(),
+                owner: order.owner(),
+                owner_slot: order.owner_slot(),
+                client_order_id: NonZeroU64::new(order.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
+        Ok(true)
+    }
+
+    /// Cancels an order that is still waiting in the trigger book. Returns
+    /// false if no stop order has this id.
+    fn cancel_stop_order(
+        trigger_book: &mut TriggerBook,
+        side: Side,
+        order_id: &u128,
+        expected_owner: &[u64; 4],
+        expected_owner_slot: u8,
+        client_order_id: Option<NonZeroU64>,
+        event_q: &mut EventQueue,
+    ) -> DexResult<bool> {
+        let index = match trigger_book.find_by_order_id(side, order_id) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+        // the order has never touched the book, so everything locked for it
+        // is released
+        let stop_order = *trigger_book.get(index).unwrap();
+        let (owner, owner_slot) = stop_order.owner();
+        if owner == expected_owner && owner_slot == expected_owner_slot {
+            trigger_book.swap_remove(index);
+            event_q
+                .push_back(Event::new(EventView::Out {
+                    side,
+                    native_qty_unlocked: stop_order.native_qty_locked(),
+                    native_qty_still_locked: 0,
+                    order_id,
+                    owner: expected_owner,
+                    owner_slot: expected_owner_slot,
+                    client_order_id,
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+        }
+        Ok(true)
+    }
+
+    /// Takes the order in `slot` of an OpenOrders account off the book or
+    /// out of the trigger book right away, without going through the request
+    /// queue, and stamps its `Out` event. Returns false if the order is in
+    /// neither, e.g. because it is still waiting in the request queue.
+    pub(crate) fn cancel_order_now(
+        &mut self,
+        trigger_book: &mut TriggerBook,
+        open_orders: &OpenOrders,
+        open_orders_address: &[u64; 4],
+        slot: u8,
+        event_q: &mut EventQueue,
+    ) -> DexResult<bool> {
+        let side = open_orders
+            .slot_side(slot)
+            .ok_or(DexErrorCode::OrderNotFound)?;
+        let order_id = open_orders.orders[slot as usize];
+        let client_order_id = NonZeroU64::new(open_orders.client_order_ids[slot as usize]);
+        let first_event = event_q.len();
+        let cancelled = Self::cancel_stop_order(
+            trigger_book,
+            side,
+            &order_id,
+            open_orders_address,
+            slot,
+            client_order_id,
+            event_q,
+        )? || self.cancel_order(
+            side,
+            &order_id,
+            open_orders_address,
+            slot,
+            client_order_id,
+            event_q,
+        )?;
+        self.stamp_book_checksums(event_q, first_event);
+        Ok(cancelled)
+    }
+
+    /// Takes an order off the book and emits its `Out` event. Returns false
+    /// if the book holds no order with this id and owner.
     fn cancel_order(
         &mut self,
         side: Side,
@@ -691,7 +1670,7 @@ impl<'ob> OrderBookState<'ob> {
         client_order_id: Option<NonZeroU64>,
 
         event_q: &mut EventQueue,
-    ) -> DexResult<()> {
+    ) -> DexResult<bool> {
         if let Some(leaf_node) = self.orders_mut(side).remove_by_key(order_id) {
             if leaf_node.owner() == expected_owner && leaf_node.owner_slot() == expected_owner_slot
             {
@@ -700,11 +1679,11 @@ impl<'ob> OrderBookState<'ob> {
                 }
                 let native_qty_unlocked = match side {
                     Side::Bid => {
-                        leaf_node.quantity()
+                        leaf_node.total_quantity()
                             * leaf_node.price().get()
                             * self.market_state.pc_lot_size
                     }
-                    Side::Ask => leaf_node.quantity() * self.market_state.coin_lot_size,
+                    Side::Ask => leaf_node.total_quantity() * self.market_state.coin_lot_size,
                 };
                 event_q
                     .push_back(Event::new(EventView::Out {
@@ -717,10 +1696,880 @@ impl<'ob> OrderBookState<'ob> {
                         client_order_id: NonZeroU64::new(leaf_node.client_order_id()),
                     }))
                     .map_err(|_| DexErrorCode::EventQueueFull)?;
-            } else {
-                self.orders_mut(side).insert_leaf(&leaf_node).unwrap();
+                return Ok(true);
             }
+            self.orders_mut(side).insert_leaf(&leaf_node).unwrap();
         }
+        Ok(false)
+    }
+
+    /// Shrinks a resting order in place, keeping its time priority. Orders
+    /// that are gone or already no larger than `new_qty` are left alone.
+    fn reduce_order(
+        &mut self,
+        side: Side,
+        order_id: &u128,
+        new_qty: NonZeroU64,
+        expected_owner: &[u64; 4],
+        expected_owner_slot: u8,
+
+        event_q: &mut EventQueue,
+    ) -> DexResult<()> {
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let orders = self.orders_mut(side);
+        let leaf_h = match orders.find_by_key(order_id) {
+            Some(h) => h,
+            None => return Ok(()),
+        };
+        let leaf_node = *orders.get(leaf_h).unwrap().as_leaf().unwrap();
+        if leaf_node.owner() != expected_owner
+            || leaf_node.owner_slot() != expected_owner_slot
+            || leaf_node.total_quantity() <= new_qty.get()
+        {
+            return Ok(());
+        }
+        let native_per_lot = match side {
+            Side::Bid => leaf_node.price().get() * pc_lot_size,
+            Side::Ask => coin_lot_size,
+        };
+        let cancelled_qty = leaf_node.total_quantity() - new_qty.get();
+        orders
+            .update_leaf(leaf_h, |leaf| leaf.reduce_to(new_qty.get()))
+            .unwrap();
+        event_q
+            .push_back(Event::new(EventView::Out {
+                side,
+                native_qty_unlocked: cancelled_qty * native_per_lot,
+                native_qty_still_locked: new_qty.get() * native_per_lot,
+                order_id,
+                owner: expected_owner,
+                owner_slot: expected_owner_slot,
+                client_order_id: NonZeroU64::new(leaf_node.client_order_id()),
+            }))
+            .map_err(|_| DexErrorCode::EventQueueFull)?;
         Ok(())
     }
+
+    /// The coin locked by an ask for `coin_qty` lots, including the reserve
+    /// for a taker fee paid in coin.
+    fn native_ask_qty_locked(&self, fee_tier: FeeTier, coin_qty: u64) -> u64 {
+        let native_coin_qty = coin_qty * self.market_state.coin_lot_size;
+        native_coin_qty
+            + self
+                .market_state
+                .fee_schedule
+                .native_coin_fee_reserve(fee_tier, native_coin_qty)
+    }
+
+    /// The most lots, up to `max_coin_qty`, an ask can be for when
+    /// `native_coin_qty` is all it may lock, reserve included.
+    fn max_ask_qty(&self, fee_tier: FeeTier, max_coin_qty: u64, native_coin_qty: u64) -> u64 {
+        // what an ask locks grows with its size, so bisect
+        let mut low = 0;
+        let mut high = max_coin_qty.min(native_coin_qty / self.market_state.coin_lot_size);
+        while low < high {
+            let mid = high - (high - low) / 2;
+            if self.native_ask_qty_locked(fee_tier, mid) <= native_coin_qty {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        low
+    }
+
+    /// Cancels the order a `ReplaceOrder` request replaces and hands the funds
+    /// the new order borrowed from it over to the new order. Returns the new
+    /// order's quantities, shrunk if the replaced order was partially filled in
+    /// the meantime, or `None` if the new order was dropped.
+    fn cancel_replaced_order(
+        &mut self,
+        replaced: &ReplacedOrder,
+        order: &NewOrderParams,
+
+        event_q: &mut EventQueue,
+    ) -> DexResult<Option<(NonZeroU64, Option<NonZeroU64>)>> {
+        let NewOrderParams {
+            side,
+            order_id,
+            owner,
+            owner_slot,
+            fee_tier,
+            max_coin_qty,
+            native_pc_qty_locked,
+            ..
+        } = *order;
+        let client_order_id = NonZeroU64::new(order.client_order_id);
+        let pc_lot_size = self.market_state.pc_lot_size;
+        let coin_lot_size = self.market_state.coin_lot_size;
+        let orders = self.orders_mut(replaced.side);
+        let cancelled = match orders.remove_by_key(&replaced.order_id) {
+            Some(leaf) if leaf.owner() == owner && leaf.owner_slot() == replaced.owner_slot => {
+                Some(leaf)
+            }
+            Some(leaf) => {
+                orders.insert_leaf(&leaf).unwrap();
+                None
+            }
+            None => None,
+        };
+
+        let native_qty_shortfall = match cancelled {
+            Some(leaf) => {
+                let native_qty_released = match replaced.side {
+                    Side::Bid => leaf
+                        .total_quantity()
+                        .checked_mul(leaf.price().get())
+                        .and_then(|lots| lots.checked_mul(pc_lot_size)),
+                    Side::Ask => leaf.total_quantity().checked_mul(coin_lot_size),
+                }
+                .ok_or(DexErrorCode::InsufficientFunds)?;
+                let native_qty_carried = native_qty_released.min(replaced.native_qty_borrowed);
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side: replaced.side,
+                        native_qty_unlocked: native_qty_released - native_qty_carried,
+                        native_qty_still_locked: 0,
+                        order_id: &replaced.order_id,
+                        owner,
+                        owner_slot: replaced.owner_slot,
+                        client_order_id: NonZeroU64::new(leaf.client_order_id()),
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+                replaced.native_qty_borrowed - native_qty_carried
+            }
+            None if replaced.place_if_missing => replaced.native_qty_borrowed,
+            None => {
+                // release whatever the new order locked on its own
+                let native_qty_locked = match side {
+                    Side::Bid => native_pc_qty_locked.map_or(0, NonZeroU64::get),
+                    Side::Ask => self.native_ask_qty_locked(fee_tier, max_coin_qty.get()),
+                };
+                event_q
+                    .push_back(Event::new(EventView::Out {
+                        side,
+                        native_qty_unlocked: native_qty_locked - replaced.native_qty_borrowed,
+                        native_qty_still_locked: 0,
+                        order_id,
+                        owner,
+                        owner_slot,
+                        client_order_id,
+                    }))
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+                return Ok(None);
+            }
+        };
+
+        let (funded, native_qty_unlocked) = match side {
+            Side::Bid => {
+                let funded = native_pc_qty_locked
+                    .and_then(|locked| NonZeroU64::new(locked.get() - native_qty_shortfall))
+                    .map(|locked| (max_coin_qty, Some(locked)));
+                (funded, 0)
+            }
+            Side::Ask => {
+                // the shortfall need not be whole lots once the borrowed coin
+                // covers part of the fee reserve, so the ask shrinks to what
+                // is left, reserve included, and only the rest is unlocked
+                let native_qty_left =
+                    self.native_ask_qty_locked(fee_tier, max_coin_qty.get()) - native_qty_shortfall;
+                let coin_qty = self.max_ask_qty(fee_tier, max_coin_qty.get(), native_qty_left);
+                let native_qty_unlocked =
+                    native_qty_left - self.native_ask_qty_locked(fee_tier, coin_qty);
+                (
+                    NonZeroU64::new(coin_qty).map(|coin_qty| (coin_qty, None)),
+                    native_qty_unlocked,
+                )
+            }
+        };
+        match funded {
+            Some((coin_qty, _)) if native_qty_unlocked > 0 => event_q
+                .push_back(Event::new(EventView::Out {
+                    side,
+                    native_qty_unlocked,
+                    native_qty_still_locked: self.native_ask_qty_locked(fee_tier, coin_qty.get()),
+                    order_id,
+                    owner,
+                    owner_slot,
+                    client_order_id,
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?,
+            _ => (),
+        }
+        if funded.is_none() {
+            // nothing is left to fund the new order, so just free its slot
+            event_q
+                .push_back(Event::new(EventView::Out {
+                    side,
+                    native_qty_unlocked,
+                    native_qty_still_locked: 0,
+                    order_id,
+                    owner,
+                    owner_slot,
+                    client_order_id,
+                }))
+                .map_err(|_| DexErrorCode::EventQueueFull)?;
+        }
+        Ok(funded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeFlag;
+    use crate::instruction::NewOrderInstructionV2;
+    use crate::state::{EventQueueHeader, RequestQueueHeader, State, StopOrder, TriggerBookHeader};
+    use bytemuck::{cast_slice_mut, Zeroable};
+    use std::cell::RefCell;
+
+    const ALICE: [u64; 4] = [1; 4];
+    const BOB: [u64; 4] = [2; 4];
+
+    struct TestMarket {
+        market: MarketState,
+        clock: Clock,
+        // u128 buffers, so the slabs are aligned for their leaves
+        bids: Vec<u128>,
+        asks: Vec<u128>,
+        req_q: (RefCell<RequestQueueHeader>, RefCell<Vec<Request>>),
+        event_q: (RefCell<EventQueueHeader>, RefCell<Vec<Event>>),
+        trigger_book: (RefCell<TriggerBookHeader>, RefCell<Vec<StopOrder>>),
+    }
+
+    impl TestMarket {
+        fn new() -> Self {
+            let mut market = MarketState::zeroed();
+            market.coin_lot_size = 10;
+            market.pc_lot_size = 1;
+            market.fee_schedule = FeeSchedule::default();
+            // as if the orders' funds had been deposited
+            market.coin_deposits_total = 1 << 40;
+            market.pc_deposits_total = 1 << 40;
+            TestMarket {
+                market,
+                clock: Clock::default(),
+                bids: vec![0; 4096],
+                asks: vec![0; 4096],
+                req_q: (
+                    RefCell::new(Zeroable::zeroed()),
+                    RefCell::new(vec![Zeroable::zeroed(); 32]),
+                ),
+                event_q: (
+                    RefCell::new(Zeroable::zeroed()),
+                    RefCell::new(vec![Zeroable::zeroed(); 64]),
+                ),
+                trigger_book: (
+                    RefCell::new(Zeroable::zeroed()),
+                    RefCell::new(vec![Zeroable::zeroed(); 4]),
+                ),
+            }
+        }
+
+        fn req_q(&self) -> RequestQueue<'_> {
+            RequestQueue::new(
+                self.req_q.0.borrow_mut(),
+                std::cell::RefMut::map(self.req_q.1.borrow_mut(), Vec::as_mut_slice),
+            )
+        }
+
+        fn event_q(&self) -> EventQueue<'_> {
+            EventQueue::new(
+                self.event_q.0.borrow_mut(),
+                std::cell::RefMut::map(self.event_q.1.borrow_mut(), Vec::as_mut_slice),
+            )
+        }
+
+        fn slabs(&mut self) -> (&mut Slab, &mut Slab) {
+            (
+                Slab::new(cast_slice_mut(&mut self.bids)),
+                Slab::new(cast_slice_mut(&mut self.asks)),
+            )
+        }
+
+        /// Queues a limit order and returns its order id.
+        fn queue_order(
+            &self,
+            side: Side,
+            price: u64,
+            qty: u64,
+            owner: &[u64; 4],
+            self_trade_behavior: SelfTradeBehavior,
+        ) -> u128 {
+            self.queue_order_with_type(
+                side,
+                OrderType::Limit,
+                price,
+                qty,
+                owner,
+                self_trade_behavior,
+            )
+        }
+
+        fn queue_order_with_type(
+            &self,
+            side: Side,
+            order_type: OrderType,
+            price: u64,
+            qty: u64,
+            owner: &[u64; 4],
+            self_trade_behavior: SelfTradeBehavior,
+        ) -> u128 {
+            self.queue_new_order(side, price, qty, owner, |request| {
+                if let RequestView::NewOrder {
+                    order_type: ref mut request_order_type,
+                    self_trade_behavior: ref mut request_self_trade_behavior,
+                    ..
+                } = request
+                {
+                    *request_order_type = order_type;
+                    *request_self_trade_behavior = self_trade_behavior;
+                }
+            })
+        }
+
+        /// Queues a limit order that expires as given and returns its order
+        /// id.
+        fn queue_order_with_expiry(
+            &self,
+            side: Side,
+            price: u64,
+            qty: u64,
+            owner: &[u64; 4],
+            expiry_kind: ExpiryKind,
+            expiry: u64,
+        ) -> u128 {
+            self.queue_new_order(side, price, qty, owner, |request| {
+                if let RequestView::NewOrder {
+                    expiry_kind: ref mut request_expiry_kind,
+                    expiry: ref mut request_expiry,
+                    ..
+                } = request
+                {
+                    *request_expiry_kind = expiry_kind;
+                    *request_expiry = expiry;
+                }
+            })
+        }
+
+        /// Queues a limit order, adjusted by `f`, with enough locked to pay
+        /// for it in full.
+        fn queue_new_order(
+            &self,
+            side: Side,
+            price: u64,
+            qty: u64,
+            owner: &[u64; 4],
+            f: impl FnOnce(&mut RequestView),
+        ) -> u128 {
+            let mut req_q = self.req_q();
+            let order_id = req_q.gen_order_id(price, side);
+            let native_pc_qty_locked = match side {
+                Side::Bid => {
+                    let native_pc_qty = price * qty * self.market.pc_lot_size;
+                    NonZeroU64::new(
+                        native_pc_qty
+                            + self
+                                .market
+                                .fee_schedule
+                                .taker_fee(FeeTier::Base, native_pc_qty),
+                    )
+                }
+                Side::Ask => None,
+            };
+            let mut request = RequestView::NewOrder {
+                side,
+                order_type: OrderType::Limit,
+                owner_slot: 0,
+                fee_tier: FeeTier::Base,
+                order_id: &order_id,
+                max_coin_qty: NonZeroU64::new(qty).unwrap(),
+                native_pc_qty_locked,
+                owner,
+                client_order_id: None,
+                self_trade_behavior: SelfTradeBehavior::DecrementTake,
+                expiry_kind: ExpiryKind::Never,
+                expiry: 0,
+                display_qty: None,
+                replaces: None,
+                native_pc_qty_to_receive: None,
+                referrer: None,
+            };
+            f(&mut request);
+            req_q.push_back(Request::new(request)).unwrap();
+            order_id
+        }
+
+        /// Runs `MatchOrders` with the given limit.
+        fn match_orders(&mut self, limit: u16) {
+            let mut req_q = RequestQueue::new(
+                self.req_q.0.borrow_mut(),
+                std::cell::RefMut::map(self.req_q.1.borrow_mut(), Vec::as_mut_slice),
+            );
+            let mut event_q = EventQueue::new(
+                self.event_q.0.borrow_mut(),
+                std::cell::RefMut::map(self.event_q.1.borrow_mut(), Vec::as_mut_slice),
+            );
+            let mut trigger_book = TriggerBook::new(
+                self.trigger_book.0.borrow_mut(),
+                std::cell::RefMut::map(self.trigger_book.1.borrow_mut(), Vec::as_mut_slice),
+            );
+            let mut order_book = OrderBookState {
+                bids: Slab::new(cast_slice_mut(&mut self.bids)),
+                asks: Slab::new(cast_slice_mut(&mut self.asks)),
+                market_state: &mut self.market,
+                clock: &self.clock,
+            };
+            order_book
+                .process_requests(&mut req_q, &mut event_q, &mut trigger_book, limit)
+                .unwrap();
+        }
+
+        fn events(&self) -> Vec<Event> {
+            self.event_q().iter().copied().collect()
+        }
+    }
+
+    #[test]
+    fn abort_on_self_trade_leaves_book_unchanged() {
+        let mut market = TestMarket::new();
+        market.queue_order(Side::Ask, 100, 5, &BOB, SelfTradeBehavior::DecrementTake);
+        market.queue_order(Side::Ask, 101, 5, &ALICE, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+        let events_before = market.events().len();
+        let (bids, asks) = market.slabs();
+        let asks_checksum = asks.checksum();
+
+        let instruction = NewOrderInstructionV2 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(101).unwrap(),
+            max_qty: NonZeroU64::new(10).unwrap(),
+            order_type: OrderType::Limit,
+            client_id: 0,
+            self_trade_behavior: SelfTradeBehavior::AbortTransaction,
+            expiry_kind: ExpiryKind::Never,
+            expiry: 0,
+            display_qty: 0,
+            native_pc_qty: 0,
+        };
+        let result = State::check_self_trade(&instruction, &ALICE, bids, asks, None);
+        assert!(matches!(
+            result,
+            Err(DexError::ErrorCode(DexErrorCode::WouldSelfTrade))
+        ));
+        // bob's ask alone would have filled a smaller order
+        let small = NewOrderInstructionV2 {
+            max_qty: NonZeroU64::new(5).unwrap(),
+            ..instruction
+        };
+        assert!(State::check_self_trade(&small, &ALICE, bids, asks, None).is_ok());
+
+        // the same order reaching the matching engine is cancelled in full
+        market.queue_order(
+            Side::Bid,
+            101,
+            10,
+            &ALICE,
+            SelfTradeBehavior::AbortTransaction,
+        );
+        market.match_orders(10);
+        let (_, asks) = market.slabs();
+        assert_eq!(asks.checksum(), asks_checksum);
+        let events = market.events();
+        assert_eq!(events.len(), events_before + 1);
+        match events.last().unwrap().as_view().unwrap() {
+            EventView::Out {
+                side: Side::Bid,
+                native_qty_still_locked: 0,
+                ..
+            } => (),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fill_or_kill_fills_within_limit() {
+        let mut market = TestMarket::new();
+        market.queue_order(Side::Ask, 100, 5, &BOB, SelfTradeBehavior::DecrementTake);
+        market.queue_order(Side::Ask, 101, 5, &BOB, SelfTradeBehavior::DecrementTake);
+        market.queue_order(Side::Ask, 101, 5, &BOB, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+        let events_before = market.events().len();
+
+        // takes two resting orders, one step each
+        market.queue_order_with_type(
+            Side::Bid,
+            OrderType::FillOrKill,
+            101,
+            10,
+            &ALICE,
+            SelfTradeBehavior::DecrementTake,
+        );
+        market.match_orders(2);
+        assert_eq!(market.req_q().len(), 0);
+        let fills = market.events()[events_before..]
+            .iter()
+            .filter(|event| matches!(event.as_view().unwrap(), EventView::Fill { .. }))
+            .count();
+        assert_eq!(fills, 4);
+    }
+
+    #[test]
+    fn fill_or_kill_beyond_limit_is_killed() {
+        let mut market = TestMarket::new();
+        market.queue_order(Side::Ask, 100, 5, &BOB, SelfTradeBehavior::DecrementTake);
+        market.queue_order(Side::Ask, 101, 5, &BOB, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+        let events_before = market.events().len();
+        let (_, asks) = market.slabs();
+        let asks_checksum = asks.checksum();
+
+        // would take both resting orders, but may only use one step
+        let order_id = market.queue_order_with_type(
+            Side::Bid,
+            OrderType::FillOrKill,
+            101,
+            10,
+            &ALICE,
+            SelfTradeBehavior::DecrementTake,
+        );
+        let native_pc_qty = 101 * 10 * market.market.pc_lot_size;
+        let native_pc_qty_locked = native_pc_qty
+            + market
+                .market
+                .fee_schedule
+                .taker_fee(FeeTier::Base, native_pc_qty);
+        market.match_orders(1);
+        assert_eq!(market.req_q().len(), 0);
+        let events = market.events();
+        assert_eq!(events.len(), events_before + 1);
+        match events.last().unwrap().as_view().unwrap() {
+            EventView::Out {
+                side: Side::Bid,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                order_id: out_order_id,
+                ..
+            } => {
+                assert_eq!(*out_order_id, order_id);
+                assert_eq!(native_qty_unlocked, native_pc_qty_locked);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        let (_, asks) = market.slabs();
+        assert_eq!(asks.checksum(), asks_checksum);
+    }
+
+    #[test]
+    fn pruned_orders_are_stamped_with_book_checksum() {
+        let mut market = TestMarket::new();
+        market.queue_order(Side::Bid, 99, 5, &ALICE, SelfTradeBehavior::DecrementTake);
+        market.queue_order_with_expiry(Side::Bid, 100, 5, &BOB, ExpiryKind::Slot, 10);
+        market.match_orders(10);
+        let events_before = market.events().len();
+
+        market.clock.slot = 11;
+        let mut event_q = EventQueue::new(
+            market.event_q.0.borrow_mut(),
+            std::cell::RefMut::map(market.event_q.1.borrow_mut(), Vec::as_mut_slice),
+        );
+        let mut order_book = OrderBookState {
+            bids: Slab::new(cast_slice_mut(&mut market.bids)),
+            asks: Slab::new(cast_slice_mut(&mut market.asks)),
+            market_state: &mut market.market,
+            clock: &market.clock,
+        };
+        order_book.prune_expired_orders(&mut event_q, 10).unwrap();
+        let bids_checksum = order_book.bids.checksum();
+        assert_ne!(bids_checksum, 0);
+        drop(event_q);
+
+        let events = market.events();
+        assert_eq!(events.len(), events_before + 1);
+        assert_eq!(events.last().unwrap().book_checksum(), bids_checksum);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn lots_to_receive_is_smallest(
+            native_pc_qty in 1..=1u64 << 48,
+            price in 1..=1u64 << 16,
+            pc_lot_size in 1..=100u64,
+            tier in 0..7u8,
+        ) {
+            let fee_schedule = FeeSchedule::default();
+            let fee_tier = FeeTier::try_from_primitive(tier).unwrap();
+            let lots = lots_to_receive(native_pc_qty, price, pc_lot_size, &fee_schedule, fee_tier)
+                .unwrap();
+            let received = |lots: u64| {
+                let native_pc_qty_traded = lots * price * pc_lot_size;
+                native_pc_qty_traded - fee_schedule.ask_taker_pc_fee(fee_tier, native_pc_qty_traded)
+            };
+            assert!(received(lots) >= native_pc_qty);
+            assert!(received(lots - 1) < native_pc_qty);
+        }
+    }
+
+    #[test]
+    fn cancelling_stop_ask_unlocks_coin_fee_reserve() {
+        let mut market = TestMarket::new();
+        market.market.fee_schedule.fee_flags = FeeFlag::CoinFeesOnAsks as u16;
+        let order_id = market.req_q().gen_order_id(100, Side::Ask);
+        let native_coin_qty = 5 * market.market.coin_lot_size;
+        let native_qty_locked = native_coin_qty
+            + market
+                .market
+                .fee_schedule
+                .native_coin_fee_reserve(FeeTier::Base, native_coin_qty);
+        assert!(native_qty_locked > native_coin_qty);
+        let request = Request::new(RequestView::NewOrder {
+            side: Side::Ask,
+            order_type: OrderType::Limit,
+            owner_slot: 0,
+            fee_tier: FeeTier::Base,
+            order_id: &order_id,
+            max_coin_qty: NonZeroU64::new(5).unwrap(),
+            native_pc_qty_locked: None,
+            owner: &ALICE,
+            client_order_id: None,
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            expiry_kind: ExpiryKind::Never,
+            expiry: 0,
+            display_qty: None,
+            replaces: None,
+            native_pc_qty_to_receive: None,
+            referrer: None,
+        });
+        TriggerBook::new(
+            market.trigger_book.0.borrow_mut(),
+            std::cell::RefMut::map(market.trigger_book.1.borrow_mut(), Vec::as_mut_slice),
+        )
+        .push(StopOrder::new(
+            NonZeroU64::new(90).unwrap(),
+            TriggerCondition::AtOrBelow,
+            request,
+            native_qty_locked,
+        ))
+        .unwrap();
+
+        market
+            .req_q()
+            .push_back(Request::new(RequestView::CancelOrder {
+                side: Side::Ask,
+                order_id: &order_id,
+                cancel_id: 0,
+                expected_owner_slot: 0,
+                expected_owner: &ALICE,
+                client_order_id: None,
+            }))
+            .unwrap();
+        market.match_orders(10);
+        match market.events().last().unwrap().as_view().unwrap() {
+            EventView::Out {
+                side: Side::Ask,
+                native_qty_unlocked,
+                native_qty_still_locked: 0,
+                ..
+            } => assert_eq!(native_qty_unlocked, native_qty_locked),
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[test]
+    fn evict_smallest_only_scans_the_least_aggressive_orders() {
+        let mut market = TestMarket::new();
+        market.market.book_full_policy = u8::from(BookFullPolicy::EvictSmallest) as u64;
+        market.market.book_full_notional_threshold = 100;
+        // fill the asks with large orders behind a single small one at the
+        // top of the book, out of reach of the scan
+        let (_, asks) = market.slabs();
+        let small_id = 1u128 << 64;
+        asks.insert_leaf(&LeafNode::new(0, &small_id, &BOB, 1, FeeTier::Base, 0))
+            .unwrap();
+        let mut price = 2u64;
+        loop {
+            let key = (price as u128) << 64;
+            let leaf = LeafNode::new(0, &key, &BOB, 1_000, FeeTier::Base, 0);
+            if asks.insert_leaf(&leaf).is_err() {
+                break;
+            }
+            price += 1;
+        }
+        assert!(asks.leaf_count() as usize > EVICT_SMALLEST_SCAN_DEPTH);
+
+        market.queue_order(
+            Side::Ask,
+            price,
+            1,
+            &ALICE,
+            SelfTradeBehavior::DecrementTake,
+        );
+        market.match_orders(10);
+        assert!(market.events().last().unwrap().is_book_full());
+        let (_, asks) = market.slabs();
+        assert!(asks.find_by_key(&small_id).is_some());
+    }
+
+    #[test]
+    fn iceberg_refill_queues_behind_resting_orders() {
+        let mut market = TestMarket::new();
+        let iceberg_id = market.queue_new_order(Side::Ask, 100, 10, &BOB, |request| {
+            if let RequestView::NewOrder { display_qty, .. } = request {
+                *display_qty = NonZeroU64::new(4);
+            }
+        });
+        let plain_id =
+            market.queue_order(Side::Ask, 100, 5, &BOB, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+        let events_before = market.events().len();
+
+        // takes the whole visible slice
+        market.queue_order(Side::Bid, 100, 4, &ALICE, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+        let new_iceberg_id = market.events()[events_before..]
+            .iter()
+            .find_map(|event| match event.as_view().unwrap() {
+                EventView::Refill {
+                    side: Side::Ask,
+                    order_id,
+                    new_order_id,
+                    ..
+                } => {
+                    assert_eq!(*order_id, iceberg_id);
+                    Some(new_order_id)
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert!(new_iceberg_id > plain_id);
+        let (_, asks) = market.slabs();
+        assert!(asks.find_by_key(&iceberg_id).is_none());
+        let refilled_h = asks.find_by_key(&new_iceberg_id).unwrap();
+        let refilled = *asks.get(refilled_h).unwrap().as_leaf().unwrap();
+        assert_eq!(refilled.quantity(), 4);
+        assert_eq!(refilled.hidden_quantity(), 2);
+
+        // the order that was behind the iceberg now fills first
+        market.queue_order(Side::Bid, 100, 5, &ALICE, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+        let (_, asks) = market.slabs();
+        assert!(asks.find_by_key(&plain_id).is_none());
+        let refilled_h = asks.find_by_key(&new_iceberg_id).unwrap();
+        let refilled = *asks.get(refilled_h).unwrap().as_leaf().unwrap();
+        assert_eq!(refilled.total_quantity(), 6);
+    }
+
+    #[test]
+    fn reduce_order_keeps_time_priority() {
+        let mut market = TestMarket::new();
+        let first_id =
+            market.queue_order(Side::Ask, 100, 10, &BOB, SelfTradeBehavior::DecrementTake);
+        let second_id =
+            market.queue_order(Side::Ask, 100, 10, &BOB, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+
+        market
+            .req_q()
+            .push_back(Request::new(RequestView::ReduceOrder {
+                side: Side::Ask,
+                order_id: &first_id,
+                new_qty: NonZeroU64::new(3).unwrap(),
+                expected_owner_slot: 0,
+                expected_owner: &BOB,
+            }))
+            .unwrap();
+        market.match_orders(10);
+        let coin_lot_size = market.market.coin_lot_size;
+        match market.events().last().unwrap().as_view().unwrap() {
+            EventView::Out {
+                side: Side::Ask,
+                native_qty_unlocked,
+                native_qty_still_locked,
+                order_id,
+                ..
+            } => {
+                assert_eq!(*order_id, first_id);
+                assert_eq!(native_qty_unlocked, 7 * coin_lot_size);
+                assert_eq!(native_qty_still_locked, 3 * coin_lot_size);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        // the reduced order is still first in line at its price
+        market.queue_order(Side::Bid, 100, 3, &ALICE, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+        let (_, asks) = market.slabs();
+        assert!(asks.find_by_key(&first_id).is_none());
+        let second_h = asks.find_by_key(&second_id).unwrap();
+        let second = *asks.get(second_h).unwrap().as_leaf().unwrap();
+        assert_eq!(second.total_quantity(), 10);
+    }
+
+    #[test]
+    fn replacing_a_filled_ask_keeps_its_coin_fee_reserve() {
+        let mut market = TestMarket::new();
+        market.market.fee_schedule.fee_flags = FeeFlag::CoinFeesOnAsks as u16;
+        let replaced_id =
+            market.queue_order(Side::Ask, 100, 100, &BOB, SelfTradeBehavior::DecrementTake);
+        market.match_orders(10);
+
+        // the new ask borrows its reserve too, since the old one has plenty
+        let coin_lot_size = market.market.coin_lot_size;
+        let fee_schedule = market.market.fee_schedule;
+        let native_ask_qty_locked = |coin_qty: u64| {
+            coin_qty * coin_lot_size
+                + fee_schedule.native_coin_fee_reserve(FeeTier::Base, coin_qty * coin_lot_size)
+        };
+        let native_qty_borrowed = native_ask_qty_locked(50);
+        assert_ne!(native_qty_borrowed % coin_lot_size, 0);
+
+        // a bid leaves only 40 lots of the old ask, which can't also pay
+        // for the reserve of a 40 lot ask
+        market.queue_order(Side::Bid, 100, 60, &ALICE, SelfTradeBehavior::DecrementTake);
+        let new_id = market.queue_new_order(Side::Ask, 101, 50, &BOB, |request| {
+            if let RequestView::NewOrder {
+                owner_slot,
+                replaces,
+                ..
+            } = request
+            {
+                *owner_slot = 1;
+                *replaces = Some(ReplacedOrder {
+                    side: Side::Ask,
+                    order_id: replaced_id,
+                    owner_slot: 0,
+                    native_qty_borrowed,
+                    place_if_missing: false,
+                });
+            }
+        });
+        market.match_orders(10);
+
+        let native_qty_left = 40 * coin_lot_size;
+        assert!(native_ask_qty_locked(40) > native_qty_left);
+        assert!(native_ask_qty_locked(39) <= native_qty_left);
+        let (_, asks) = market.slabs();
+        let new_h = asks.find_by_key(&new_id).unwrap();
+        assert_eq!(asks.get(new_h).unwrap().as_leaf().unwrap().quantity(), 39);
+        // the first event for the new order hands back what it can't use
+        let (native_qty_unlocked, native_qty_still_locked) = market
+            .events()
+            .iter()
+            .find_map(|event| match event.as_view().unwrap() {
+                EventView::Out {
+                    order_id,
+                    native_qty_unlocked,
+                    native_qty_still_locked,
+                    ..
+                } if *order_id == new_id => Some((native_qty_unlocked, native_qty_still_locked)),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(native_qty_still_locked, native_ask_qty_locked(39));
+        assert_eq!(
+            native_qty_unlocked,
+            native_qty_left - native_ask_qty_locked(39)
+        );
+    }
 }
@@ -22,6 +22,7 @@ macro_rules! info {
 
 use solana_sdk::{
     account_info::AccountInfo,
+    clock::Clock,
     program_error::ProgramError,
     pubkey::Pubkey,
     rent::Rent,
@@ -31,14 +32,18 @@ use spl_token::error::TokenError;
 use spl_token::pack::Pack;
 
 use crate::{
-    critbit::Slab,
+    critbit::{Slab, SlabView},
     error::{DexErrorCode, DexResult, SourceFileId},
-    fees::FeeTier,
+    fees::{CrankReward, FeeDiscounts, FeeSchedule, FeeSplit, FeeTier},
     instruction::{
-        disable_authority, fee_sweeper, msrm_token, srm_token, CancelOrderInstruction,
-        InitializeMarketInstruction, MarketInstruction, NewOrderInstruction,
+        CancelAllOrdersInstruction, CancelOrderInstruction, InitializeMarketInstruction,
+        MarketInstruction, NewOrderInstructionV2, NewStopOrderInstruction, ReduceOrderInstruction,
+        ReplaceOrderInstruction, ReplaceTarget,
+    },
+    matching::{
+        would_self_trade, BookFullPolicy, ExpiryKind, MarketMode, OrderBookState, OrderType,
+        SelfTradeBehavior, Side, TriggerCondition,
     },
-    matching::{OrderBookState, OrderType, Side},
 };
 
 declare_check_assert_macros!(SourceFileId::State);
@@ -64,6 +69,7 @@ pub enum AccountFlag {
     Bids = 1u64 << 5,
     Asks = 1u64 << 6,
     Disabled = 1u64 << 7,
+    TriggerBook = 1u64 << 8,
 }
 
 #[cfg_attr(target_endian = "little", derive(Debug))]
@@ -116,7 +122,52 @@ pub struct MarketState {
     pub pc_lot_size: u64,
 
     // 45
+    // the base tier's taker fee, kept equal to `fee_schedule.taker_bps[0]`
     pub fee_rate_bps: u64,
+
+    // 46
+    pub trigger_book: [u64; 4],
+    // 50
+    pub last_traded_price: u64,
+
+    // 51
+    pub fee_schedule: FeeSchedule,
+
+    // 55
+    pub fee_discounts: FeeDiscounts,
+
+    // 75
+    pub fee_split: FeeSplit,
+
+    // 93
+    // the clock epoch as of the last instruction given the clock, which
+    // volume fee tiers are counted in
+    pub fee_epoch: u64,
+
+    // 94
+    pub crank_reward: CrankReward,
+    // 98
+    // the crank reward paid out in `crank_reward_epoch`, counted against
+    // the per-epoch budget
+    pub crank_reward_epoch: u64,
+    // 99
+    pub crank_reward_paid: u64,
+
+    // 100
+    pub book_full_policy: u64,
+    // 101
+    // in native pc, for `BookFullPolicy::EvictSmallest`
+    pub book_full_notional_threshold: u64,
+
+    // 102
+    // signs for administering the market, or zero once renounced
+    pub authority: [u64; 4],
+    // 106
+    // offered the authority by `SetAuthority`, until it accepts
+    pub pending_authority: [u64; 4],
+
+    // 110
+    pub mode: u64,
 }
 #[cfg(target_endian = "little")]
 unsafe impl Zeroable for MarketState {}
@@ -125,6 +176,10 @@ unsafe impl Pod for MarketState {}
 #[cfg(target_endian = "little")]
 unsafe impl TriviallyTransmutable for MarketState {}
 
+/// The most resting orders, queued requests and events together that
+/// `MigrateBooks` copies in one instruction.
+pub const MAX_MIGRATED_ITEMS: u64 = 128;
+
 pub const ACCOUNT_HEAD_PADDING: &[u8; 5] = b"serum";
 pub const ACCOUNT_TAIL_PADDING: &[u8; 7] = b"padding";
 
@@ -211,6 +266,11 @@ impl MarketState {
         Ok(state)
     }
 
+    #[inline]
+    pub fn book_full_policy(&self) -> BookFullPolicy {
+        BookFullPolicy::try_from_primitive(self.book_full_policy as u8).unwrap()
+    }
+
     pub fn check_flags(&self) -> DexResult {
         let flags = BitFlags::from_bits(self.account_flags)
             .map_err(|_| DexErrorCode::InvalidMarketFlags)?;
@@ -299,6 +359,18 @@ impl MarketState {
         Ok(Queue { header, buf })
     }
 
+    fn load_trigger_book_mut<'a>(&self, book: &'a AccountInfo) -> DexResult<TriggerBook<'a>> {
+        check_assert_eq!(&book.key.to_aligned_bytes(), &self.trigger_book)
+            .map_err(|_| DexErrorCode::WrongTriggerBookAccount)?;
+        let (header, buf) = strip_header::<TriggerBookHeader, StopOrder>(book, false)?;
+        let flags = BitFlags::from_bits(header.account_flags).unwrap();
+        check_assert_eq!(
+            &flags,
+            &(AccountFlag::Initialized | AccountFlag::TriggerBook)
+        )?;
+        Ok(TriggerBook { header, buf })
+    }
+
     fn check_coin_vault(&self, vault: account_parser::TokenAccount) -> DexResult {
         if self.coin_vault != vault.inner().key.to_aligned_bytes() {
             Err(DexErrorCode::WrongCoinVault)?
@@ -343,21 +415,25 @@ impl MarketState {
         let (mint, owner, &[balance]) = array_refs![&aligned_data, 4, 4, 1];
 
         check_assert_eq!(owner, expected_owner)?;
-        if mint == &srm_token::ID.to_aligned_bytes() {
-            return Ok(FeeTier::from_srm_and_msrm_balances(balance, 0));
-        }
+        Ok(self.fee_discounts.fee_tier(mint, balance))
+    }
 
-        if mint == &msrm_token::ID.to_aligned_bytes() {
-            return Ok(FeeTier::from_srm_and_msrm_balances(0, balance));
-        }
+    #[inline]
+    pub fn mode(&self) -> MarketMode {
+        MarketMode::try_from_primitive(self.mode as u8).unwrap()
+    }
 
-        Ok(FeeTier::from_srm_and_msrm_balances(0, 0))
+    fn check_accepts_orders(&self) -> DexResult {
+        match self.mode() {
+            MarketMode::Active | MarketMode::PostOnly => Ok(()),
+            MarketMode::CancelOnly => Err(DexErrorCode::MarketIsCancelOnly.into()),
+            MarketMode::Halted => Err(DexErrorCode::MarketIsHalted.into()),
+        }
     }
 
-    fn check_enabled(&self) -> DexResult {
-        let flags = BitFlags::from_bits(self.account_flags).unwrap();
-        if flags.contains(AccountFlag::Disabled) {
-            return Err(DexErrorCode::MarketIsDisabled.into());
+    fn check_not_halted(&self) -> DexResult {
+        if self.mode() == MarketMode::Halted {
+            return Err(DexErrorCode::MarketIsHalted.into());
         }
         Ok(())
     }
@@ -386,6 +462,16 @@ pub struct OpenOrders {
     pub orders: [u128; 128],
     // Using Option<NonZeroU64> in a pod type requires nightly
     pub client_order_ids: [u64; 128],
+
+    // The pc wallet of the referrer, which is paid the referral rebates
+    // accrued by this account's taker fees when its funds are settled. It can
+    // only change while the account has no orders and no unpaid rebates.
+    pub referrer: [u64; 4],
+    pub referrer_rebates_accrued: u64,
+
+    // The native pc traded by this account's orders in `volume_epoch`.
+    pub volume_epoch: u64,
+    pub native_pc_volume: u64,
 }
 unsafe impl Pod for OpenOrders {}
 unsafe impl Zeroable for OpenOrders {}
@@ -411,9 +497,54 @@ impl OpenOrders {
         self.native_pc_total = 0;
         self.native_pc_free = 0;
         self.free_slot_bits = std::u128::MAX;
+        self.referrer = [0; 4];
+        self.referrer_rebates_accrued = 0;
+        self.volume_epoch = 0;
+        self.native_pc_volume = 0;
+        Ok(())
+    }
+
+    fn check_empty(&self) -> DexResult {
+        if self.free_slot_bits != std::u128::MAX
+            || self.native_coin_free != 0
+            || self.native_coin_total != 0
+            || self.native_pc_free != 0
+            || self.native_pc_total != 0
+        {
+            Err(DexErrorCode::OrdersNotEmpty)?
+        }
         Ok(())
     }
 
+    /// Makes `referrer` the account's referrer. Rebates are accrued without
+    /// recording whom they are owed to, so the referrer can't change while
+    /// some are unpaid or could still be accrued by an open order.
+    fn set_referrer(&mut self, referrer: [u64; 4]) -> DexResult {
+        if self.referrer != referrer
+            && self.referrer != [0; 4]
+            && (self.referrer_rebates_accrued != 0 || self.free_slot_bits != std::u128::MAX)
+        {
+            Err(DexErrorCode::ReferrerInUse)?
+        }
+        self.referrer = referrer;
+        Ok(())
+    }
+
+    fn native_pc_volume_in(&self, epoch: u64) -> u64 {
+        if self.volume_epoch == epoch {
+            self.native_pc_volume
+        } else {
+            0
+        }
+    }
+
+    fn add_native_pc_volume(&mut self, epoch: u64, native_pc_qty: u64) {
+        self.native_pc_volume = self
+            .native_pc_volume_in(epoch)
+            .saturating_add(native_pc_qty);
+        self.volume_epoch = epoch;
+    }
+
     fn credit_locked_coin(&mut self, native_coin_amount: u64) {
         self.native_coin_total = self
             .native_coin_total
@@ -578,6 +709,12 @@ impl<'a, H: QueueHeader> Queue<'a, H> {
             index: 0,
         }
     }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut H::Item> {
+        let count = self.header.count() as usize;
+        let (wrapped, tail) = self.buf.split_at_mut(self.header.head() as usize);
+        tail.iter_mut().chain(wrapped.iter_mut()).take(count)
+    }
 }
 
 struct QueueIterator<'a, 'b, H: QueueHeader> {
@@ -634,7 +771,7 @@ impl QueueHeader for RequestQueueHeader {
 pub type RequestQueue<'a> = Queue<'a, RequestQueueHeader>;
 
 impl RequestQueue<'_> {
-    fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
+    pub(crate) fn gen_order_id(&mut self, limit_price: u64, side: Side) -> u128 {
         let seq_num = self.gen_seq_num();
         let upper = (limit_price as u128) << 64;
         let lower = match side {
@@ -659,6 +796,16 @@ enum RequestFlag {
     Bid = 0x04,
     PostOnly = 0x08,
     ImmediateOrCancel = 0x10,
+    FillOrKill = 0x20,
+    ReduceOrder = 0x40,
+    ReplaceOrder = 0x80,
+}
+
+#[derive(Copy, Clone, BitFlags, Debug)]
+#[repr(u8)]
+enum ReplaceFlag {
+    Bid = 0x01,
+    PlaceIfMissing = 0x02,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -667,16 +814,40 @@ pub struct Request {
     request_flags: u8,
     owner_slot: u8,
     fee_tier: u8,
-    padding: [u8; 5],
+    self_trade_behavior: u8,
+    expiry_kind: u8,
+    replaced_owner_slot: u8,
+    replace_flags: u8,
+    padding: [u8; 1],
     max_coin_qty_or_cancel_id: u64,
     native_pc_qty_locked: u64,
     order_id: u128,
     owner: [u64; 4],
     client_order_id: u64,
+    expiry: u64,
+    display_qty: u64,
+    native_qty_borrowed: u64,
+    replaced_order_id: u128,
+    native_pc_qty_to_receive: u64,
+    referrer: [u64; 4],
 }
 unsafe impl Zeroable for Request {}
 unsafe impl Pod for Request {}
 
+/// The resting order a `ReplaceOrder` request cancels before placing its new
+/// order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ReplacedOrder {
+    pub side: Side,
+    pub order_id: u128,
+    pub owner_slot: u8,
+    /// Funds still locked by the replaced order that the new order counts on
+    /// instead of locking its own.
+    pub native_qty_borrowed: u64,
+    /// Place the new order even if the replaced order is no longer on the book.
+    pub place_if_missing: bool,
+}
+
 #[derive(Debug)]
 pub enum RequestView<'a> {
     NewOrder {
@@ -689,6 +860,13 @@ pub enum RequestView<'a> {
         native_pc_qty_locked: Option<NonZeroU64>,
         owner: &'a [u64; 4],
         client_order_id: Option<NonZeroU64>,
+        self_trade_behavior: SelfTradeBehavior,
+        expiry_kind: ExpiryKind,
+        expiry: u64,
+        display_qty: Option<NonZeroU64>,
+        replaces: Option<ReplacedOrder>,
+        native_pc_qty_to_receive: Option<NonZeroU64>,
+        referrer: Option<&'a [u64; 4]>,
     },
     CancelOrder {
         side: Side,
@@ -698,6 +876,13 @@ pub enum RequestView<'a> {
         expected_owner: &'a [u64; 4],
         client_order_id: Option<NonZeroU64>,
     },
+    ReduceOrder {
+        side: Side,
+        order_id: &'a u128,
+        new_qty: NonZeroU64,
+        expected_owner_slot: u8,
+        expected_owner: &'a [u64; 4],
+    },
 }
 
 impl Request {
@@ -714,6 +899,13 @@ impl Request {
                 max_coin_qty,
                 native_pc_qty_locked,
                 client_order_id,
+                self_trade_behavior,
+                expiry_kind,
+                expiry,
+                display_qty,
+                replaces,
+                native_pc_qty_to_receive,
+                referrer,
             } => {
                 let mut flags = BitFlags::from_flag(RequestFlag::NewOrder);
                 if side == Side::Bid {
@@ -722,19 +914,46 @@ impl Request {
                 match order_type {
                     OrderType::PostOnly => flags |= RequestFlag::PostOnly,
                     OrderType::ImmediateOrCancel => flags |= RequestFlag::ImmediateOrCancel,
+                    OrderType::FillOrKill => flags |= RequestFlag::FillOrKill,
                     OrderType::Limit => (),
                 };
+                let mut replace_flags = BitFlags::<ReplaceFlag>::empty();
+                let mut replaced_order_id = 0;
+                let mut replaced_owner_slot = 0;
+                let mut native_qty_borrowed = 0;
+                if let Some(replaced) = replaces {
+                    flags.insert(RequestFlag::ReplaceOrder);
+                    if replaced.side == Side::Bid {
+                        replace_flags.insert(ReplaceFlag::Bid);
+                    }
+                    if replaced.place_if_missing {
+                        replace_flags.insert(ReplaceFlag::PlaceIfMissing);
+                    }
+                    replaced_order_id = replaced.order_id;
+                    replaced_owner_slot = replaced.owner_slot;
+                    native_qty_borrowed = replaced.native_qty_borrowed;
+                }
 
                 Request {
                     request_flags: flags.bits(),
                     owner_slot,
                     fee_tier: fee_tier.into(),
+                    self_trade_behavior: self_trade_behavior.into(),
+                    expiry_kind: expiry_kind.into(),
+                    replaced_owner_slot,
+                    replace_flags: replace_flags.bits(),
                     padding: Zeroable::zeroed(),
                     order_id: *order_id,
                     owner: *owner,
                     max_coin_qty_or_cancel_id: max_coin_qty.get(),
                     native_pc_qty_locked: native_pc_qty_locked.map_or(0, NonZeroU64::get),
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    expiry,
+                    display_qty: display_qty.map_or(0, NonZeroU64::get),
+                    native_qty_borrowed,
+                    replaced_order_id,
+                    native_pc_qty_to_receive: native_pc_qty_to_receive.map_or(0, NonZeroU64::get),
+                    referrer: referrer.map_or([0; 4], |referrer| *referrer),
                 }
             }
             RequestView::CancelOrder {
@@ -755,10 +974,53 @@ impl Request {
                     order_id: *order_id,
                     owner_slot: expected_owner_slot,
                     fee_tier: 0,
+                    self_trade_behavior: 0,
+                    expiry_kind: 0,
                     owner: *expected_owner,
                     native_pc_qty_locked: 0,
+                    replaced_owner_slot: 0,
+                    replace_flags: 0,
                     padding: Zeroable::zeroed(),
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    expiry: 0,
+                    display_qty: 0,
+                    native_qty_borrowed: 0,
+                    replaced_order_id: 0,
+                    native_pc_qty_to_receive: 0,
+                    referrer: [0; 4],
+                }
+            }
+            RequestView::ReduceOrder {
+                side,
+                order_id,
+                new_qty,
+                expected_owner_slot,
+                expected_owner,
+            } => {
+                let mut flags = BitFlags::from_flag(RequestFlag::ReduceOrder);
+                if side == Side::Bid {
+                    flags.insert(RequestFlag::Bid);
+                }
+                Request {
+                    request_flags: flags.bits(),
+                    max_coin_qty_or_cancel_id: new_qty.get(),
+                    order_id: *order_id,
+                    owner_slot: expected_owner_slot,
+                    fee_tier: 0,
+                    self_trade_behavior: 0,
+                    expiry_kind: 0,
+                    owner: *expected_owner,
+                    native_pc_qty_locked: 0,
+                    replaced_owner_slot: 0,
+                    replace_flags: 0,
+                    padding: Zeroable::zeroed(),
+                    client_order_id: 0,
+                    expiry: 0,
+                    display_qty: 0,
+                    native_qty_borrowed: 0,
+                    replaced_order_id: 0,
+                    native_pc_qty_to_receive: 0,
+                    referrer: [0; 4],
                 }
             }
         }
@@ -784,16 +1046,35 @@ impl Request {
         if flags.contains(RequestFlag::NewOrder) {
             let allowed_flags = {
                 use RequestFlag::*;
-                NewOrder | Bid | PostOnly | ImmediateOrCancel
+                NewOrder | Bid | PostOnly | ImmediateOrCancel | FillOrKill | ReplaceOrder
             };
             check_assert!(allowed_flags.contains(flags))?;
             let post_only = flags.contains(RequestFlag::PostOnly);
             let ioc = flags.contains(RequestFlag::ImmediateOrCancel);
-            let order_type = match (post_only, ioc) {
-                (true, false) => OrderType::PostOnly,
-                (false, true) => OrderType::ImmediateOrCancel,
-                (false, false) => OrderType::Limit,
-                (true, true) => unreachable!(),
+            let fok = flags.contains(RequestFlag::FillOrKill);
+            let order_type = match (post_only, ioc, fok) {
+                (true, false, false) => OrderType::PostOnly,
+                (false, true, false) => OrderType::ImmediateOrCancel,
+                (false, false, true) => OrderType::FillOrKill,
+                (false, false, false) => OrderType::Limit,
+                _ => unreachable!(),
+            };
+            let replaces = if flags.contains(RequestFlag::ReplaceOrder) {
+                let replace_flags = BitFlags::<ReplaceFlag>::from_bits(self.replace_flags)
+                    .or(check_unreachable!())?;
+                Some(ReplacedOrder {
+                    side: if replace_flags.contains(ReplaceFlag::Bid) {
+                        Side::Bid
+                    } else {
+                        Side::Ask
+                    },
+                    order_id: self.replaced_order_id,
+                    owner_slot: self.replaced_owner_slot,
+                    native_qty_borrowed: self.native_qty_borrowed,
+                    place_if_missing: replace_flags.contains(ReplaceFlag::PlaceIfMissing),
+                })
+            } else {
+                None
             };
             Ok(RequestView::NewOrder {
                 side,
@@ -805,6 +1086,35 @@ impl Request {
                 max_coin_qty: NonZeroU64::new(self.max_coin_qty_or_cancel_id).unwrap(),
                 native_pc_qty_locked: NonZeroU64::new(self.native_pc_qty_locked),
                 client_order_id: NonZeroU64::new(self.client_order_id),
+                self_trade_behavior: SelfTradeBehavior::try_from_primitive(
+                    self.self_trade_behavior,
+                )
+                .or(check_unreachable!())?,
+                expiry_kind: ExpiryKind::try_from_primitive(self.expiry_kind)
+                    .or(check_unreachable!())?,
+                expiry: self.expiry,
+                display_qty: NonZeroU64::new(self.display_qty),
+                replaces,
+                native_pc_qty_to_receive: NonZeroU64::new(self.native_pc_qty_to_receive),
+                referrer: if self.referrer == [0; 4] {
+                    None
+                } else {
+                    Some(&self.referrer)
+                },
+            })
+        } else if flags.contains(RequestFlag::ReduceOrder) {
+            let allowed_flags = {
+                use RequestFlag::*;
+                ReduceOrder | Bid
+            };
+            check_assert!(allowed_flags.contains(flags))?;
+            Ok(RequestView::ReduceOrder {
+                side,
+                order_id: &self.order_id,
+                new_qty: NonZeroU64::new(self.max_coin_qty_or_cancel_id)
+                    .ok_or(assertion_error!())?,
+                expected_owner_slot: self.owner_slot,
+                expected_owner: &self.owner,
             })
         } else {
             check_assert!(flags.contains(RequestFlag::CancelOrder))?;
@@ -825,6 +1135,140 @@ impl Request {
     }
 }
 
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct TriggerBookHeader {
+    account_flags: u64, // Initialized, TriggerBook
+    count: u64,
+}
+unsafe impl Zeroable for TriggerBookHeader {}
+unsafe impl Pod for TriggerBookHeader {}
+
+/// A new order parked in the trigger book until the market trades through
+/// its trigger price.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct StopOrder {
+    trigger_price: u64,
+    trigger_condition: u8,
+    padding: [u8; 7],
+    // everything locked for the order, including an ask's coin fee reserve
+    native_qty_locked: u64,
+    request: Request,
+}
+unsafe impl Zeroable for StopOrder {}
+unsafe impl Pod for StopOrder {}
+
+impl StopOrder {
+    pub fn new(
+        trigger_price: NonZeroU64,
+        trigger_condition: TriggerCondition,
+        request: Request,
+        native_qty_locked: u64,
+    ) -> Self {
+        StopOrder {
+            trigger_price: trigger_price.get(),
+            trigger_condition: trigger_condition.into(),
+            padding: Zeroable::zeroed(),
+            native_qty_locked,
+            request,
+        }
+    }
+
+    #[inline]
+    pub fn is_triggered(&self, last_traded_price: u64) -> bool {
+        TriggerCondition::try_from_primitive(self.trigger_condition)
+            .unwrap()
+            .is_met(self.trigger_price, last_traded_price)
+    }
+
+    #[inline]
+    pub fn request(&self) -> &Request {
+        &self.request
+    }
+
+    #[inline]
+    pub fn owner(&self) -> (&[u64; 4], u8) {
+        (&self.request.owner, self.request.owner_slot)
+    }
+
+    /// The native amount locked in the owner's `OpenOrders` for this order.
+    #[inline]
+    pub fn native_qty_locked(&self) -> u64 {
+        self.native_qty_locked
+    }
+
+    fn side(&self) -> Side {
+        let flags: BitFlags<RequestFlag> = BitFlags::from_bits(self.request.request_flags).unwrap();
+        if flags.contains(RequestFlag::Bid) {
+            Side::Bid
+        } else {
+            Side::Ask
+        }
+    }
+}
+
+/// Unordered storage for stop orders. Every entry is checked whenever the
+/// last traded price changes, so removal simply swaps in the last entry.
+pub struct TriggerBook<'a> {
+    header: RefMut<'a, TriggerBookHeader>,
+    buf: RefMut<'a, [StopOrder]>,
+}
+
+impl<'a> TriggerBook<'a> {
+    pub fn new(header: RefMut<'a, TriggerBookHeader>, buf: RefMut<'a, [StopOrder]>) -> Self {
+        Self { header, buf }
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.header.count
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn full(&self) -> bool {
+        self.header.count as usize == self.buf.len()
+    }
+
+    #[inline]
+    pub fn get(&self, index: u64) -> Option<&StopOrder> {
+        if index < self.len() {
+            Some(&self.buf[index as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn push(&mut self, stop_order: StopOrder) -> Result<(), StopOrder> {
+        if self.full() {
+            return Err(stop_order);
+        }
+        self.buf[self.header.count as usize] = stop_order;
+        self.header.count += 1;
+        Ok(())
+    }
+
+    pub fn swap_remove(&mut self, index: u64) -> StopOrder {
+        assert!(index < self.len());
+        let last = self.header.count as usize - 1;
+        self.buf.swap(index as usize, last);
+        self.header.count -= 1;
+        self.buf[last]
+    }
+
+    pub fn find_by_order_id(&self, side: Side, order_id: &u128) -> Option<u64> {
+        (0..self.len()).find(|&index| {
+            let stop_order = &self.buf[index as usize];
+            stop_order.request.order_id == *order_id && stop_order.side() == side
+        })
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 #[repr(C)]
 pub struct EventQueueHeader {
@@ -838,6 +1282,7 @@ unsafe impl Pod for EventQueueHeader {}
 
 unsafe impl TriviallyTransmutable for EventQueueHeader {}
 unsafe impl TriviallyTransmutable for RequestQueueHeader {}
+unsafe impl TriviallyTransmutable for TriggerBookHeader {}
 
 impl QueueHeader for EventQueueHeader {
     type Item = Event;
@@ -871,6 +1316,9 @@ enum EventFlag {
     Out = 0x2,
     Bid = 0x4,
     Maker = 0x8,
+    Refill = 0x10,
+    Referred = 0x20,
+    BookFull = 0x40,
 }
 
 impl EventFlag {
@@ -907,12 +1355,14 @@ pub struct Event {
     order_id: u128,
     pub owner: [u64; 4],
     client_order_id: u64,
+    book_checksum: u64,
 }
 unsafe impl Zeroable for Event {}
 unsafe impl Pod for Event {}
 
 unsafe impl TriviallyTransmutable for Event {}
 unsafe impl TriviallyTransmutable for Request {}
+unsafe impl TriviallyTransmutable for StopOrder {}
 
 impl Event {
     #[inline]
@@ -929,14 +1379,16 @@ impl Event {
                 owner_slot,
                 fee_tier,
                 client_order_id,
+                referred,
             } => {
-                let maker_flag = if maker {
-                    BitFlags::from_flag(EventFlag::Maker).bits()
-                } else {
-                    0
-                };
-                let event_flags =
-                    (EventFlag::from_side(side) | EventFlag::Fill).bits() | maker_flag;
+                let mut flags = EventFlag::from_side(side) | EventFlag::Fill;
+                if maker {
+                    flags.insert(EventFlag::Maker);
+                }
+                if referred {
+                    flags.insert(EventFlag::Referred);
+                }
+                let event_flags = flags.bits();
                 Event {
                     event_flags,
                     owner_slot,
@@ -952,6 +1404,7 @@ impl Event {
                     owner: *owner,
 
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    book_checksum: 0,
                 }
             }
 
@@ -979,11 +1432,72 @@ impl Event {
                     order_id: *order_id,
                     owner: *owner,
                     client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    book_checksum: 0,
+                }
+            }
+
+            EventView::Refill {
+                side,
+                order_id,
+                new_order_id,
+                owner,
+                owner_slot,
+                client_order_id,
+            } => {
+                // a refill keeps the price, so only the sequence number changes
+                debug_assert_eq!(*order_id >> 64, new_order_id >> 64);
+                let event_flags = (EventFlag::from_side(side) | EventFlag::Refill).bits();
+                Event {
+                    event_flags,
+                    owner_slot,
+                    fee_tier: 0,
+
+                    _padding: Zeroable::zeroed(),
+
+                    native_qty_released: 0,
+                    native_qty_paid: new_order_id as u64,
+                    native_fee_or_rebate: 0,
+
+                    order_id: *order_id,
+                    owner: *owner,
+                    client_order_id: client_order_id.map_or(0, NonZeroU64::get),
+                    book_checksum: 0,
                 }
             }
         }
     }
 
+    pub fn side(&self) -> Side {
+        EventFlag::flags_to_side(BitFlags::from_bits(self.event_flags).unwrap())
+    }
+
+    /// The checksum of this event's side of the book after the request that
+    /// produced it was matched or its order was pruned, see `Slab::checksum`.
+    #[inline]
+    pub fn book_checksum(&self) -> u64 {
+        self.book_checksum
+    }
+
+    #[inline]
+    pub fn set_book_checksum(&mut self, book_checksum: u64) {
+        self.book_checksum = book_checksum;
+    }
+
+    /// Marks an `Out` as cancelling an order that would have rested on a
+    /// full side of the book.
+    #[inline]
+    pub fn book_full(self) -> Self {
+        Event {
+            event_flags: self.event_flags | EventFlag::BookFull as u8,
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn is_book_full(&self) -> bool {
+        self.event_flags & EventFlag::BookFull as u8 != 0
+    }
+
     pub fn as_view(&self) -> DexResult<EventView> {
         let flags = BitFlags::from_bits(self.event_flags).unwrap();
         let side = EventFlag::flags_to_side(flags);
@@ -991,7 +1505,7 @@ impl Event {
         if flags.contains(EventFlag::Fill) {
             let allowed_flags = {
                 use EventFlag::*;
-                Fill | Bid | Maker
+                Fill | Bid | Maker | Referred
             };
             check_assert!(allowed_flags.contains(flags))?;
 
@@ -1008,11 +1522,29 @@ impl Event {
                 owner_slot: self.owner_slot,
                 fee_tier: self.fee_tier.try_into().or(check_unreachable!())?,
                 client_order_id,
+                referred: flags.contains(EventFlag::Referred),
+            });
+        }
+        if flags.contains(EventFlag::Refill) {
+            let allowed_flags = {
+                use EventFlag::*;
+                Refill | Bid
+            };
+            check_assert!(allowed_flags.contains(flags))?;
+
+            let price_bits = (self.order_id >> 64) << 64;
+            return Ok(EventView::Refill {
+                side,
+                order_id: &self.order_id,
+                new_order_id: price_bits | self.native_qty_paid as u128,
+                owner: &self.owner,
+                owner_slot: self.owner_slot,
+                client_order_id,
             });
         }
         let allowed_flags = {
             use EventFlag::*;
-            Out | Bid | Maker
+            Out | Bid | Maker | BookFull
         };
         check_assert!(allowed_flags.contains(flags))?;
         Ok(EventView::Out {
@@ -1042,6 +1574,8 @@ pub enum EventView<'a> {
         owner_slot: u8,
         fee_tier: FeeTier,
         client_order_id: Option<NonZeroU64>,
+        /// Set on the taker's fill when part of its fee goes to a referrer.
+        referred: bool,
     },
     Out {
         side: Side,
@@ -1052,12 +1586,24 @@ pub enum EventView<'a> {
         owner_slot: u8,
         client_order_id: Option<NonZeroU64>,
     },
+    /// The visible slice of an iceberg order was refilled from its hidden
+    /// remainder, which moves the order from `order_id` to `new_order_id`.
+    Refill {
+        side: Side,
+        order_id: &'a u128,
+        new_order_id: u128,
+        owner: &'a [u64; 4],
+        owner_slot: u8,
+        client_order_id: Option<NonZeroU64>,
+    },
 }
 
 impl<'a> EventView<'a> {
     fn side(&self) -> Side {
         match self {
-            &EventView::Fill { side, .. } | &EventView::Out { side, .. } => side,
+            &EventView::Fill { side, .. }
+            | &EventView::Out { side, .. }
+            | &EventView::Refill { side, .. } => side,
         }
     }
 }
@@ -1213,22 +1759,29 @@ pub mod account_parser {
         Ok(())
     });
 
-    declare_validated_account_wrapper!(SignerAccount, |account: &AccountInfo| {
-        check_assert!(account.is_signer)?;
+    declare_validated_account_wrapper!(ClockSysvarAccount, |account: &AccountInfo| {
+        check_assert!(Clock::check_id(account.key))?;
         Ok(())
     });
 
-    declare_validated_account_wrapper!(SigningFeeSweeper, |account: &AccountInfo| {
+    declare_validated_account_wrapper!(SignerAccount, |account: &AccountInfo| {
         check_assert!(account.is_signer)?;
-        check_assert_eq!(account.key, &fee_sweeper::ID)?;
         Ok(())
     });
 
-    declare_validated_account_wrapper!(SigningDisableAuthority, |account: &AccountInfo| {
-        check_assert!(account.is_signer)?;
-        check_assert_eq!(account.key, &disable_authority::ID)?;
-        Ok(())
-    });
+    // Only asks for a signature, so the authority can be a program-derived
+    // address, e.g. one that a multisig program signs for.
+    declare_validated_account_wrapper!(
+        SigningAuthority,
+        |account: &AccountInfo, market: &MarketState| {
+            check_assert!(account.is_signer)?;
+            if market.authority == [0; 4] || account.key.to_aligned_bytes() != market.authority {
+                Err(DexErrorCode::WrongAuthority)?
+            }
+            Ok(())
+        },
+        market: &MarketState
+    );
 
     declare_validated_token_account_wrapper!(
         CoinVault,
@@ -1278,6 +1831,11 @@ pub mod account_parser {
             let data = self.inner().try_borrow_data()?;
             Ok(u64::from_le_bytes(*array_ref![data, 64, 8]))
         }
+
+        pub fn is_owned_by(self, owner: &[u64; 4]) -> DexResult<bool> {
+            let data = self.inner().try_borrow_data()?;
+            Ok(array_ref![data, 32, 32] == transmute_to_bytes(owner))
+        }
     }
 
     #[derive(Copy, Clone)]
@@ -1308,6 +1866,8 @@ pub mod account_parser {
         serum_dex_accounts: &'a [AccountInfo<'b>; 5],
         pub coin_vault_and_mint: TokenAccountAndMint<'a, 'b>,
         pub pc_vault_and_mint: TokenAccountAndMint<'a, 'b>,
+        trigger_book: &'a AccountInfo<'b>,
+        pub authority: &'a AccountInfo<'b>,
     }
 
     impl<'a, 'b: 'a> InitializeMarketArgs<'a, 'b> {
@@ -1316,12 +1876,20 @@ pub mod account_parser {
             instruction: &'a InitializeMarketInstruction,
             accounts: &'a [AccountInfo<'b>],
         ) -> DexResult<Self> {
-            check_assert_eq!(accounts.len(), 9)?;
-            let accounts = array_ref![accounts, 0, 9];
-            let (unchecked_serum_dex_accounts, unchecked_vaults, unchecked_mints) =
-                array_refs![accounts, 5, 2, 2];
+            check_assert_eq!(accounts.len(), 11)?;
+            let accounts = array_ref![accounts, 0, 11];
+            let (
+                unchecked_serum_dex_accounts,
+                unchecked_vaults,
+                unchecked_mints,
+                &[ref unchecked_trigger_book],
+                &[ref authority],
+            ) = array_refs![accounts, 5, 2, 2, 1, 1];
             let mut checked_vaults = [None, None];
-            for account in unchecked_serum_dex_accounts {
+            let unchecked_accounts = unchecked_serum_dex_accounts
+                .iter()
+                .chain(std::iter::once(unchecked_trigger_book));
+            for account in unchecked_accounts {
                 check_assert_eq!(account.owner, program_id)?;
                 let data = account.try_borrow_data()?;
                 check_assert_eq!(data.len() % 8, 4)?;
@@ -1332,6 +1900,7 @@ pub mod account_parser {
                 check_assert_eq!(*padding7, [0u8; 7])?;
             }
             let serum_dex_accounts = unchecked_serum_dex_accounts;
+            let trigger_book = unchecked_trigger_book;
             let vault_owner_key_bytes = gen_vault_signer_key(
                 instruction.vault_signer_nonce,
                 serum_dex_accounts[0].key,
@@ -1364,6 +1933,8 @@ pub mod account_parser {
                 serum_dex_accounts,
                 coin_vault_and_mint,
                 pc_vault_and_mint,
+                trigger_book,
+                authority,
             })
         }
 
@@ -1390,10 +1961,14 @@ pub mod account_parser {
         pub fn get_asks(&self) -> &'a AccountInfo<'b> {
             &self.serum_dex_accounts[4]
         }
+
+        pub fn get_trigger_book(&self) -> &'a AccountInfo<'b> {
+            self.trigger_book
+        }
     }
 
     pub struct NewOrderArgs<'a, 'b: 'a> {
-        pub instruction: &'a NewOrderInstruction,
+        pub instruction: &'a NewOrderInstructionV2,
         pub market: &'a mut MarketState,
         pub open_orders: &'a mut OpenOrders,
         pub open_orders_address: &'a [u64; 4],
@@ -1404,19 +1979,60 @@ pub mod account_parser {
         pub pc_vault: PcVault<'a, 'b>,
         pub spl_token_program: SplTokenProgram<'a, 'b>,
         pub fee_tier: FeeTier,
+        pub referrer: Option<PcWallet<'a, 'b>>,
+        // the bids and asks, passed with orders that abort on a self-trade
+        pub books: Option<(RefMut<'a, Slab>, RefMut<'a, Slab>)>,
     }
     impl<'a, 'b: 'a> NewOrderArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
-            instruction: &'a NewOrderInstruction,
+            instruction: &'a NewOrderInstructionV2,
             accounts: &'a [AccountInfo<'b>],
             f: impl FnOnce(NewOrderArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert!(accounts.len() == 9 || accounts.len() == 10)?;
-            let (fixed_accounts, fee_discount_account): (
+            let needs_books =
+                instruction.self_trade_behavior == SelfTradeBehavior::AbortTransaction;
+            Self::with_accounts(program_id, instruction, needs_books, accounts, f)
+        }
+
+        fn with_accounts<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a NewOrderInstructionV2,
+            needs_books: bool,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            // the bids and asks come last, after the optional accounts
+            let (accounts, book_accounts) = if needs_books {
+                check_assert!(accounts.len() >= 11)?;
+                let (accounts, book_accounts) = accounts.split_at(accounts.len() - 2);
+                (accounts, Some(array_ref![book_accounts, 0, 2]))
+            } else {
+                (accounts, None)
+            };
+            check_assert!(accounts.len() >= 9 && accounts.len() <= 12)?;
+            let (fixed_accounts, optional_accounts): (
                 &'a [AccountInfo<'b>; 9],
                 &'a [AccountInfo<'b>],
             ) = array_refs![accounts, 9; .. ;];
+            Self::with_split_accounts(
+                program_id,
+                instruction,
+                fixed_accounts,
+                optional_accounts,
+                book_accounts,
+                f,
+            )
+        }
+
+        fn with_split_accounts<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a NewOrderInstructionV2,
+            fixed_accounts: &'a [AccountInfo<'b>; 9],
+            optional_accounts: &'a [AccountInfo<'b>],
+            book_accounts: Option<&'a [AccountInfo<'b>; 2]>,
+            f: impl FnOnce(NewOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
             let &[
                 ref market_acc,
                 ref open_orders_acc,
@@ -1428,19 +2044,48 @@ pub mod account_parser {
                 ref spl_token_program_acc,
                 ref rent_sysvar_acc,
             ]: &'a [AccountInfo<'b>; 9] = fixed_accounts;
-            let srm_or_msrm_account = match fee_discount_account {
-                &[] => None,
-                &[ref account] => Some(TokenAccount::new(account)?),
+            let mut market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+
+            // a market with volume fee tiers takes the clock after the other
+            // optional accounts, so the epoch is current when volume is read
+            let optional_accounts = if market.fee_discounts.has_volume_tiers() {
+                let (clock_acc, optional_accounts) =
+                    optional_accounts.split_last().ok_or(assertion_error!())?;
+                let clock = {
+                    let clock_sysvar = ClockSysvarAccount::new(clock_acc)?;
+                    Clock::from_account_info(clock_sysvar.inner()).or(check_unreachable!())?
+                };
+                market.fee_epoch = clock.epoch;
+                optional_accounts
+            } else {
+                optional_accounts
+            };
+            // the fee discount account belongs to the order's owner and the
+            // referrer's wallet can't, so a lone optional account is told
+            // apart by its owner
+            let order_owner = owner_acc.key.to_aligned_bytes();
+            let (srm_or_msrm_account, referrer_acc) = match optional_accounts {
+                &[] => (None, None),
+                &[ref account] => {
+                    let account = TokenAccount::new(account)?;
+                    if account.is_owned_by(&order_owner)? {
+                        (Some(account), None)
+                    } else {
+                        (None, Some(account.inner()))
+                    }
+                }
+                &[ref account, ref referrer_acc] => {
+                    (Some(TokenAccount::new(account)?), Some(referrer_acc))
+                }
                 _ => check_unreachable!()?,
             };
 
-            let mut market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
             let rent = {
                 let rent_sysvar = RentSysvarAccount::new(rent_sysvar_acc)?;
                 Rent::from_account_info(rent_sysvar.inner()).or(check_unreachable!())?
             };
             let owner = SignerAccount::new(owner_acc)?;
-            let fee_tier =
+            let balance_fee_tier =
                 market.load_fee_tier(&owner.inner().key.to_aligned_bytes(), srm_or_msrm_account)?;
             let mut open_orders = market.load_orders_mut(
                 open_orders_acc,
@@ -1448,6 +2093,9 @@ pub mod account_parser {
                 program_id,
                 Some(rent),
             )?;
+            let native_pc_volume = open_orders.native_pc_volume_in(market.fee_epoch);
+            let volume_fee_tier = market.fee_discounts.volume_tier(native_pc_volume);
+            let fee_tier = balance_fee_tier.max(volume_fee_tier);
             let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
             let req_q = market.load_request_queue_mut(req_q_acc)?;
 
@@ -1458,8 +2106,25 @@ pub mod account_parser {
             };
             let coin_vault = CoinVault::from_account(coin_vault_acc, &market)?;
             let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
-            market.check_enabled()?;
+            market.check_accepts_orders()?;
             let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
+            let referrer = match referrer_acc {
+                Some(account) => {
+                    let referrer = PcWallet::from_account(account, &market)?;
+                    if referrer.token_account().is_owned_by(&order_owner)? {
+                        Err(DexErrorCode::WrongReferrer)?
+                    }
+                    Some(referrer)
+                }
+                None => None,
+            };
+            let books = match book_accounts {
+                Some(&[ref bids_acc, ref asks_acc]) => Some((
+                    market.load_bids_mut(bids_acc)?,
+                    market.load_asks_mut(asks_acc)?,
+                )),
+                None => None,
+            };
             let args = NewOrderArgs {
                 instruction,
                 market: market.deref_mut(),
@@ -1472,34 +2137,195 @@ pub mod account_parser {
                 pc_vault,
                 spl_token_program,
                 fee_tier,
+                referrer,
+                books,
             };
             f(args)
         }
     }
 
-    pub struct MatchOrdersArgs<'a> {
-        pub limit: u16,
-        pub order_book_state: OrderBookState<'a>,
-        pub req_q: RequestQueue<'a>,
-        pub event_q: EventQueue<'a>,
+    pub struct NewOrdersArgs<'a, 'b: 'a> {
+        pub new_order_args: NewOrderArgs<'a, 'b>,
+        pub orders: &'a [NewOrderInstructionV2],
     }
-    impl<'a> MatchOrdersArgs<'a> {
-        pub fn with_parsed_args<'b, T>(
+    impl<'a, 'b: 'a> NewOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            orders: &'a [NewOrderInstructionV2],
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            let (first, rest) = orders.split_first().ok_or(assertion_error!())?;
+            if rest.iter().any(|order| order.side != first.side) {
+                Err(DexErrorCode::NewOrdersSideMismatch)?
+            }
+            let needs_books = orders
+                .iter()
+                .any(|order| order.self_trade_behavior == SelfTradeBehavior::AbortTransaction);
+            // the payer is checked against the side of the first order
+            NewOrderArgs::with_accounts(
+                program_id,
+                first,
+                needs_books,
+                accounts,
+                |new_order_args| {
+                    f(NewOrdersArgs {
+                        new_order_args,
+                        orders,
+                    })
+                },
+            )
+        }
+    }
+
+    pub struct NewStopOrderArgs<'a, 'b: 'a> {
+        pub new_order_args: NewOrderArgs<'a, 'b>,
+        pub trigger_book: TriggerBook<'a>,
+        pub trigger_price: NonZeroU64,
+        pub trigger_condition: TriggerCondition,
+    }
+    impl<'a, 'b: 'a> NewStopOrderArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a NewStopOrderInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(NewStopOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() >= 10 && accounts.len() <= 13)?;
+            let (fixed_accounts, &[ref trigger_book_acc], optional_accounts): (
+                &'a [AccountInfo<'b>; 9],
+                &'a [AccountInfo<'b>; 1],
+                &'a [AccountInfo<'b>],
+            ) = array_refs![accounts, 9, 1; .. ;];
+            NewOrderArgs::with_split_accounts(
+                program_id,
+                &instruction.order,
+                fixed_accounts,
+                optional_accounts,
+                None,
+                |new_order_args| {
+                    let trigger_book = new_order_args
+                        .market
+                        .load_trigger_book_mut(trigger_book_acc)?;
+                    f(NewStopOrderArgs {
+                        new_order_args,
+                        trigger_book,
+                        trigger_price: instruction.trigger_price,
+                        trigger_condition: instruction.trigger_condition,
+                    })
+                },
+            )
+        }
+    }
+
+    pub struct ReplaceOrderArgs<'a, 'b: 'a> {
+        pub new_order_args: NewOrderArgs<'a, 'b>,
+        pub bids: RefMut<'a, Slab>,
+        pub asks: RefMut<'a, Slab>,
+        pub target: &'a ReplaceTarget,
+        pub place_if_missing: bool,
+    }
+    impl<'a, 'b: 'a> ReplaceOrderArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            instruction: &'a ReplaceOrderInstruction,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(ReplaceOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() >= 11 && accounts.len() <= 14)?;
+            let (fixed_accounts, &[ref bids_acc, ref asks_acc], optional_accounts): (
+                &'a [AccountInfo<'b>; 9],
+                &'a [AccountInfo<'b>; 2],
+                &'a [AccountInfo<'b>],
+            ) = array_refs![accounts, 9, 2; .. ;];
+            NewOrderArgs::with_split_accounts(
+                program_id,
+                &instruction.order,
+                fixed_accounts,
+                optional_accounts,
+                None,
+                |new_order_args| {
+                    let bids = new_order_args.market.load_bids_mut(bids_acc)?;
+                    let asks = new_order_args.market.load_asks_mut(asks_acc)?;
+                    f(ReplaceOrderArgs {
+                        new_order_args,
+                        bids,
+                        asks,
+                        target: &instruction.target,
+                        place_if_missing: instruction.place_if_missing,
+                    })
+                },
+            )
+        }
+    }
+
+    /// The accounts a crank reward is paid with.
+    pub struct CrankRewardAccounts<'a, 'b: 'a> {
+        pub pc_vault: PcVault<'a, 'b>,
+        pub vault_signer: VaultSigner<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub reward_wallet: PcWallet<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> CrankRewardAccounts<'a, 'b> {
+        fn new(
+            accounts: &'a [AccountInfo<'b>; 4],
+            market: &MarketState,
+            program_id: &'a Pubkey,
+        ) -> DexResult<Self> {
+            #[rustfmt::skip]
+            let &[
+                ref pc_vault_acc,
+                ref vault_signer_acc,
+                ref spl_token_program_acc,
+                ref reward_wallet_acc
+            ] = accounts;
+            Ok(CrankRewardAccounts {
+                pc_vault: PcVault::from_account(pc_vault_acc, market)?,
+                vault_signer: VaultSigner::new(vault_signer_acc, market, program_id)?,
+                spl_token_program: SplTokenProgram::new(spl_token_program_acc)?,
+                reward_wallet: PcWallet::from_account(reward_wallet_acc, market)?,
+            })
+        }
+    }
+
+    pub struct MatchOrdersArgs<'a, 'b: 'a> {
+        pub limit: u16,
+        pub order_book_state: OrderBookState<'a>,
+        pub req_q: RequestQueue<'a>,
+        pub event_q: EventQueue<'a>,
+        pub trigger_book: TriggerBook<'a>,
+        pub crank_reward_accounts: Option<CrankRewardAccounts<'a, 'b>>,
+    }
+    impl<'a, 'b: 'a> MatchOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
             accounts: &'a [AccountInfo<'b>],
             limit: u16,
             f: impl FnOnce(MatchOrdersArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert!(accounts.len() >= 5)?;
+            check_assert!(accounts.len() == 9 || accounts.len() == 13)?;
+            let (fixed_accounts, reward_accounts): (
+                &'a [AccountInfo<'b>; 9],
+                &'a [AccountInfo<'b>],
+            ) = array_refs![accounts, 9; .. ;];
             #[rustfmt::skip]
             let &[
                 ref market_acc,
                 ref req_q_acc,
                 ref event_q_acc,
                 ref bids_acc,
-                ref asks_acc
-            ] = array_ref![accounts, 0, 5];
+                ref asks_acc,
+                _,
+                _,
+                ref clock_sysvar_acc,
+                ref trigger_book_acc,
+            ] = fixed_accounts;
+            let clock = {
+                let clock_sysvar = ClockSysvarAccount::new(clock_sysvar_acc)?;
+                Clock::from_account_info(clock_sysvar.inner()).or(check_unreachable!())?
+            };
             let mut market = MarketState::load(market_acc, program_id).or(check_unreachable!())?;
+            market.check_not_halted()?;
             let req_q = market
                 .load_request_queue_mut(req_q_acc)
                 .or(check_unreachable!())?;
@@ -1508,11 +2334,22 @@ pub mod account_parser {
                 .or(check_unreachable!())?;
             let mut bids = market.load_bids_mut(bids_acc).or(check_unreachable!())?;
             let mut asks = market.load_asks_mut(asks_acc).or(check_unreachable!())?;
+            let trigger_book = market.load_trigger_book_mut(trigger_book_acc)?;
+            let crank_reward_accounts = if reward_accounts.is_empty() {
+                None
+            } else {
+                Some(CrankRewardAccounts::new(
+                    array_ref![reward_accounts, 0, 4],
+                    &market,
+                    program_id,
+                )?)
+            };
 
             let order_book_state = OrderBookState {
                 bids: bids.deref_mut(),
                 asks: asks.deref_mut(),
                 market_state: market.deref_mut(),
+                clock: &clock,
             };
 
             let args = MatchOrdersArgs {
@@ -1520,6 +2357,54 @@ pub mod account_parser {
                 order_book_state,
                 req_q,
                 event_q,
+                trigger_book,
+                crank_reward_accounts,
+            };
+            f(args)
+        }
+    }
+
+    pub struct PruneExpiredOrdersArgs<'a> {
+        pub limit: u16,
+        pub order_book_state: OrderBookState<'a>,
+        pub event_q: EventQueue<'a>,
+    }
+    impl<'a> PruneExpiredOrdersArgs<'a> {
+        pub fn with_parsed_args<'b, T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            limit: u16,
+            f: impl FnOnce(PruneExpiredOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 5)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref event_q_acc,
+                ref bids_acc,
+                ref asks_acc,
+                ref clock_sysvar_acc
+            ] = array_ref![accounts, 0, 5];
+            let clock = {
+                let clock_sysvar = ClockSysvarAccount::new(clock_sysvar_acc)?;
+                Clock::from_account_info(clock_sysvar.inner()).or(check_unreachable!())?
+            };
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let event_q = market.load_event_queue_mut(event_q_acc)?;
+            let mut bids = market.load_bids_mut(bids_acc)?;
+            let mut asks = market.load_asks_mut(asks_acc)?;
+
+            let order_book_state = OrderBookState {
+                bids: bids.deref_mut(),
+                asks: asks.deref_mut(),
+                market_state: market.deref_mut(),
+                clock: &clock,
+            };
+
+            let args = PruneExpiredOrdersArgs {
+                limit,
+                order_book_state,
+                event_q,
             };
             f(args)
         }
@@ -1531,14 +2416,24 @@ pub mod account_parser {
         pub open_orders_accounts: &'a [AccountInfo<'b>],
         pub market: &'a mut MarketState,
         pub event_q: EventQueue<'a>,
+        pub crank_reward_accounts: Option<CrankRewardAccounts<'a, 'b>>,
     }
     impl<'a, 'b: 'a> ConsumeEventsArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
             accounts: &'a [AccountInfo<'b>],
             limit: u16,
+            with_crank_reward: bool,
             f: impl FnOnce(ConsumeEventsArgs) -> DexResult<T>,
         ) -> DexResult<T> {
+            // the crank reward accounts come last
+            let (accounts, reward_accounts) = if with_crank_reward {
+                check_assert!(accounts.len() >= 9)?;
+                let (accounts, reward_accounts) = accounts.split_at(accounts.len() - 4);
+                (accounts, Some(array_ref![reward_accounts, 0, 4]))
+            } else {
+                (accounts, None)
+            };
             check_assert!(accounts.len() >= 5)?;
             #[rustfmt::skip]
             let (
@@ -1550,12 +2445,21 @@ pub mod account_parser {
             ) = array_refs![accounts, 0; .. ; 1, 1, 2];
             let mut market = MarketState::load(market_acc, program_id)?;
             let event_q = market.load_event_queue_mut(event_q_acc)?;
+            let crank_reward_accounts = match reward_accounts {
+                Some(reward_accounts) => Some(CrankRewardAccounts::new(
+                    reward_accounts,
+                    &market,
+                    program_id,
+                )?),
+                None => None,
+            };
             let args = ConsumeEventsArgs {
                 limit,
                 program_id,
                 open_orders_accounts,
                 market: market.deref_mut(),
                 event_q,
+                crank_reward_accounts,
             };
             f(args)
         }
@@ -1600,6 +2504,112 @@ pub mod account_parser {
         }
     }
 
+    pub struct ReduceOrderArgs<'a, 'b: 'a> {
+        pub instruction: &'a ReduceOrderInstruction,
+        pub open_orders: &'a mut OpenOrders,
+        pub open_orders_address: &'a [u64; 4],
+        pub req_q: RequestQueue<'a>,
+        pub orders_owner: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> ReduceOrderArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            instruction: &'a ReduceOrderInstruction,
+            f: impl FnOnce(ReduceOrderArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref req_q_acc,
+                ref owner_acc
+            ] = array_ref![accounts, 0, 4];
+            let market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let mut open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let args = ReduceOrderArgs {
+                instruction,
+                open_orders: open_orders.deref_mut(),
+                open_orders_address,
+                req_q,
+                orders_owner: owner,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CancelAllOrdersArgs<'a, 'b: 'a> {
+        pub instruction: &'a CancelAllOrdersInstruction,
+        pub open_orders: &'a mut OpenOrders,
+        pub open_orders_address: &'a [u64; 4],
+        pub orders_owner: SignerAccount<'a, 'b>,
+        pub order_book_state: OrderBookState<'a>,
+        pub req_q: RequestQueue<'a>,
+        pub event_q: EventQueue<'a>,
+        pub trigger_book: TriggerBook<'a>,
+    }
+    impl<'a, 'b: 'a> CancelAllOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            instruction: &'a CancelAllOrdersInstruction,
+            f: impl FnOnce(CancelAllOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 9)?;
+            #[rustfmt::skip]
+            let &[
+                ref market_acc,
+                ref open_orders_acc,
+                ref owner_acc,
+                ref req_q_acc,
+                ref event_q_acc,
+                ref bids_acc,
+                ref asks_acc,
+                ref trigger_book_acc,
+                ref clock_sysvar_acc
+            ] = array_ref![accounts, 0, 9];
+            let clock = {
+                let clock_sysvar = ClockSysvarAccount::new(clock_sysvar_acc)?;
+                Clock::from_account_info(clock_sysvar.inner()).or(check_unreachable!())?
+            };
+            let mut market: RefMut<'a, MarketState> = MarketState::load(market_acc, program_id)?;
+            market.check_not_halted()?;
+            let owner = SignerAccount::new(owner_acc)?;
+            let mut open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            let ref open_orders_address = open_orders_acc.key.to_aligned_bytes();
+            let req_q = market.load_request_queue_mut(req_q_acc)?;
+            let event_q = market.load_event_queue_mut(event_q_acc)?;
+            let mut bids = market.load_bids_mut(bids_acc)?;
+            let mut asks = market.load_asks_mut(asks_acc)?;
+            let trigger_book = market.load_trigger_book_mut(trigger_book_acc)?;
+
+            let order_book_state = OrderBookState {
+                bids: bids.deref_mut(),
+                asks: asks.deref_mut(),
+                market_state: market.deref_mut(),
+                clock: &clock,
+            };
+
+            let args = CancelAllOrdersArgs {
+                instruction,
+                open_orders: open_orders.deref_mut(),
+                open_orders_address,
+                orders_owner: owner,
+                order_book_state,
+                req_q,
+                event_q,
+                trigger_book,
+            };
+            f(args)
+        }
+    }
+
     pub struct CancelOrderByClientIdArgs<'a, 'b: 'a> {
         pub client_order_id: NonZeroU64,
         pub open_orders: &'a mut OpenOrders,
@@ -1649,6 +2659,7 @@ pub mod account_parser {
         pub pc_wallet: PcWallet<'a, 'b>,
         pub vault_signer: VaultSigner<'a, 'b>,
         pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub referrer: Option<PcWallet<'a, 'b>>,
     }
     impl<'a, 'b: 'a> SettleFundsArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
@@ -1656,7 +2667,9 @@ pub mod account_parser {
             accounts: &'a [AccountInfo<'b>],
             f: impl FnOnce(SettleFundsArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert_eq!(accounts.len(), 9)?;
+            check_assert!(accounts.len() == 9 || accounts.len() == 10)?;
+            let (fixed_accounts, referrer_acc): (&'a [AccountInfo<'b>; 9], &'a [AccountInfo<'b>]) =
+                array_refs![accounts, 9; .. ;];
             let &[
                 ref market_acc,
                 ref open_orders_acc,
@@ -1667,7 +2680,7 @@ pub mod account_parser {
                 ref pc_wallet_acc,
                 ref vault_signer_acc,
                 ref spl_token_program_acc,
-            ]: &'a [AccountInfo<'b>; 9] = array_ref![accounts, 0, 9];
+            ]: &'a [AccountInfo<'b>; 9] = fixed_accounts;
             let spl_token_program = SplTokenProgram::new(spl_token_program_acc)?;
             let mut market = MarketState::load(market_acc, program_id)?;
             let owner = SignerAccount::new(owner_acc).or(check_unreachable!())?;
@@ -1685,6 +2698,22 @@ pub mod account_parser {
             let mut open_orders =
                 market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
 
+            // rebates are owed to the referrer, so they have to be paid out
+            // whenever the owner settles
+            let referrer = match referrer_acc {
+                &[] if open_orders.referrer_rebates_accrued != 0 => {
+                    Err(DexErrorCode::WrongReferrer)?
+                }
+                &[] => None,
+                &[ref account] => {
+                    if account.key.to_aligned_bytes() != open_orders.referrer {
+                        Err(DexErrorCode::WrongReferrer)?
+                    }
+                    Some(PcWallet::from_account(account, &market)?)
+                }
+                _ => check_unreachable!()?,
+            };
+
             let args = SettleFundsArgs {
                 market: market.deref_mut(),
                 open_orders: open_orders.deref_mut(),
@@ -1694,6 +2723,36 @@ pub mod account_parser {
                 pc_wallet,
                 vault_signer,
                 spl_token_program,
+                referrer,
+            };
+            f(args)
+        }
+    }
+
+    pub struct CloseOpenOrdersArgs<'a, 'b: 'a> {
+        pub open_orders: &'a mut OpenOrders,
+        pub open_orders_acc: &'a AccountInfo<'b>,
+        pub destination: &'a AccountInfo<'b>,
+    }
+    impl<'a, 'b: 'a> CloseOpenOrdersArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(CloseOpenOrdersArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 4)?;
+            let &[ref open_orders_acc, ref owner_acc, ref destination_acc, ref market_acc] =
+                array_ref![accounts, 0, 4];
+            let owner = SignerAccount::new(owner_acc)?;
+            let market = MarketState::load(market_acc, program_id)?;
+            let mut open_orders =
+                market.load_orders_mut(open_orders_acc, Some(owner.inner()), program_id, None)?;
+            check_assert!(destination_acc.key != open_orders_acc.key)?;
+
+            let args = CloseOpenOrdersArgs {
+                open_orders: open_orders.deref_mut(),
+                open_orders_acc,
+                destination: destination_acc,
             };
             f(args)
         }
@@ -1701,7 +2760,7 @@ pub mod account_parser {
 
     pub struct DisableMarketArgs<'a, 'b: 'a> {
         pub market: &'a mut MarketState,
-        pub authorization: SigningDisableAuthority<'a, 'b>,
+        pub authorization: SigningAuthority<'a, 'b>,
     }
     impl<'a, 'b: 'a> DisableMarketArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
@@ -1712,7 +2771,7 @@ pub mod account_parser {
             check_assert_eq!(accounts.len(), 2)?;
             let &[ref market_acc, ref signer_acc] = array_ref![accounts, 0, 2];
             let mut market = MarketState::load(market_acc, program_id)?;
-            let authorization = SigningDisableAuthority::new(signer_acc)?;
+            let authorization = SigningAuthority::new(signer_acc, &market)?;
 
             let args = DisableMarketArgs {
                 market: market.deref_mut(),
@@ -1722,68 +2781,281 @@ pub mod account_parser {
         }
     }
 
-    pub struct SweepFeesArgs<'a, 'b: 'a> {
+    pub struct SetMarketModeArgs<'a, 'b: 'a> {
+        pub mode: MarketMode,
         pub market: &'a mut MarketState,
-        pub pc_vault: PcVault<'a, 'b>,
-        pub fee_receiver: PcWallet<'a, 'b>,
-        pub vault_signer: VaultSigner<'a, 'b>,
-        pub spl_token_program: SplTokenProgram<'a, 'b>,
-        pub authorization: SigningFeeSweeper<'a, 'b>,
+        pub authorization: SigningAuthority<'a, 'b>,
     }
-    impl<'a, 'b: 'a> SweepFeesArgs<'a, 'b> {
+    impl<'a, 'b: 'a> SetMarketModeArgs<'a, 'b> {
         pub fn with_parsed_args<T>(
             program_id: &'a Pubkey,
+            mode: MarketMode,
             accounts: &'a [AccountInfo<'b>],
-            f: impl FnOnce(SweepFeesArgs) -> DexResult<T>,
+            f: impl FnOnce(SetMarketModeArgs) -> DexResult<T>,
         ) -> DexResult<T> {
-            check_assert_eq!(accounts.len(), 6)?;
-            #[rustfmt::skip]
-            let &[
-                ref market_acc,
-                ref pc_vault_acc,
-                ref sweep_authority_acc,
-                ref pc_wallet_acc,
-                ref vault_signer_acc,
-                ref spl_token_program
-            ] = array_ref![accounts, 0, 6];
-
+            check_assert_eq!(accounts.len(), 2)?;
+            let &[ref market_acc, ref signer_acc] = array_ref![accounts, 0, 2];
             let mut market = MarketState::load(market_acc, program_id)?;
-            let pc_vault = PcVault::from_account(pc_vault_acc, &market)?;
-            let fee_receiver = PcWallet::from_account(pc_wallet_acc, &market)?;
-            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
-            let spl_token_program = SplTokenProgram::new(spl_token_program)?;
-            let authorization = SigningFeeSweeper::new(sweep_authority_acc)?;
+            let authorization = SigningAuthority::new(signer_acc, &market)?;
 
-            let args = SweepFeesArgs {
+            let args = SetMarketModeArgs {
+                mode,
                 market: market.deref_mut(),
-                pc_vault,
-                fee_receiver,
-                vault_signer,
-                spl_token_program,
                 authorization,
             };
             f(args)
         }
     }
-}
-
-#[inline]
-fn remove_slop<T: Pod>(bytes: &[u8]) -> &[T] {
-    let slop = bytes.len() % size_of::<T>();
-    let new_len = bytes.len() - slop;
-    cast_slice(&bytes[..new_len])
-}
 
-#[inline]
-fn remove_slop_mut<T: Pod>(bytes: &mut [u8]) -> &mut [T] {
-    let slop = bytes.len() % size_of::<T>();
-    let new_len = bytes.len() - slop;
-    cast_slice_mut(&mut bytes[..new_len])
-}
+    pub struct SetAuthorityArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub new_authority: Option<&'a AccountInfo<'b>>,
+        pub authorization: SigningAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SetAuthorityArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetAuthorityArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() == 2 || accounts.len() == 3)?;
+            let (&[ref market_acc, ref signer_acc], new_authority_acc) =
+                array_refs![accounts, 2; .. ;];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authorization = SigningAuthority::new(signer_acc, &market)?;
 
-#[cfg_attr(not(feature = "program"), allow(unused))]
-impl State {
-    #[cfg(feature = "program")]
+            let args = SetAuthorityArgs {
+                market: market.deref_mut(),
+                new_authority: new_authority_acc.first(),
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct AcceptAuthorityArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub new_authority: SignerAccount<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> AcceptAuthorityArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(AcceptAuthorityArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 2)?;
+            let &[ref market_acc, ref signer_acc] = array_ref![accounts, 0, 2];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let new_authority = SignerAccount::new(signer_acc)?;
+
+            let args = AcceptAuthorityArgs {
+                market: market.deref_mut(),
+                new_authority,
+            };
+            f(args)
+        }
+    }
+
+    pub struct MigrateBooksArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        // bids, asks, request queue and event queue
+        pub old_accounts: &'a [AccountInfo<'b>; 4],
+        pub new_accounts: &'a [AccountInfo<'b>; 4],
+        pub authorization: SigningAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> MigrateBooksArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(MigrateBooksArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 10)?;
+            let (&[ref market_acc], old_accounts, new_accounts, &[ref signer_acc]) =
+                array_refs![array_ref![accounts, 0, 10], 1, 4, 4, 1];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authorization = SigningAuthority::new(signer_acc, &market)?;
+            for (i, new_account) in new_accounts.iter().enumerate() {
+                check_assert!(new_account.key != market_acc.key)?;
+                check_assert!(old_accounts
+                    .iter()
+                    .chain(&new_accounts[..i])
+                    .all(|other| other.key != new_account.key))?;
+            }
+            for (old_account, new_account) in old_accounts.iter().zip(new_accounts.iter()) {
+                check_assert_eq!(new_account.owner, program_id)?;
+                let data = new_account.try_borrow_data()?;
+                check_assert_eq!(data.len() % 8, 4)?;
+                check_assert!(data.len() >= 20)?;
+                let (padding5, header, _, padding7) = array_refs![&data, 5, 8; .. ; 7];
+                check_assert_eq!(*padding5, [0u8; 5])?;
+                check_assert_eq!(*header, [0u8; 8])?;
+                check_assert_eq!(*padding7, [0u8; 7])?;
+                if data.len() < old_account.data_len() {
+                    Err(DexErrorCode::MigrationTargetTooSmall)?
+                }
+            }
+
+            let args = MigrateBooksArgs {
+                market: market.deref_mut(),
+                old_accounts,
+                new_accounts,
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetCrankRewardArgs<'a, 'b: 'a> {
+        pub crank_reward: &'a CrankReward,
+        pub market: &'a mut MarketState,
+        pub authorization: SigningAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SetCrankRewardArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            crank_reward: &'a CrankReward,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetCrankRewardArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 2)?;
+            let &[ref market_acc, ref signer_acc] = array_ref![accounts, 0, 2];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authorization = SigningAuthority::new(signer_acc, &market)?;
+
+            let args = SetCrankRewardArgs {
+                crank_reward,
+                market: market.deref_mut(),
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub struct SetFeeSplitArgs<'a, 'b: 'a> {
+        pub fee_split: &'a FeeSplit,
+        pub market: &'a mut MarketState,
+        pub authorization: SigningAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SetFeeSplitArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            fee_split: &'a FeeSplit,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SetFeeSplitArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert_eq!(accounts.len(), 2)?;
+            let &[ref market_acc, ref signer_acc] = array_ref![accounts, 0, 2];
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let authorization = SigningAuthority::new(signer_acc, &market)?;
+
+            let args = SetFeeSplitArgs {
+                fee_split,
+                market: market.deref_mut(),
+                authorization,
+            };
+            f(args)
+        }
+    }
+
+    pub enum FeeVault<'a, 'b: 'a> {
+        Coin(CoinVault<'a, 'b>),
+        Pc(PcVault<'a, 'b>),
+    }
+
+    pub struct SweepFeesArgs<'a, 'b: 'a> {
+        pub market: &'a mut MarketState,
+        pub fee_vault: FeeVault<'a, 'b>,
+        // one per recipient of the market's fee split, in order, or a single
+        // receiver if the market has no split
+        pub fee_receivers: Vec<TokenAccount<'a, 'b>>,
+        pub vault_signer: VaultSigner<'a, 'b>,
+        pub spl_token_program: SplTokenProgram<'a, 'b>,
+        pub authorization: SigningAuthority<'a, 'b>,
+    }
+    impl<'a, 'b: 'a> SweepFeesArgs<'a, 'b> {
+        pub fn with_parsed_args<T>(
+            program_id: &'a Pubkey,
+            accounts: &'a [AccountInfo<'b>],
+            f: impl FnOnce(SweepFeesArgs) -> DexResult<T>,
+        ) -> DexResult<T> {
+            check_assert!(accounts.len() >= 6)?;
+            #[rustfmt::skip]
+            let (&[
+                ref market_acc,
+                ref vault_acc,
+                ref sweep_authority_acc,
+                ref wallet_acc,
+                ref vault_signer_acc,
+                ref spl_token_program
+            ], extra_wallet_accs) = array_refs![accounts, 6; .. ;];
+
+            let mut market = MarketState::load(market_acc, program_id)?;
+            let fee_split = market.fee_split;
+            let wallet_accs = std::iter::once(wallet_acc).chain(extra_wallet_accs);
+            let is_coin_vault = vault_acc.key.to_aligned_bytes() == market.coin_vault;
+            let fee_vault = if is_coin_vault {
+                FeeVault::Coin(CoinVault::from_account(vault_acc, &market)?)
+            } else {
+                FeeVault::Pc(PcVault::from_account(vault_acc, &market)?)
+            };
+            let mut fee_receivers = Vec::with_capacity(1 + extra_wallet_accs.len());
+            for wallet_acc in wallet_accs {
+                fee_receivers.push(if is_coin_vault {
+                    CoinWallet::from_account(wallet_acc, &market)?.token_account()
+                } else {
+                    PcWallet::from_account(wallet_acc, &market)?.token_account()
+                });
+            }
+            if fee_split.is_empty() {
+                check_assert_eq!(fee_receivers.len(), 1)?;
+            } else {
+                let recipients = fee_split
+                    .recipients
+                    .iter()
+                    .zip(&fee_split.shares_bps)
+                    .filter(|(_, &share)| share > 0)
+                    .map(|(recipient, _)| recipient);
+                check_assert_eq!(fee_receivers.len(), recipients.clone().count())?;
+                for (fee_receiver, recipient) in fee_receivers.iter().zip(recipients) {
+                    if !fee_receiver.is_owned_by(recipient)? {
+                        Err(DexErrorCode::WrongFeeReceiver)?
+                    }
+                }
+            }
+            let vault_signer = VaultSigner::new(vault_signer_acc, &market, program_id)?;
+            let spl_token_program = SplTokenProgram::new(spl_token_program)?;
+            let authorization = SigningAuthority::new(sweep_authority_acc, &market)?;
+
+            let args = SweepFeesArgs {
+                market: market.deref_mut(),
+                fee_vault,
+                fee_receivers,
+                vault_signer,
+                spl_token_program,
+                authorization,
+            };
+            f(args)
+        }
+    }
+}
+
+#[inline]
+fn remove_slop<T: Pod>(bytes: &[u8]) -> &[T] {
+    let slop = bytes.len() % size_of::<T>();
+    let new_len = bytes.len() - slop;
+    cast_slice(&bytes[..new_len])
+}
+
+#[inline]
+fn remove_slop_mut<T: Pod>(bytes: &mut [u8]) -> &mut [T] {
+    let slop = bytes.len() % size_of::<T>();
+    let new_len = bytes.len() - slop;
+    cast_slice_mut(&mut bytes[..new_len])
+}
+
+#[cfg_attr(not(feature = "program"), allow(unused))]
+impl State {
+    #[cfg(feature = "program")]
     pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> DexResult {
         let instruction = MarketInstruction::unpack(input).ok_or(ProgramError::InvalidArgument)?;
         match instruction {
@@ -1791,6 +3063,25 @@ impl State {
                 account_parser::InitializeMarketArgs::new(program_id, inner, accounts)?,
             )?,
             MarketInstruction::NewOrder(ref inner) => {
+                let inner = inner
+                    .clone()
+                    .add_self_trade_behavior(SelfTradeBehavior::DecrementTake);
+                account_parser::NewOrderArgs::with_parsed_args(
+                    program_id,
+                    &inner,
+                    accounts,
+                    Self::process_new_order,
+                )?
+            }
+            MarketInstruction::NewOrders(ref inner) => {
+                account_parser::NewOrdersArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_new_orders,
+                )?
+            }
+            MarketInstruction::NewOrderV2(ref inner) => {
                 account_parser::NewOrderArgs::with_parsed_args(
                     program_id,
                     inner,
@@ -1811,6 +3102,16 @@ impl State {
                     program_id,
                     accounts,
                     limit,
+                    false,
+                    Self::process_consume_events,
+                )?
+            }
+            MarketInstruction::ConsumeEventsWithReward(limit) => {
+                account_parser::ConsumeEventsArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    limit,
+                    true,
                     Self::process_consume_events,
                 )?
             }
@@ -1842,11 +3143,99 @@ impl State {
                     Self::process_disable_market,
                 )?
             }
+            MarketInstruction::MigrateBooks => account_parser::MigrateBooksArgs::with_parsed_args(
+                program_id,
+                accounts,
+                Self::process_migrate_books,
+            )?,
+            MarketInstruction::SetMarketMode(mode) => {
+                account_parser::SetMarketModeArgs::with_parsed_args(
+                    program_id,
+                    mode,
+                    accounts,
+                    Self::process_set_market_mode,
+                )?
+            }
+            MarketInstruction::CloseOpenOrders => {
+                account_parser::CloseOpenOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_close_open_orders,
+                )?
+            }
+            MarketInstruction::SetAuthority => account_parser::SetAuthorityArgs::with_parsed_args(
+                program_id,
+                accounts,
+                Self::process_set_authority,
+            )?,
+            MarketInstruction::AcceptAuthority => {
+                account_parser::AcceptAuthorityArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    Self::process_accept_authority,
+                )?
+            }
             MarketInstruction::SweepFees => account_parser::SweepFeesArgs::with_parsed_args(
                 program_id,
                 accounts,
                 Self::process_sweep_fees,
             )?,
+            MarketInstruction::SetCrankReward(ref inner) => {
+                account_parser::SetCrankRewardArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_set_crank_reward,
+                )?
+            }
+            MarketInstruction::SetFeeSplit(ref inner) => {
+                account_parser::SetFeeSplitArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_set_fee_split,
+                )?
+            }
+            MarketInstruction::PruneExpiredOrders(limit) => {
+                account_parser::PruneExpiredOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    limit,
+                    Self::process_prune_expired_orders,
+                )?
+            }
+            MarketInstruction::ReduceOrder(ref inner) => {
+                account_parser::ReduceOrderArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    inner,
+                    Self::process_reduce_order,
+                )?
+            }
+            MarketInstruction::NewStopOrder(ref inner) => {
+                account_parser::NewStopOrderArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_new_stop_order,
+                )?
+            }
+            MarketInstruction::CancelAllOrders(ref inner) => {
+                account_parser::CancelAllOrdersArgs::with_parsed_args(
+                    program_id,
+                    accounts,
+                    inner,
+                    Self::process_cancel_all_orders,
+                )?
+            }
+            MarketInstruction::ReplaceOrder(ref inner) => {
+                account_parser::ReplaceOrderArgs::with_parsed_args(
+                    program_id,
+                    inner,
+                    accounts,
+                    Self::process_replace_order,
+                )?
+            }
         };
         Ok(())
     }
@@ -1862,6 +3251,7 @@ impl State {
             pc_wallet,
             vault_signer,
             spl_token_program,
+            referrer,
         } = args;
 
         let native_coin_amount = open_orders.native_coin_free;
@@ -1895,11 +3285,20 @@ impl State {
                 pc_vault.token_account(),
             ),
         ];
+        let referrer_info = referrer.map(|referrer| {
+            let native_rebate_amount = open_orders.referrer_rebates_accrued;
+            open_orders.referrer_rebates_accrued = 0;
+            (
+                native_rebate_amount,
+                referrer.token_account(),
+                pc_vault.token_account(),
+            )
+        });
 
         let market_pubkey = market.pubkey();
         let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
 
-        for (token_amount, wallet_account, vault) in token_infos.iter() {
+        for (token_amount, wallet_account, vault) in token_infos.iter().chain(&referrer_info) {
             let deposit_instruction = spl_token::instruction::transfer(
                 &spl_token::ID,
                 vault.inner().key,
@@ -1986,6 +3385,82 @@ impl State {
         Ok(())
     }
 
+    fn process_cancel_all_orders(args: account_parser::CancelAllOrdersArgs) -> DexResult {
+        let account_parser::CancelAllOrdersArgs {
+            instruction,
+            open_orders,
+            open_orders_address,
+            orders_owner: _,
+            mut order_book_state,
+            mut req_q,
+            mut event_q,
+            mut trigger_book,
+        } = args;
+
+        let mut limit_remaining = instruction.limit;
+        for slot in 0..128u8 {
+            if limit_remaining == 0 {
+                break;
+            }
+            let side = match open_orders.slot_side(slot) {
+                Some(side) if instruction.side.map_or(true, |s| s == side) => side,
+                _ => continue,
+            };
+            let cancelled = order_book_state.cancel_order_now(
+                &mut trigger_book,
+                open_orders,
+                open_orders_address,
+                slot,
+                &mut event_q,
+            )?;
+            if !cancelled {
+                // the order is still waiting in the request queue, so the
+                // cancel has to wait behind it
+                let request = Request::new(RequestView::CancelOrder {
+                    cancel_id: req_q.gen_seq_num(),
+                    expected_owner: open_orders_address,
+                    expected_owner_slot: slot,
+                    order_id: &open_orders.orders[slot as usize],
+                    side,
+                    client_order_id: NonZeroU64::new(open_orders.client_order_ids[slot as usize]),
+                });
+                req_q
+                    .push_back(request)
+                    .map_err(|_| DexErrorCode::RequestQueueFull)?;
+            }
+            limit_remaining -= 1;
+        }
+        Ok(())
+    }
+
+    fn process_reduce_order(args: account_parser::ReduceOrderArgs) -> DexResult {
+        let account_parser::ReduceOrderArgs {
+            instruction,
+            open_orders,
+            open_orders_address,
+            mut req_q,
+            orders_owner: _,
+        } = args;
+
+        let expected_owner_slot = (0..128u8)
+            .find(|&slot| {
+                open_orders.slot_side(slot) == Some(instruction.side)
+                    && open_orders.orders[slot as usize] == instruction.order_id
+            })
+            .ok_or(DexErrorCode::OrderNotFound)?;
+        let request = Request::new(RequestView::ReduceOrder {
+            side: instruction.side,
+            order_id: &instruction.order_id,
+            new_qty: instruction.new_qty,
+            expected_owner_slot,
+            expected_owner: open_orders_address,
+        });
+        req_q
+            .push_back(request)
+            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        Ok(())
+    }
+
     fn process_consume_events(args: account_parser::ConsumeEventsArgs) -> DexResult {
         let account_parser::ConsumeEventsArgs {
             limit,
@@ -1993,8 +3468,10 @@ impl State {
             open_orders_accounts,
             market,
             mut event_q,
+            crank_reward_accounts,
         } = args;
 
+        let mut events_consumed = 0u64;
         for _i in 0u16..limit {
             let event = match event_q.peek_front() {
                 None => break,
@@ -2032,13 +3509,30 @@ impl State {
                     owner: _,
                     owner_slot,
                     client_order_id,
+                    referred,
                 } => {
+                    // the notional traded, without the fee or rebate if it
+                    // was paid in pc
+                    let coin_fees = market.fee_schedule.coin_fees_on_asks();
+                    let native_pc_fee_or_rebate = if coin_fees && (side == Side::Bid) == maker {
+                        0
+                    } else {
+                        native_fee_or_rebate
+                    };
+                    let native_pc_qty = match (side, maker) {
+                        (Side::Bid, false) => native_qty_paid - native_pc_fee_or_rebate,
+                        (Side::Bid, true) => native_qty_paid + native_pc_fee_or_rebate,
+                        (Side::Ask, false) => native_qty_received + native_pc_fee_or_rebate,
+                        (Side::Ask, true) => native_qty_received - native_pc_fee_or_rebate,
+                    };
+                    open_orders.add_native_pc_volume(market.fee_epoch, native_pc_qty);
                     match side {
                         Side::Bid => {
                             open_orders.native_pc_total -= native_qty_paid;
                             open_orders.native_coin_total += native_qty_received;
                             open_orders.native_coin_free += native_qty_received;
-                            if maker {
+                            // a rebate in coin is already part of the coin received
+                            if maker && !market.fee_schedule.coin_fees_on_asks() {
                                 open_orders.native_pc_free += native_fee_or_rebate;
                             }
                         }
@@ -2048,6 +3542,10 @@ impl State {
                             open_orders.native_pc_free += native_qty_received;
                         }
                     };
+                    if referred {
+                        open_orders.referrer_rebates_accrued +=
+                            market.fee_schedule.referrer_rebate(native_fee_or_rebate);
+                    }
                     if let Some(client_id) = client_order_id {
                         debug_assert_eq!(
                             client_id.get(),
@@ -2090,11 +3588,23 @@ impl State {
                         open_orders.remove_order(owner_slot)?;
                     }
                 }
-            };
-
+                EventView::Refill {
+                    new_order_id,
+                    owner_slot,
+                    ..
+                } => {
+                    open_orders.orders[owner_slot as usize] = new_order_id;
+                }
+            };
+
             event_q
                 .pop_front()
                 .map_err(|()| DexErrorCode::ConsumeEventsQueueFailure)?;
+            events_consumed += 1;
+        }
+        #[cfg(feature = "program")]
+        if let Some(crank_reward_accounts) = crank_reward_accounts {
+            Self::pay_crank_reward(market, crank_reward_accounts, events_consumed)?;
         }
         Ok(())
     }
@@ -2104,32 +3614,280 @@ impl State {
             mut order_book_state,
             mut req_q,
             mut event_q,
+            mut trigger_book,
+            limit,
+            crank_reward_accounts,
+        } = args;
+        order_book_state.market_state.fee_epoch = order_book_state.clock.epoch;
+        let work_done = order_book_state.process_requests(
+            &mut req_q,
+            &mut event_q,
+            &mut trigger_book,
+            limit,
+        )?;
+        #[cfg(feature = "program")]
+        if let Some(crank_reward_accounts) = crank_reward_accounts {
+            Self::pay_crank_reward(
+                order_book_state.market_state,
+                crank_reward_accounts,
+                work_done.into(),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn process_prune_expired_orders(args: account_parser::PruneExpiredOrdersArgs) -> DexResult {
+        let account_parser::PruneExpiredOrdersArgs {
+            mut order_book_state,
+            mut event_q,
             limit,
         } = args;
-        order_book_state.process_requests(&mut req_q, &mut event_q, limit)
+        order_book_state.prune_expired_orders(&mut event_q, limit)
     }
 
     #[cfg(feature = "program")]
     fn process_new_order(args: account_parser::NewOrderArgs) -> DexResult {
-        let account_parser::NewOrderArgs {
-            instruction,
-            market,
-            open_orders,
-            open_orders_address,
-            mut req_q,
-            payer,
-            owner,
-            coin_vault,
-            pc_vault,
-            spl_token_program,
-            fee_tier,
+        let mut args = args;
+        if let Some((ref bids, ref asks)) = args.books {
+            Self::check_self_trade(args.instruction, args.open_orders_address, bids, asks, None)?;
+        }
+        let request = Self::lock_new_order(&mut args, None)?;
+        args.req_q
+            .push_back(request)
+            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_orders(args: account_parser::NewOrdersArgs) -> DexResult {
+        let account_parser::NewOrdersArgs {
+            mut new_order_args,
+            orders,
         } = args;
 
-        let deposit_amount;
-        let deposit_vault;
+        if let Some((ref bids, ref asks)) = new_order_args.books {
+            for order in orders {
+                Self::check_self_trade(
+                    order,
+                    new_order_args.open_orders_address,
+                    bids,
+                    asks,
+                    None,
+                )?;
+            }
+        }
+
+        let mut native_qty_locked = Vec::with_capacity(orders.len());
+        let mut total_qty_to_lock: u64 = 0;
+        for order in orders {
+            let lock_qty_native =
+                Self::native_qty_to_lock(new_order_args.market, order, new_order_args.fee_tier)?;
+            total_qty_to_lock = total_qty_to_lock
+                .checked_add(lock_qty_native)
+                .ok_or(DexErrorCode::InsufficientFunds)?;
+            native_qty_locked.push(lock_qty_native);
+        }
+        Self::lock_funds(&mut new_order_args, orders[0].side, total_qty_to_lock)?;
+
+        for (order, &lock_qty_native) in orders.iter().zip(&native_qty_locked) {
+            let request =
+                Self::record_new_order(&mut new_order_args, order, lock_qty_native, None)?;
+            new_order_args
+                .req_q
+                .push_back(request)
+                .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_new_stop_order(args: account_parser::NewStopOrderArgs) -> DexResult {
+        let account_parser::NewStopOrderArgs {
+            mut new_order_args,
+            mut trigger_book,
+            trigger_price,
+            trigger_condition,
+        } = args;
+        if trigger_book.full() {
+            Err(DexErrorCode::TriggerBookFull)?
+        }
+        let native_qty_locked = Self::native_qty_to_lock(
+            new_order_args.market,
+            new_order_args.instruction,
+            new_order_args.fee_tier,
+        )?;
+        let request = Self::lock_new_order(&mut new_order_args, None)?;
+        trigger_book
+            .push(StopOrder::new(
+                trigger_price,
+                trigger_condition,
+                request,
+                native_qty_locked,
+            ))
+            .map_err(|_| DexErrorCode::TriggerBookFull)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "program")]
+    fn process_replace_order(args: account_parser::ReplaceOrderArgs) -> DexResult {
+        let account_parser::ReplaceOrderArgs {
+            mut new_order_args,
+            bids,
+            asks,
+            target,
+            place_if_missing,
+        } = args;
+
+        let replaced = {
+            let open_orders = &new_order_args.open_orders;
+            let replaced_owner_slot = (0..128u8).find(|&slot| {
+                open_orders.slot_side(slot).is_some()
+                    && match *target {
+                        ReplaceTarget::OrderId(order_id) => {
+                            open_orders.orders[slot as usize] == order_id
+                        }
+                        ReplaceTarget::ClientId(client_id) => {
+                            open_orders.client_order_ids[slot as usize] == client_id
+                        }
+                    }
+            });
+            match replaced_owner_slot {
+                None if place_if_missing => None,
+                None => Err(DexErrorCode::OrderNotFound)?,
+                Some(owner_slot) => {
+                    let side = open_orders.slot_side(owner_slot).unwrap();
+                    let order_id = open_orders.orders[owner_slot as usize];
+
+                    // only the funds of a same-side order can be carried over
+                    let mut native_qty_borrowed = 0;
+                    if side == new_order_args.instruction.side {
+                        let slab = match side {
+                            Side::Bid => &bids,
+                            Side::Ask => &asks,
+                        };
+                        let resting = slab
+                            .find_by_key(&order_id)
+                            .and_then(|h| slab.get(h))
+                            .and_then(|node| node.as_leaf())
+                            .filter(|leaf| {
+                                leaf.owner() == new_order_args.open_orders_address
+                                    && leaf.owner_slot() == owner_slot
+                            });
+                        if let Some(leaf) = resting {
+                            let market = &new_order_args.market;
+                            native_qty_borrowed = match side {
+                                Side::Bid => leaf
+                                    .total_quantity()
+                                    .checked_mul(leaf.price().get())
+                                    .and_then(|lots| lots.checked_mul(market.pc_lot_size)),
+                                Side::Ask => {
+                                    leaf.total_quantity().checked_mul(market.coin_lot_size)
+                                }
+                            }
+                            .ok_or(DexErrorCode::InsufficientFunds)?;
+                        }
+                    }
+                    Some(ReplacedOrder {
+                        side,
+                        order_id,
+                        owner_slot,
+                        native_qty_borrowed,
+                        place_if_missing,
+                    })
+                }
+            }
+        };
+
+        Self::check_self_trade(
+            new_order_args.instruction,
+            new_order_args.open_orders_address,
+            &bids,
+            &asks,
+            replaced.as_ref().map(|replaced| &replaced.order_id),
+        )?;
+        let request = Self::lock_new_order(&mut new_order_args, replaced)?;
+        new_order_args
+            .req_q
+            .push_back(request)
+            .map_err(|_| DexErrorCode::RequestQueueFull)?;
+        Ok(())
+    }
 
-        let native_pc_qty_locked;
-        match instruction.side {
+    /// Fails an order that aborts on a self-trade if it would cross one of
+    /// the owner's resting orders. The order it replaces, if any, is passed
+    /// over, since that one is cancelled before the new order is matched.
+    pub(crate) fn check_self_trade(
+        instruction: &NewOrderInstructionV2,
+        owner: &[u64; 4],
+        bids: &Slab,
+        asks: &Slab,
+        replaced_order_id: Option<&u128>,
+    ) -> DexResult {
+        if instruction.self_trade_behavior != SelfTradeBehavior::AbortTransaction {
+            return Ok(());
+        }
+        let opposite = match instruction.side {
+            Side::Bid => asks,
+            Side::Ask => bids,
+        };
+        if would_self_trade(
+            opposite,
+            instruction.side,
+            instruction.limit_price.get(),
+            instruction.max_qty.get(),
+            owner,
+            |resting| Some(resting.order_id()) == replaced_order_id,
+        ) {
+            Err(DexErrorCode::WouldSelfTrade)?
+        }
+        Ok(())
+    }
+
+    /// Locks the funds for a new order in the owner's `OpenOrders`, records
+    /// the order there and returns the request that will match it. Funds the
+    /// order borrows from a replaced order are not locked a second time.
+    #[cfg(feature = "program")]
+    fn lock_new_order(
+        args: &mut account_parser::NewOrderArgs,
+        replaces: Option<ReplacedOrder>,
+    ) -> DexResult<Request> {
+        let instruction = args.instruction;
+        let lock_qty_native = Self::native_qty_to_lock(args.market, instruction, args.fee_tier)?;
+        let native_qty_borrowed =
+            replaces.map_or(0, |r| r.native_qty_borrowed.min(lock_qty_native));
+        Self::lock_funds(
+            args,
+            instruction.side,
+            lock_qty_native - native_qty_borrowed,
+        )?;
+        Self::record_new_order(
+            args,
+            instruction,
+            lock_qty_native,
+            replaces.map(|replaced| ReplacedOrder {
+                native_qty_borrowed,
+                ..replaced
+            }),
+        )
+    }
+
+    /// The native quantity a new order locks: the coin it offers, or the pc
+    /// it may spend including the taker fee.
+    pub(crate) fn native_qty_to_lock(
+        market: &MarketState,
+        instruction: &NewOrderInstructionV2,
+        fee_tier: FeeTier,
+    ) -> DexResult<u64> {
+        if instruction.native_pc_qty != 0 {
+            match instruction.order_type {
+                OrderType::ImmediateOrCancel | OrderType::FillOrKill => (),
+                OrderType::Limit | OrderType::PostOnly => {
+                    Err(DexErrorCode::QuoteSizedOrderNotImmediate)?
+                }
+            }
+        }
+        Ok(match instruction.side {
+            Side::Bid if instruction.native_pc_qty != 0 => instruction.native_pc_qty,
             Side::Bid => {
                 let lock_qty_lots = instruction
                     .max_qty
@@ -2139,12 +3897,56 @@ impl State {
                 let native_lock_qty_before_fee = lock_qty_lots
                     .checked_mul(market.pc_lot_size)
                     .ok_or(DexErrorCode::InsufficientFunds)?;
-                let lock_qty_native = native_lock_qty_before_fee
-                    .checked_add(fee_tier.taker_fee(native_lock_qty_before_fee))
+                native_lock_qty_before_fee
+                    .checked_add(
+                        market
+                            .fee_schedule
+                            .taker_fee(fee_tier, native_lock_qty_before_fee),
+                    )
+                    .ok_or(DexErrorCode::InsufficientFunds)?
+            }
+            Side::Ask => {
+                let native_lock_qty_before_fee = instruction
+                    .max_qty
+                    .get()
+                    .checked_mul(market.coin_lot_size)
                     .ok_or(DexErrorCode::InsufficientFunds)?;
-                native_pc_qty_locked = Some(NonZeroU64::new(lock_qty_native).unwrap());
-                let free_qty_to_lock = lock_qty_native.min(open_orders.native_pc_free);
-                deposit_amount = lock_qty_native - free_qty_to_lock;
+                native_lock_qty_before_fee
+                    .checked_add(
+                        market
+                            .fee_schedule
+                            .native_coin_fee_reserve(fee_tier, native_lock_qty_before_fee),
+                    )
+                    .ok_or(DexErrorCode::InsufficientFunds)?
+            }
+        })
+    }
+
+    /// Locks `qty_to_lock` of the currency paid by `side`, first from the
+    /// owner's free balance and then by depositing the rest from the payer.
+    #[cfg(feature = "program")]
+    fn lock_funds(
+        args: &mut account_parser::NewOrderArgs,
+        side: Side,
+        qty_to_lock: u64,
+    ) -> DexResult {
+        let account_parser::NewOrderArgs {
+            ref mut market,
+            ref mut open_orders,
+            ref payer,
+            ref owner,
+            ref coin_vault,
+            ref pc_vault,
+            ref spl_token_program,
+            ..
+        } = *args;
+
+        let deposit_amount;
+        let deposit_vault;
+        match side {
+            Side::Bid => {
+                let free_qty_to_lock = qty_to_lock.min(open_orders.native_pc_free);
+                deposit_amount = qty_to_lock - free_qty_to_lock;
                 deposit_vault = pc_vault.token_account();
                 open_orders.lock_free_pc(free_qty_to_lock);
                 open_orders.credit_locked_pc(deposit_amount);
@@ -2154,17 +3956,11 @@ impl State {
                     .unwrap();
             }
             Side::Ask => {
-                let lock_qty_native = instruction
-                    .max_qty
-                    .get()
-                    .checked_mul((*market).coin_lot_size)
-                    .ok_or(DexErrorCode::InsufficientFunds)?;
-                let free_qty_to_lock = lock_qty_native.min(open_orders.native_coin_free);
-                deposit_amount = lock_qty_native - free_qty_to_lock;
+                let free_qty_to_lock = qty_to_lock.min(open_orders.native_coin_free);
+                deposit_amount = qty_to_lock - free_qty_to_lock;
                 deposit_vault = coin_vault.token_account();
                 open_orders.lock_free_coin(free_qty_to_lock);
                 open_orders.credit_locked_coin(deposit_amount);
-                native_pc_qty_locked = None;
                 market.coin_deposits_total = market
                     .coin_deposits_total
                     .checked_add(deposit_amount)
@@ -2203,6 +3999,37 @@ impl State {
             _ => DexErrorCode::TransferFailed,
         })?;
 
+        Ok(())
+    }
+
+    /// Records a new order whose funds are already locked in the owner's
+    /// `OpenOrders` and returns the request that will match it.
+    #[cfg(feature = "program")]
+    fn record_new_order(
+        args: &mut account_parser::NewOrderArgs,
+        instruction: &NewOrderInstructionV2,
+        native_qty_locked: u64,
+        replaces: Option<ReplacedOrder>,
+    ) -> DexResult<Request> {
+        let account_parser::NewOrderArgs {
+            ref mut open_orders,
+            open_orders_address,
+            ref mut req_q,
+            fee_tier,
+            ref referrer,
+            ..
+        } = *args;
+
+        let native_pc_qty_locked = match instruction.side {
+            Side::Bid => Some(NonZeroU64::new(native_qty_locked).ok_or(assertion_error!())?),
+            Side::Ask => None,
+        };
+
+        let referrer = referrer.map(|referrer| referrer.account().key.to_aligned_bytes());
+        if let Some(referrer) = referrer {
+            open_orders.set_referrer(referrer)?;
+        }
+
         // record the open order in the user account
         let order_id = req_q.gen_order_id(instruction.limit_price.get(), instruction.side);
         let owner_slot = open_orders.add_order(order_id, instruction.side)?;
@@ -2219,12 +4046,18 @@ impl State {
             max_coin_qty: instruction.max_qty,
             native_pc_qty_locked,
             client_order_id: NonZeroU64::new(instruction.client_id),
+            self_trade_behavior: instruction.self_trade_behavior,
+            expiry_kind: instruction.expiry_kind,
+            expiry: instruction.expiry,
+            display_qty: NonZeroU64::new(instruction.display_qty),
+            replaces,
+            native_pc_qty_to_receive: match instruction.side {
+                Side::Bid => None,
+                Side::Ask => NonZeroU64::new(instruction.native_pc_qty),
+            },
+            referrer: referrer.as_ref(),
         });
-
-        req_q
-            .push_back(request)
-            .map_err(|_| DexErrorCode::RequestQueueFull)?;
-        Ok(())
+        Ok(request)
     }
 
     fn process_disable_market(args: account_parser::DisableMarketArgs) -> DexResult {
@@ -2232,71 +4065,299 @@ impl State {
             market,
             authorization: _,
         } = args;
-        market.account_flags = market.account_flags | (AccountFlag::Disabled as u64);
+        market.mode = u8::from(MarketMode::Halted) as u64;
+        Ok(())
+    }
+
+    fn process_close_open_orders(args: account_parser::CloseOpenOrdersArgs) -> DexResult {
+        let account_parser::CloseOpenOrdersArgs {
+            open_orders,
+            open_orders_acc,
+            destination,
+        } = args;
+        open_orders.check_empty()?;
+        // the rebates are owed to the referrer until they are settled
+        if open_orders.referrer_rebates_accrued != 0 {
+            Err(DexErrorCode::ReferrerInUse)?
+        }
+
+        let mut orders_lamports = open_orders_acc
+            .try_borrow_mut_lamports()
+            .map_err(|_| DexErrorCode::BorrowError)?;
+        let mut destination_lamports = destination
+            .try_borrow_mut_lamports()
+            .map_err(|_| DexErrorCode::BorrowError)?;
+        **destination_lamports = destination_lamports
+            .checked_add(**orders_lamports)
+            .ok_or(DexErrorCode::LamportsOverflow)?;
+        **orders_lamports = 0;
+        *open_orders = OpenOrders::zeroed();
+        Ok(())
+    }
+
+    fn process_set_market_mode(args: account_parser::SetMarketModeArgs) -> DexResult {
+        let account_parser::SetMarketModeArgs {
+            mode,
+            market,
+            authorization: _,
+        } = args;
+        market.mode = u8::from(mode) as u64;
+        Ok(())
+    }
+
+    fn process_set_authority(args: account_parser::SetAuthorityArgs) -> DexResult {
+        let account_parser::SetAuthorityArgs {
+            market,
+            new_authority,
+            authorization: _,
+        } = args;
+        match new_authority {
+            Some(new_authority) => {
+                market.pending_authority = new_authority.key.to_aligned_bytes();
+            }
+            None => {
+                market.authority = [0; 4];
+                market.pending_authority = [0; 4];
+            }
+        }
+        Ok(())
+    }
+
+    fn process_accept_authority(args: account_parser::AcceptAuthorityArgs) -> DexResult {
+        let account_parser::AcceptAuthorityArgs {
+            market,
+            new_authority,
+        } = args;
+        let new_authority = new_authority.inner().key.to_aligned_bytes();
+        if market.pending_authority == [0; 4] || market.pending_authority != new_authority {
+            Err(DexErrorCode::WrongAuthority)?
+        }
+        market.authority = new_authority;
+        market.pending_authority = [0; 4];
+        Ok(())
+    }
+
+    fn process_migrate_books(args: account_parser::MigrateBooksArgs) -> DexResult {
+        let account_parser::MigrateBooksArgs {
+            market,
+            old_accounts,
+            new_accounts,
+            authorization: _,
+        } = args;
+        let &[ref old_bids_acc, ref old_asks_acc, ref old_req_q_acc, ref old_event_q_acc] =
+            old_accounts;
+        let &[ref bids_acc, ref asks_acc, ref req_q_acc, ref event_q_acc] = new_accounts;
+
+        Self::init_order_book(bids_acc, AccountFlag::Bids)?;
+        Self::init_order_book(asks_acc, AccountFlag::Asks)?;
+        Self::init_request_queue(req_q_acc)?;
+        Self::init_event_queue(event_q_acc)?;
+        {
+            let old_bids = market.load_bids_mut(old_bids_acc)?;
+            let old_asks = market.load_asks_mut(old_asks_acc)?;
+            let old_req_q = market.load_request_queue_mut(old_req_q_acc)?;
+            let old_event_q = market.load_event_queue_mut(old_event_q_acc)?;
+            let items =
+                old_bids.leaf_count() + old_asks.leaf_count() + old_req_q.len() + old_event_q.len();
+            if items > MAX_MIGRATED_ITEMS {
+                Err(DexErrorCode::MigrationTooLarge)?
+            }
+
+            market.bids = bids_acc.key.to_aligned_bytes();
+            market.asks = asks_acc.key.to_aligned_bytes();
+            market.req_q = req_q_acc.key.to_aligned_bytes();
+            market.event_q = event_q_acc.key.to_aligned_bytes();
+            let mut bids = market.load_bids_mut(bids_acc)?;
+            let mut asks = market.load_asks_mut(asks_acc)?;
+            let mut req_q = market.load_request_queue_mut(req_q_acc)?;
+            let mut event_q = market.load_event_queue_mut(event_q_acc)?;
+
+            for leaf in old_bids.iter_desc() {
+                bids.insert_leaf(leaf)
+                    .map_err(|_| DexErrorCode::SlabTooSmall)?;
+            }
+            for leaf in old_asks.iter() {
+                asks.insert_leaf(leaf)
+                    .map_err(|_| DexErrorCode::SlabTooSmall)?;
+            }
+            for request in old_req_q.iter() {
+                req_q
+                    .push_back(*request)
+                    .map_err(|_| DexErrorCode::RequestQueueFull)?;
+            }
+            // later orders keep drawing their ids from the same sequence
+            req_q.header.next_seq_num = old_req_q.header.next_seq_num;
+            for event in old_event_q.iter() {
+                event_q
+                    .push_back(*event)
+                    .map_err(|_| DexErrorCode::EventQueueFull)?;
+            }
+            event_q.header.seq_num = old_event_q.header.seq_num;
+        }
+        for account in old_accounts {
+            Self::disable_account(account)?;
+        }
+        Ok(())
+    }
+
+    /// Flags an account the market no longer uses, so that clients reading it
+    /// can tell that it was replaced.
+    fn disable_account(account: &AccountInfo) -> DexResult {
+        let (mut header, _) = strip_header::<[u64; 1], u8>(account, false)?;
+        header[0] |= AccountFlag::Disabled as u64;
+        Ok(())
+    }
+
+    fn process_set_crank_reward(args: account_parser::SetCrankRewardArgs) -> DexResult {
+        let account_parser::SetCrankRewardArgs {
+            crank_reward,
+            market,
+            authorization: _,
+        } = args;
+        if !crank_reward.is_valid() {
+            Err(DexErrorCode::InvalidCrankReward)?
+        }
+        market.crank_reward = *crank_reward;
         Ok(())
     }
 
+    /// Pays the crank reward for `units` of work out of the accrued pc fees.
     #[cfg(feature = "program")]
-    fn process_sweep_fees(args: account_parser::SweepFeesArgs) -> DexResult {
-        let account_parser::SweepFeesArgs {
-            mut market,
+    fn pay_crank_reward(
+        market: &mut MarketState,
+        crank_reward_accounts: account_parser::CrankRewardAccounts,
+        units: u64,
+    ) -> DexResult {
+        let account_parser::CrankRewardAccounts {
             pc_vault,
-            fee_receiver,
             vault_signer,
             spl_token_program,
-            authorization: _,
-        } = args;
-        let token_amount = market.pc_fees_accrued;
-        market.pc_fees_accrued = 0;
-        let deposit_instruction = spl_token::instruction::transfer(
+            reward_wallet,
+        } = crank_reward_accounts;
+        if market.crank_reward_epoch != market.fee_epoch {
+            market.crank_reward_epoch = market.fee_epoch;
+            market.crank_reward_paid = 0;
+        }
+        let token_amount =
+            market
+                .crank_reward
+                .reward(units, market.pc_fees_accrued, market.crank_reward_paid);
+        if token_amount == 0 {
+            return Ok(());
+        }
+        market.pc_fees_accrued -= token_amount;
+        market.crank_reward_paid += token_amount;
+
+        let transfer_instruction = spl_token::instruction::transfer(
             &spl_token::ID,
             pc_vault.account().key,
-            fee_receiver.account().key,
+            reward_wallet.account().key,
             &vault_signer.inner().key,
             &[],
             token_amount,
         )?;
-
         let market_pubkey = market.pubkey();
         let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
         let accounts: &[AccountInfo] = &[
             pc_vault.account().clone(),
-            fee_receiver.account().clone(),
+            reward_wallet.account().clone(),
             vault_signer.inner().clone(),
             spl_token_program.inner().clone(),
         ];
-        info!("invoking...");
         invoke_spl_token(
-            &deposit_instruction,
+            &transfer_instruction,
             &accounts[..],
             &[&vault_signer_seeds[..]],
         )
         .map_err(|_| DexErrorCode::TransferFailed)?;
-        info!("invoked");
         Ok(())
     }
 
-    #[inline(never)]
-    fn process_initialize_market(args: account_parser::InitializeMarketArgs) -> DexResult {
-        let &InitializeMarketInstruction {
-            coin_lot_size,
-            pc_lot_size,
-            fee_rate_bps,
-            vault_signer_nonce,
-            pc_dust_threshold,
-        } = args.instruction;
+    fn process_set_fee_split(args: account_parser::SetFeeSplitArgs) -> DexResult {
+        let account_parser::SetFeeSplitArgs {
+            fee_split,
+            market,
+            authorization: _,
+        } = args;
+        if !fee_split.is_valid() {
+            Err(DexErrorCode::InvalidFeeSplit)?
+        }
+        market.fee_split = *fee_split;
+        Ok(())
+    }
 
-        let market = args.get_market();
-        let req_q = args.get_req_q();
-        let event_q = args.get_event_q();
-        let bids = args.get_bids();
-        let asks = args.get_asks();
-        let coin_vault = args.coin_vault_and_mint.get_account().inner();
-        let coin_mint = args.coin_vault_and_mint.get_mint().inner();
-        let pc_vault = args.pc_vault_and_mint.get_account().inner();
-        let pc_mint = args.pc_vault_and_mint.get_mint().inner();
+    #[cfg(feature = "program")]
+    fn process_sweep_fees(args: account_parser::SweepFeesArgs) -> DexResult {
+        let account_parser::SweepFeesArgs {
+            mut market,
+            fee_vault,
+            fee_receivers,
+            vault_signer,
+            spl_token_program,
+            authorization: _,
+        } = args;
+        let (token_amount, vault) = match fee_vault {
+            account_parser::FeeVault::Coin(vault) => {
+                let token_amount = market.coin_fees_accrued;
+                market.coin_fees_accrued = 0;
+                (token_amount, vault.account())
+            }
+            account_parser::FeeVault::Pc(vault) => {
+                let token_amount = market.pc_fees_accrued;
+                market.pc_fees_accrued = 0;
+                (token_amount, vault.account())
+            }
+        };
+        let fee_split = market.fee_split;
+        let mut amounts = if fee_split.is_empty() {
+            vec![token_amount]
+        } else {
+            let split_amounts = fee_split.split(token_amount);
+            fee_split
+                .shares_bps
+                .iter()
+                .zip(&split_amounts)
+                .filter(|(&share, _)| share > 0)
+                .map(|(_, &amount)| amount)
+                .collect()
+        };
+        // the first recipient takes whatever the others don't, so nothing the
+        // split rounds away is left behind in the vault
+        amounts[0] = token_amount - amounts[1..].iter().sum::<u64>();
+
+        let market_pubkey = market.pubkey();
+        let vault_signer_seeds = gen_vault_signer_seeds(&market.vault_signer_nonce, &market_pubkey);
+        for (fee_receiver, amount) in fee_receivers.into_iter().zip(amounts) {
+            if amount == 0 {
+                continue;
+            }
+            let fee_receiver = fee_receiver.inner();
+            let deposit_instruction = spl_token::instruction::transfer(
+                &spl_token::ID,
+                vault.key,
+                fee_receiver.key,
+                &vault_signer.inner().key,
+                &[],
+                amount,
+            )?;
+            let accounts: &[AccountInfo] = &[
+                vault.clone(),
+                fee_receiver.clone(),
+                vault_signer.inner().clone(),
+                spl_token_program.inner().clone(),
+            ];
+            invoke_spl_token(
+                &deposit_instruction,
+                &accounts[..],
+                &[&vault_signer_seeds[..]],
+            )
+            .map_err(|_| DexErrorCode::TransferFailed)?;
+        }
+        Ok(())
+    }
 
-        // initialize request queue
+    #[inline(never)]
+    fn init_request_queue(req_q: &AccountInfo) -> DexResult {
         let mut rq_data = req_q.try_borrow_mut_data()?;
         const RQ_HEADER_WORDS: usize = size_of::<RequestQueueHeader>() / size_of::<u64>();
         let rq_view = init_account_padding(&mut rq_data)?;
@@ -2313,7 +4374,10 @@ impl State {
             count: 0,
             next_seq_num: 0,
         };
-        // initialize event queue
+        Ok(())
+    }
+
+    fn init_event_queue(event_q: &AccountInfo) -> DexResult {
         let mut eq_data = event_q.try_borrow_mut_data().unwrap();
         const EQ_HEADER_WORDS: usize = size_of::<EventQueueHeader>() / size_of::<u64>();
         let eq_view = init_account_padding(&mut eq_data)?;
@@ -2332,21 +4396,76 @@ impl State {
             count: 0,
             seq_num: 0,
         };
-        // initialize orderbook storage
-        for (flag, account) in &[(AccountFlag::Bids, bids), (AccountFlag::Asks, asks)] {
-            let mut ob_data = account.try_borrow_mut_data().unwrap();
-            let ob_view = init_account_padding(&mut ob_data)?;
-            const OB_HEADER_WORDS: usize = size_of::<OrderBookStateHeader>() / size_of::<u64>();
-            check_assert!(ob_view.len() > OB_HEADER_WORDS)?;
-            let (hdr_array, slab_words) = mut_array_refs![ob_view, OB_HEADER_WORDS; .. ;];
-            let ob_hdr: &mut OrderBookStateHeader =
-                try_cast_mut(hdr_array).or(check_unreachable!())?;
-            *ob_hdr = OrderBookStateHeader {
-                account_flags: (AccountFlag::Initialized | *flag).bits(),
-            };
-            let slab = Slab::new(cast_slice_mut(slab_words));
-            slab.assert_minimum_capacity(100)?;
+        Ok(())
+    }
+
+    fn init_order_book(account: &AccountInfo, flag: AccountFlag) -> DexResult {
+        let mut ob_data = account.try_borrow_mut_data().unwrap();
+        let ob_view = init_account_padding(&mut ob_data)?;
+        const OB_HEADER_WORDS: usize = size_of::<OrderBookStateHeader>() / size_of::<u64>();
+        check_assert!(ob_view.len() > OB_HEADER_WORDS)?;
+        let (hdr_array, slab_words) = mut_array_refs![ob_view, OB_HEADER_WORDS; .. ;];
+        let ob_hdr: &mut OrderBookStateHeader = try_cast_mut(hdr_array).or(check_unreachable!())?;
+        *ob_hdr = OrderBookStateHeader {
+            account_flags: (AccountFlag::Initialized | flag).bits(),
+        };
+        let slab = Slab::new(cast_slice_mut(slab_words));
+        slab.assert_minimum_capacity(100)?;
+        Ok(())
+    }
+
+    fn process_initialize_market(args: account_parser::InitializeMarketArgs) -> DexResult {
+        let &InitializeMarketInstruction {
+            coin_lot_size,
+            pc_lot_size,
+            fee_rate_bps,
+            vault_signer_nonce,
+            pc_dust_threshold,
+            fee_schedule,
+            fee_discounts,
+            book_full_policy,
+            book_full_notional_threshold,
+        } = args.instruction;
+        if !fee_schedule.is_valid()
+            || (fee_rate_bps != 0 && fee_rate_bps != fee_schedule.taker_bps[0])
+        {
+            Err(DexErrorCode::InvalidFeeSchedule)?
+        }
+        if !fee_discounts.is_valid() {
+            Err(DexErrorCode::InvalidFeeDiscounts)?
+        }
+
+        let market = args.get_market();
+        let req_q = args.get_req_q();
+        let event_q = args.get_event_q();
+        let bids = args.get_bids();
+        let asks = args.get_asks();
+        let trigger_book = args.get_trigger_book();
+        let coin_vault = args.coin_vault_and_mint.get_account().inner();
+        let coin_mint = args.coin_vault_and_mint.get_mint().inner();
+        let pc_vault = args.pc_vault_and_mint.get_account().inner();
+        let pc_mint = args.pc_vault_and_mint.get_mint().inner();
+
+        Self::init_request_queue(req_q)?;
+        Self::init_event_queue(event_q)?;
+        Self::init_order_book(bids, AccountFlag::Bids)?;
+        Self::init_order_book(asks, AccountFlag::Asks)?;
+        // initialize trigger book
+        let mut tb_data = trigger_book.try_borrow_mut_data()?;
+        const TB_HEADER_WORDS: usize = size_of::<TriggerBookHeader>() / size_of::<u64>();
+        let tb_view = init_account_padding(&mut tb_data)?;
+        check_assert!(tb_view.len() > TB_HEADER_WORDS)?;
+        let (tb_hdr_array, tb_buf_words) = mut_array_refs![tb_view, TB_HEADER_WORDS; .. ;];
+        let tb_buf: &[StopOrder] = remove_slop(cast_slice(tb_buf_words));
+        if tb_buf.is_empty() {
+            Err(DexErrorCode::TriggerBookTooSmall)?
         }
+        let tb_hdr: &mut TriggerBookHeader =
+            try_cast_mut(tb_hdr_array).or(check_unreachable!())?;
+        *tb_hdr = TriggerBookHeader {
+            account_flags: (AccountFlag::Initialized | AccountFlag::TriggerBook).bits(),
+            count: 0,
+        };
         info!("Initializing market...");
         // initialize market
         let mut market_data = market.try_borrow_mut_data()?;
@@ -2368,6 +4487,8 @@ impl State {
             event_q: event_q.key.to_aligned_bytes(),
             bids: bids.key.to_aligned_bytes(),
             asks: asks.key.to_aligned_bytes(),
+            trigger_book: trigger_book.key.to_aligned_bytes(),
+            last_traded_price: 0,
 
             pc_mint: pc_mint.key.to_aligned_bytes(),
             pc_vault: pc_vault.key.to_aligned_bytes(),
@@ -2376,7 +4497,19 @@ impl State {
             vault_signer_nonce,
 
             pc_dust_threshold,
-            fee_rate_bps: fee_rate_bps as u64,
+            fee_rate_bps: fee_schedule.taker_bps[0] as u64,
+            fee_schedule,
+            fee_discounts,
+            fee_split: FeeSplit::zeroed(),
+            fee_epoch: 0,
+            crank_reward: CrankReward::zeroed(),
+            crank_reward_epoch: 0,
+            crank_reward_paid: 0,
+            book_full_policy: u8::from(book_full_policy) as u64,
+            book_full_notional_threshold,
+            authority: args.authority.key.to_aligned_bytes(),
+            pending_authority: [0; 4],
+            mode: u8::from(MarketMode::Active) as u64,
         };
         Ok(())
     }
@@ -1,3 +1,4 @@
+use std::convert::TryInto;
 use std::mem::size_of;
 use std::num::NonZeroU64;
 
@@ -5,7 +6,7 @@ use bumpalo::{collections::Vec as BumpVec, vec as bump_vec, Bump};
 use rand::prelude::*;
 use safe_transmute::to_bytes::{transmute_to_bytes, transmute_to_bytes_mut};
 use solana_sdk::bpf_loader;
-use solana_sdk::clock::Epoch;
+use solana_sdk::clock::{Clock, Epoch};
 use solana_sdk::rent::Rent;
 use solana_sdk::system_program;
 use solana_sdk::sysvar;
@@ -13,10 +14,15 @@ use solana_sdk::sysvar::Sysvar;
 use spl_token::pack::Pack;
 use spl_token::state::{Account, AccountState, Mint};
 
-use instruction::{initialize_market, MarketInstruction, NewOrderInstruction};
-use matching::{OrderType, Side};
+use error::{DexError, DexErrorCode, DexResult};
+use fees::{CrankReward, FeeDiscounts, FeeFlag, FeeSchedule, FeeSplit};
+use instruction::{
+    initialize_market, CancelAllOrdersInstruction, MarketInstruction, NewOrderInstruction,
+    NewOrderInstructionV2, ReplaceOrderInstruction, ReplaceTarget,
+};
+use matching::{BookFullPolicy, MarketMode, OrderType, SelfTradeBehavior, Side};
 use state::gen_vault_signer_key;
-use state::{MarketState, OpenOrders, State, ToAlignedBytes};
+use state::{AccountFlag, MarketState, OpenOrders, State, ToAlignedBytes};
 
 use super::*;
 
@@ -30,11 +36,15 @@ struct MarketAccounts<'bump> {
     event_q: AccountInfo<'bump>,
     bids: AccountInfo<'bump>,
     asks: AccountInfo<'bump>,
+    trigger_book: AccountInfo<'bump>,
     coin_vault: AccountInfo<'bump>,
     pc_vault: AccountInfo<'bump>,
+    vault_signer: AccountInfo<'bump>,
     coin_mint: AccountInfo<'bump>,
     pc_mint: AccountInfo<'bump>,
     rent_sysvar: AccountInfo<'bump>,
+    clock_sysvar: AccountInfo<'bump>,
+    authority: AccountInfo<'bump>,
 }
 
 fn allocate_dex_owned_account(unpadded_size: usize, bump: &Bump) -> &mut [u8] {
@@ -69,6 +79,26 @@ fn new_rent_sysvar_account<'bump>(
     account_info
 }
 
+fn new_clock_sysvar_account<'bump>(
+    lamports: u64,
+    clock: Clock,
+    bump: &'bump Bump,
+) -> AccountInfo<'bump> {
+    let data = bump_vec![in bump; 0u8; size_of::<Clock>()].into_bump_slice_mut();
+    let mut account_info = AccountInfo::new(
+        &sysvar::clock::ID,
+        false,
+        false,
+        bump.alloc(lamports),
+        data,
+        &sysvar::ID,
+        false,
+        Epoch::default(),
+    );
+    clock.to_account_info(&mut account_info).unwrap();
+    account_info
+}
+
 fn new_sol_account<'bump, Gen: Rng>(
     rng: &mut Gen,
     lamports: u64,
@@ -165,11 +195,14 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
     let asks = new_dex_owned_account(rng, 1 << 23, program_id, bump);
     let req_q = new_dex_owned_account(rng, 640, program_id, bump);
     let event_q = new_dex_owned_account(rng, 65536, program_id, bump);
+    let trigger_book = new_dex_owned_account(rng, 65536, program_id, bump);
+    let authority = new_sol_account(rng, 0, bump);
 
     let coin_mint = new_token_mint(rng, bump);
     let pc_mint = new_token_mint(rng, bump);
 
     let rent_sysvar = new_rent_sysvar_account(100000, Rent::default(), bump);
+    let clock_sysvar = new_clock_sysvar_account(100000, Clock::default(), bump);
 
     let mut i = 0;
     let (vault_signer_nonce, vault_signer_pk) = loop {
@@ -182,6 +215,16 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
 
     let coin_vault = new_token_account(rng, &coin_mint.key, vault_signer_pk, bump);
     let pc_vault = new_token_account(rng, &pc_mint.key, vault_signer_pk, bump);
+    let vault_signer = AccountInfo::new(
+        vault_signer_pk,
+        false,
+        false,
+        bump.alloc(0),
+        &mut [],
+        &system_program::ID,
+        false,
+        Epoch::default(),
+    );
 
     let coin_lot_size = 1_000;
     let pc_lot_size = 1;
@@ -199,10 +242,16 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
         &asks.key,
         &req_q.key,
         &event_q.key,
+        &trigger_book.key,
+        &authority.key,
         coin_lot_size,
         pc_lot_size,
         vault_signer_nonce,
         pc_dust_threshold,
+        FeeSchedule::default(),
+        FeeDiscounts::default(),
+        BookFullPolicy::EvictWorstPrice,
+        0,
     )
     .unwrap();
 
@@ -217,6 +266,8 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
             pc_vault.clone(),
             coin_mint.clone(),
             pc_mint.clone(),
+            trigger_book.clone(),
+            authority.clone(),
         ]
         .into_bump_slice_mut();
         State::process(&program_id, accounts, &init_instruction.data).unwrap();
@@ -228,11 +279,292 @@ fn setup_market<'bump, R: Rng>(rng: &mut R, bump: &'bump Bump) -> MarketAccounts
         event_q,
         bids,
         asks,
+        trigger_book,
         coin_vault,
         pc_vault,
+        vault_signer,
         coin_mint,
         pc_mint,
         rent_sysvar,
+        clock_sysvar,
+        authority,
+    }
+}
+
+/// An owner with an OpenOrders account and wallets for both currencies.
+struct Trader<'bump> {
+    owner: AccountInfo<'bump>,
+    open_orders: AccountInfo<'bump>,
+    coin_wallet: AccountInfo<'bump>,
+    pc_wallet: AccountInfo<'bump>,
+}
+
+fn new_trader<'bump, R: Rng>(
+    rng: &mut R,
+    accounts: &MarketAccounts<'bump>,
+    bump: &'bump Bump,
+) -> Trader<'bump> {
+    let owner = new_sol_account(rng, 1_000_000_000, bump);
+    let open_orders =
+        new_dex_owned_account(rng, size_of::<OpenOrders>(), accounts.market.owner, bump);
+    let coin_wallet = new_token_account(rng, accounts.coin_mint.key, owner.key, bump);
+    let pc_wallet = new_token_account(rng, accounts.pc_mint.key, owner.key, bump);
+    Trader {
+        owner,
+        open_orders,
+        coin_wallet,
+        pc_wallet,
+    }
+}
+
+/// A limit order that never expires and is shown in full.
+fn limit_order(side: Side, price: u64, qty: u64, client_id: u64) -> NewOrderInstructionV2 {
+    NewOrderInstruction {
+        side,
+        limit_price: NonZeroU64::new(price).unwrap(),
+        max_qty: NonZeroU64::new(qty).unwrap(),
+        order_type: OrderType::Limit,
+        client_id,
+    }
+    .add_self_trade_behavior(SelfTradeBehavior::DecrementTake)
+}
+
+impl<'bump> MarketAccounts<'bump> {
+    /// Sends an instruction that takes the accounts of `NewOrder`, paying
+    /// from the trader's wallet for `side`, followed by `extra_accounts`.
+    fn send_order(
+        &self,
+        trader: &Trader<'bump>,
+        side: Side,
+        instruction: MarketInstruction,
+        extra_accounts: &[AccountInfo<'bump>],
+        bump: &'bump Bump,
+    ) -> DexResult {
+        let payer = match side {
+            Side::Bid => &trader.pc_wallet,
+            Side::Ask => &trader.coin_wallet,
+        };
+        let mut instruction_accounts = bump_vec![in bump;
+            self.market.clone(),
+            trader.open_orders.clone(),
+            self.req_q.clone(),
+            payer.clone(),
+            trader.owner.clone(),
+            self.coin_vault.clone(),
+            self.pc_vault.clone(),
+            new_spl_token_program(bump),
+            self.rent_sysvar.clone(),
+        ];
+        instruction_accounts.extend(extra_accounts.iter().cloned());
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &instruction.pack(),
+        )
+    }
+
+    fn new_order(&self, trader: &Trader<'bump>, order: NewOrderInstructionV2, bump: &'bump Bump) {
+        let side = order.side;
+        let instruction = MarketInstruction::NewOrderV2(order);
+        self.send_order(trader, side, instruction, &[], bump)
+            .unwrap();
+    }
+
+    // the fee receivable accounts of `MatchOrders` and `ConsumeEvents` are
+    // unused, so the vaults stand in for them
+    fn match_orders(&self, limit: u16, bump: &'bump Bump) {
+        let instruction_accounts = bump_vec![in bump;
+            self.market.clone(),
+            self.req_q.clone(),
+            self.event_q.clone(),
+            self.bids.clone(),
+            self.asks.clone(),
+            self.coin_vault.clone(),
+            self.pc_vault.clone(),
+            self.clock_sysvar.clone(),
+            self.trigger_book.clone(),
+        ];
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &MarketInstruction::MatchOrders(limit).pack(),
+        )
+        .unwrap();
+    }
+
+    fn match_orders_with_reward(
+        &self,
+        limit: u16,
+        clock: &AccountInfo<'bump>,
+        reward_wallet: &AccountInfo<'bump>,
+        bump: &'bump Bump,
+    ) {
+        let instruction_accounts = bump_vec![in bump;
+            self.market.clone(),
+            self.req_q.clone(),
+            self.event_q.clone(),
+            self.bids.clone(),
+            self.asks.clone(),
+            self.coin_vault.clone(),
+            self.pc_vault.clone(),
+            clock.clone(),
+            self.trigger_book.clone(),
+            self.pc_vault.clone(),
+            self.vault_signer.clone(),
+            new_spl_token_program(bump),
+            reward_wallet.clone(),
+        ];
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &MarketInstruction::MatchOrders(limit).pack(),
+        )
+        .unwrap();
+    }
+
+    fn consume_events(&self, traders: &[&Trader<'bump>], bump: &'bump Bump) {
+        let mut instruction_accounts: BumpVec<AccountInfo> = BumpVec::new_in(bump);
+        instruction_accounts.extend(traders.iter().map(|trader| trader.open_orders.clone()));
+        instruction_accounts.sort_by_key(|account_info| account_info.key.to_aligned_bytes());
+        instruction_accounts.extend(vec![
+            self.market.clone(),
+            self.event_q.clone(),
+            self.coin_vault.clone(),
+            self.pc_vault.clone(),
+        ]);
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &MarketInstruction::ConsumeEvents(200).pack(),
+        )
+        .unwrap();
+    }
+
+    fn settle_funds(
+        &self,
+        trader: &Trader<'bump>,
+        referrer: Option<&AccountInfo<'bump>>,
+        bump: &'bump Bump,
+    ) -> DexResult {
+        let mut instruction_accounts = bump_vec![in bump;
+            self.market.clone(),
+            trader.open_orders.clone(),
+            trader.owner.clone(),
+            self.coin_vault.clone(),
+            self.pc_vault.clone(),
+            trader.coin_wallet.clone(),
+            trader.pc_wallet.clone(),
+            self.vault_signer.clone(),
+            new_spl_token_program(bump),
+        ];
+        instruction_accounts.extend(referrer.cloned());
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &MarketInstruction::SettleFunds.pack(),
+        )
+    }
+
+    fn close_open_orders(&self, trader: &Trader<'bump>, bump: &'bump Bump) -> DexResult {
+        let instruction_accounts = bump_vec![in bump;
+            trader.open_orders.clone(),
+            trader.owner.clone(),
+            trader.owner.clone(),
+            self.market.clone(),
+        ];
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &MarketInstruction::CloseOpenOrders.pack(),
+        )
+    }
+
+    /// Sweeps the fees accrued in `vault`'s currency to `fee_receivers`.
+    fn sweep_fees(
+        &self,
+        vault: &AccountInfo<'bump>,
+        fee_receivers: &[AccountInfo<'bump>],
+        bump: &'bump Bump,
+    ) -> DexResult {
+        let (first_receiver, other_receivers) = fee_receivers.split_first().unwrap();
+        let mut instruction_accounts = bump_vec![in bump;
+            self.market.clone(),
+            vault.clone(),
+            self.authority.clone(),
+            first_receiver.clone(),
+            self.vault_signer.clone(),
+            new_spl_token_program(bump),
+        ];
+        instruction_accounts.extend(other_receivers.iter().cloned());
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &MarketInstruction::SweepFees.pack(),
+        )
+    }
+
+    fn cancel_all_orders(
+        &self,
+        trader: &Trader<'bump>,
+        side: Option<Side>,
+        limit: u16,
+        bump: &'bump Bump,
+    ) -> DexResult {
+        let instruction_accounts = bump_vec![in bump;
+            self.market.clone(),
+            trader.open_orders.clone(),
+            trader.owner.clone(),
+            self.req_q.clone(),
+            self.event_q.clone(),
+            self.bids.clone(),
+            self.asks.clone(),
+            self.trigger_book.clone(),
+            self.clock_sysvar.clone(),
+        ];
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &MarketInstruction::CancelAllOrders(CancelAllOrdersInstruction { side, limit }).pack(),
+        )
+    }
+
+    /// Moves the book and queues to `new_accounts`, given in the same order
+    /// as bids, asks, request queue and event queue.
+    fn migrate_books(
+        &self,
+        new_accounts: &[AccountInfo<'bump>],
+        signer: &AccountInfo<'bump>,
+        bump: &'bump Bump,
+    ) -> DexResult {
+        let mut instruction_accounts = bump_vec![in bump;
+            self.market.clone(),
+            self.bids.clone(),
+            self.asks.clone(),
+            self.req_q.clone(),
+            self.event_q.clone(),
+        ];
+        instruction_accounts.extend(new_accounts.iter().cloned());
+        instruction_accounts.push(signer.clone());
+        State::process(
+            self.market.owner,
+            &instruction_accounts,
+            &MarketInstruction::MigrateBooks.pack(),
+        )
+    }
+
+    fn set_market_mode(&self, mode: MarketMode, signer: &AccountInfo<'bump>) -> DexResult {
+        State::process(
+            self.market.owner,
+            &[self.market.clone(), signer.clone()],
+            &MarketInstruction::SetMarketMode(mode).pack(),
+        )
+    }
+
+    fn open_orders(&self, trader: &Trader<'bump>) -> OpenOrders {
+        *MarketState::load(&self.market, self.market.owner)
+            .unwrap()
+            .load_orders_mut(&trader.open_orders, None, self.market.owner, None)
+            .unwrap()
     }
 }
 
@@ -296,6 +628,8 @@ fn test_new_order() {
             accounts.asks.clone(),
             coin_account.clone(),
             pc_account.clone(),
+            accounts.clock_sysvar.clone(),
+            accounts.trigger_book.clone(),
         ]
         .into_bump_slice(),
         &instruction_data,
@@ -341,6 +675,8 @@ fn test_new_order() {
             accounts.asks.clone(),
             coin_account.clone(),
             pc_account.clone(),
+            accounts.clock_sysvar.clone(),
+            accounts.trigger_book.clone(),
         ]
         .into_bump_slice(),
         &instruction_data,
@@ -409,3 +745,895 @@ fn test_new_order() {
         assert_eq!(open_orders_seller.native_pc_total, 399_120);
     }
 }
+
+#[test]
+fn test_match_orders_requires_clock_and_trigger_book() {
+    let mut rng = StdRng::seed_from_u64(2);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let dex_program_id = accounts.market.owner;
+    let owner = new_sol_account(&mut rng, 1_000_000_000, &bump);
+    let coin_account = new_token_account(&mut rng, accounts.coin_mint.key, owner.key, &bump);
+    let pc_account = new_token_account(&mut rng, accounts.pc_mint.key, owner.key, &bump);
+
+    let instruction_data = MarketInstruction::MatchOrders(1).pack();
+    let mut instruction_accounts = bump_vec![in &bump;
+        accounts.market.clone(),
+        accounts.req_q.clone(),
+        accounts.event_q.clone(),
+        accounts.bids.clone(),
+        accounts.asks.clone(),
+        coin_account.clone(),
+        pc_account.clone(),
+    ];
+    // without the clock, expired orders would match as live ones
+    assert!(State::process(dex_program_id, &instruction_accounts, &instruction_data).is_err());
+
+    instruction_accounts.push(accounts.clock_sysvar.clone());
+    // without the trigger book, a cancel of a stop order would find nothing
+    assert!(State::process(dex_program_id, &instruction_accounts, &instruction_data).is_err());
+
+    instruction_accounts.push(accounts.trigger_book.clone());
+    State::process(dex_program_id, &instruction_accounts, &instruction_data).unwrap();
+}
+
+#[test]
+fn test_replace_missing_order() {
+    let mut rng = StdRng::seed_from_u64(3);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    let replace = |place_if_missing| {
+        MarketInstruction::ReplaceOrder(ReplaceOrderInstruction {
+            order: limit_order(Side::Ask, 100, 5, 2),
+            place_if_missing,
+            target: ReplaceTarget::ClientId(1),
+        })
+    };
+    let books = [accounts.bids.clone(), accounts.asks.clone()];
+
+    let result = accounts.send_order(&bob, Side::Ask, replace(false), &books, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::OrderNotFound))
+    ));
+
+    accounts
+        .send_order(&bob, Side::Ask, replace(true), &books, &bump)
+        .unwrap();
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&bob], &bump);
+    let open_orders = accounts.open_orders(&bob);
+    assert_eq!(open_orders.native_coin_total, 5_000);
+    assert_eq!(open_orders.native_coin_free, 0);
+    assert!(open_orders.client_order_ids.contains(&2));
+}
+
+#[test]
+fn test_replace_partially_filled_order() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&bob, limit_order(Side::Ask, 100, 10, 1), &bump);
+    accounts.match_orders(10, &bump);
+
+    // the replace is matched after a bid that takes part of the order
+    accounts.new_order(&alice, limit_order(Side::Bid, 100, 4, 0), &bump);
+    accounts
+        .send_order(
+            &bob,
+            Side::Ask,
+            MarketInstruction::ReplaceOrder(ReplaceOrderInstruction {
+                order: limit_order(Side::Ask, 101, 10, 2),
+                place_if_missing: false,
+                target: ReplaceTarget::ClientId(1),
+            }),
+            &[accounts.bids.clone(), accounts.asks.clone()],
+            &bump,
+        )
+        .unwrap();
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&alice, &bob], &bump);
+
+    // the new order keeps only what the old one had left
+    let open_orders = accounts.open_orders(&bob);
+    assert_eq!(open_orders.native_coin_total, 6_000);
+    assert_eq!(open_orders.native_coin_free, 0);
+    assert!(!open_orders.client_order_ids.contains(&1));
+    assert!(open_orders.client_order_ids.contains(&2));
+    let alice_open_orders = accounts.open_orders(&alice);
+    assert_eq!(alice_open_orders.native_coin_free, 4_000);
+}
+
+#[test]
+fn test_replace_order_on_other_side() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 99, 5, 1), &bump);
+    accounts.match_orders(10, &bump);
+    let native_pc_locked = accounts.open_orders(&bob).native_pc_total;
+
+    // nothing carries over, so the ask is paid for in full
+    accounts
+        .send_order(
+            &bob,
+            Side::Ask,
+            MarketInstruction::ReplaceOrder(ReplaceOrderInstruction {
+                order: limit_order(Side::Ask, 101, 5, 2),
+                place_if_missing: false,
+                target: ReplaceTarget::ClientId(1),
+            }),
+            &[accounts.bids.clone(), accounts.asks.clone()],
+            &bump,
+        )
+        .unwrap();
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&bob], &bump);
+
+    let open_orders = accounts.open_orders(&bob);
+    assert_eq!(open_orders.native_pc_free, native_pc_locked);
+    assert_eq!(open_orders.native_pc_total, native_pc_locked);
+    assert_eq!(open_orders.native_coin_total, 5_000);
+    assert_eq!(open_orders.native_coin_free, 0);
+    assert_eq!(open_orders.is_bid_bits, 0);
+}
+
+#[test]
+fn test_cancel_all_orders_frees_funds_right_away() {
+    let mut rng = StdRng::seed_from_u64(6);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 99, 5, 1), &bump);
+    accounts.new_order(&bob, limit_order(Side::Ask, 101, 3, 2), &bump);
+    accounts.match_orders(10, &bump);
+    // not matched yet, so its cancel has to be queued behind it
+    accounts.new_order(&bob, limit_order(Side::Ask, 102, 4, 3), &bump);
+
+    accounts.cancel_all_orders(&bob, None, 10, &bump).unwrap();
+    accounts.consume_events(&[&bob], &bump);
+    let open_orders = accounts.open_orders(&bob);
+    assert_eq!(open_orders.native_pc_free, open_orders.native_pc_total);
+    assert_eq!(open_orders.native_coin_free, 3_000);
+    assert_eq!(open_orders.native_coin_total, 7_000);
+
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&bob], &bump);
+    let open_orders = accounts.open_orders(&bob);
+    assert_eq!(open_orders.native_coin_free, 7_000);
+    assert_eq!(open_orders.free_slot_bits, !0);
+}
+
+#[test]
+fn test_cancel_all_orders_respects_side_and_limit() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 99, 5, 1), &bump);
+    accounts.new_order(&bob, limit_order(Side::Ask, 101, 3, 2), &bump);
+    accounts.new_order(&bob, limit_order(Side::Ask, 102, 4, 3), &bump);
+    accounts.match_orders(10, &bump);
+
+    accounts
+        .cancel_all_orders(&bob, Some(Side::Ask), 1, &bump)
+        .unwrap();
+    accounts.consume_events(&[&bob], &bump);
+    let open_orders = accounts.open_orders(&bob);
+    assert!(open_orders.client_order_ids.contains(&1));
+    assert!(!open_orders.client_order_ids.contains(&2));
+    assert!(open_orders.client_order_ids.contains(&3));
+    assert_eq!(open_orders.native_coin_free, 3_000);
+}
+
+#[test]
+fn test_new_orders_locks_the_sum_of_its_orders() {
+    let mut rng = StdRng::seed_from_u64(8);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    let orders = vec![
+        limit_order(Side::Bid, 97, 3, 1),
+        limit_order(Side::Bid, 98, 4, 2),
+        limit_order(Side::Bid, 99, 5, 3),
+    ];
+    for order in &orders {
+        accounts.new_order(&alice, order.clone(), &bump);
+    }
+    accounts.match_orders(10, &bump);
+    accounts
+        .send_order(
+            &bob,
+            Side::Bid,
+            MarketInstruction::NewOrders(orders),
+            &[],
+            &bump,
+        )
+        .unwrap();
+
+    let alice_open_orders = accounts.open_orders(&alice);
+    let bob_open_orders = accounts.open_orders(&bob);
+    assert_ne!(bob_open_orders.native_pc_total, 0);
+    assert_eq!(
+        bob_open_orders.native_pc_total,
+        alice_open_orders.native_pc_total
+    );
+    assert_eq!(bob_open_orders.native_pc_free, 0);
+    assert_eq!(bob_open_orders.free_slot_bits.count_zeros(), 3);
+
+    let result = accounts.send_order(
+        &bob,
+        Side::Bid,
+        MarketInstruction::NewOrders(vec![
+            limit_order(Side::Bid, 99, 1, 4),
+            limit_order(Side::Ask, 101, 1, 5),
+        ]),
+        &[],
+        &bump,
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::NewOrdersSideMismatch))
+    ));
+}
+
+#[test]
+fn test_referrer_is_paid_on_settle() {
+    let mut rng = StdRng::seed_from_u64(9);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    MarketState::load(&accounts.market, accounts.market.owner)
+        .unwrap()
+        .fee_schedule
+        .referrer_share_bps = 5_000;
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    let referrer_owner = new_sol_account(&mut rng, 0, &bump);
+    let referrer = new_token_account(&mut rng, accounts.pc_mint.key, referrer_owner.key, &bump);
+
+    accounts.new_order(&alice, limit_order(Side::Ask, 10_000, 5, 1), &bump);
+    let bid = MarketInstruction::NewOrderV2(limit_order(Side::Bid, 10_000, 5, 2));
+    let result = accounts.send_order(
+        &bob,
+        Side::Bid,
+        bid.clone(),
+        &[bob.pc_wallet.clone(), bob.pc_wallet.clone()],
+        &bump,
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongReferrer))
+    ));
+    // without a fee discount account the referrer's wallet comes first
+    accounts
+        .send_order(&bob, Side::Bid, bid, &[referrer.clone()], &bump)
+        .unwrap();
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&alice, &bob], &bump);
+
+    // half of the 110 taker fee goes to the referrer and the maker is
+    // rebated 15
+    let open_orders = accounts.open_orders(&bob);
+    assert_eq!(open_orders.referrer, referrer.key.to_aligned_bytes());
+    assert_eq!(open_orders.referrer_rebates_accrued, 55);
+    {
+        let market = MarketState::load(&accounts.market, accounts.market.owner).unwrap();
+        assert_eq!(market.pc_fees_accrued, 40);
+    }
+
+    let result = accounts.settle_funds(&bob, None, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongReferrer))
+    ));
+    let result = accounts.settle_funds(&bob, Some(&bob.pc_wallet), &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongReferrer))
+    ));
+    accounts.settle_funds(&bob, Some(&referrer), &bump).unwrap();
+    assert_eq!(accounts.open_orders(&bob).referrer_rebates_accrued, 0);
+    accounts.close_open_orders(&bob, &bump).unwrap();
+}
+
+#[test]
+fn test_close_open_orders_keeps_unpaid_referrer_rebates() {
+    let mut rng = StdRng::seed_from_u64(10);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 99, 5, 1), &bump);
+    accounts.match_orders(10, &bump);
+    accounts.cancel_all_orders(&bob, None, 1, &bump).unwrap();
+    accounts.consume_events(&[&bob], &bump);
+    accounts.settle_funds(&bob, None, &bump).unwrap();
+    MarketState::load(&accounts.market, accounts.market.owner)
+        .unwrap()
+        .load_orders_mut(&bob.open_orders, None, accounts.market.owner, None)
+        .unwrap()
+        .referrer_rebates_accrued = 1;
+
+    let result = accounts.close_open_orders(&bob, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::ReferrerInUse))
+    ));
+}
+
+#[test]
+fn test_sweep_coin_fees() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    MarketState::load(&accounts.market, accounts.market.owner)
+        .unwrap()
+        .fee_schedule
+        .fee_flags = FeeFlag::CoinFeesOnAsks as u16;
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&alice, limit_order(Side::Bid, 100, 50, 1), &bump);
+    accounts.match_orders(10, &bump);
+    accounts.new_order(&bob, limit_order(Side::Ask, 100, 50, 2), &bump);
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&alice, &bob], &bump);
+
+    let (coin_fees_accrued, coin_deposits_total, pc_fees_accrued) = {
+        let market = MarketState::load(&accounts.market, accounts.market.owner).unwrap();
+        (
+            market.coin_fees_accrued,
+            market.coin_deposits_total,
+            market.pc_fees_accrued,
+        )
+    };
+    assert_ne!(coin_fees_accrued, 0);
+    // the taker's coin fee isn't part of anyone's deposits any more
+    let alice_open_orders = accounts.open_orders(&alice);
+    let bob_open_orders = accounts.open_orders(&bob);
+    assert_eq!(
+        coin_deposits_total,
+        alice_open_orders.native_coin_total + bob_open_orders.native_coin_total
+    );
+
+    // the fees are paid in the vault's currency
+    let result = accounts.sweep_fees(&accounts.coin_vault, &[alice.pc_wallet.clone()], &bump);
+    assert!(result.is_err());
+    accounts
+        .sweep_fees(&accounts.coin_vault, &[alice.coin_wallet.clone()], &bump)
+        .unwrap();
+    let market = MarketState::load(&accounts.market, accounts.market.owner).unwrap();
+    assert_eq!(market.coin_fees_accrued, 0);
+    assert_eq!(market.coin_deposits_total, coin_deposits_total);
+    assert_eq!(market.pc_fees_accrued, pc_fees_accrued);
+}
+
+#[test]
+fn test_sweep_fees_to_split_recipients() {
+    let mut rng = StdRng::seed_from_u64(12);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    let carol = new_trader(&mut rng, &accounts, &bump);
+    let fee_split = FeeSplit {
+        recipients: [
+            alice.owner.key.to_aligned_bytes(),
+            [0; 4],
+            bob.owner.key.to_aligned_bytes(),
+            carol.owner.key.to_aligned_bytes(),
+        ],
+        shares_bps: [5_000, 0, 3_000, 2_000],
+        dust_recipient: 3,
+    };
+    State::process(
+        accounts.market.owner,
+        &[accounts.market.clone(), accounts.authority.clone()],
+        &MarketInstruction::SetFeeSplit(fee_split).pack(),
+    )
+    .unwrap();
+
+    accounts.new_order(&alice, limit_order(Side::Ask, 10_000, 5, 1), &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 10_000, 5, 2), &bump);
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&alice, &bob], &bump);
+    assert_ne!(
+        MarketState::load(&accounts.market, accounts.market.owner)
+            .unwrap()
+            .pc_fees_accrued,
+        0
+    );
+
+    // one receivable account per recipient with a share, in order
+    let out_of_order = [
+        bob.pc_wallet.clone(),
+        alice.pc_wallet.clone(),
+        carol.pc_wallet.clone(),
+    ];
+    let result = accounts.sweep_fees(&accounts.pc_vault, &out_of_order, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongFeeReceiver))
+    ));
+    let result = accounts.sweep_fees(&accounts.pc_vault, &out_of_order[1..], &bump);
+    assert!(result.is_err());
+    let fee_receivers = [
+        alice.pc_wallet.clone(),
+        bob.pc_wallet.clone(),
+        carol.pc_wallet.clone(),
+    ];
+    accounts
+        .sweep_fees(&accounts.pc_vault, &fee_receivers, &bump)
+        .unwrap();
+    assert_eq!(
+        MarketState::load(&accounts.market, accounts.market.owner)
+            .unwrap()
+            .pc_fees_accrued,
+        0
+    );
+}
+
+#[test]
+fn test_volume_fee_tier() {
+    let mut rng = StdRng::seed_from_u64(13);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    MarketState::load(&accounts.market, accounts.market.owner)
+        .unwrap()
+        .fee_discounts
+        .volume_thresholds = [10_000, 20_000, 30_000, 40_000, 45_000, 1 << 40];
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    let next_epoch = new_clock_sysvar_account(
+        100000,
+        Clock {
+            epoch: 1,
+            ..Clock::default()
+        },
+        &bump,
+    );
+
+    // how much pc a bid for 10 lots at 1_000 locks, taker fee included
+    fn place_bid<'bump>(
+        accounts: &MarketAccounts<'bump>,
+        trader: &Trader<'bump>,
+        clock: &AccountInfo<'bump>,
+        client_id: u64,
+        bump: &'bump Bump,
+    ) -> u64 {
+        let native_pc_locked = || {
+            let open_orders = accounts.open_orders(trader);
+            open_orders.native_pc_total - open_orders.native_pc_free
+        };
+        let before = native_pc_locked();
+        let bid = limit_order(Side::Bid, 1_000, 10, client_id);
+        accounts
+            .send_order(
+                trader,
+                Side::Bid,
+                MarketInstruction::NewOrderV2(bid),
+                &[clock.clone()],
+                bump,
+            )
+            .unwrap();
+        native_pc_locked() - before
+    }
+
+    // an ask far from the book opens bob's account
+    let ask = limit_order(Side::Ask, 100_000, 1, 0);
+    accounts
+        .send_order(
+            &bob,
+            Side::Ask,
+            MarketInstruction::NewOrderV2(ask),
+            &[accounts.clock_sysvar.clone()],
+            &bump,
+        )
+        .unwrap();
+    // 10_000 pc plus the taker fee of the base tier
+    assert_eq!(
+        place_bid(&accounts, &bob, &accounts.clock_sysvar, 1, &bump),
+        10_022
+    );
+    let ask = limit_order(Side::Ask, 1_000, 50, 2);
+    accounts
+        .send_order(
+            &alice,
+            Side::Ask,
+            MarketInstruction::NewOrderV2(ask),
+            &[accounts.clock_sysvar.clone()],
+            &bump,
+        )
+        .unwrap();
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&alice, &bob], &bump);
+
+    // the fill of bob's bid is enough volume for the first tier
+    assert_eq!(accounts.open_orders(&bob).native_pc_volume, 10_000);
+    assert_eq!(
+        place_bid(&accounts, &bob, &accounts.clock_sysvar, 3, &bump),
+        10_020
+    );
+    // the volume doesn't carry over into the next epoch
+    assert_eq!(place_bid(&accounts, &bob, &next_epoch, 4, &bump), 10_022);
+}
+
+#[test]
+fn test_crank_reward_is_capped_per_call_and_epoch() {
+    let mut rng = StdRng::seed_from_u64(14);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let crank_reward = CrankReward {
+        native_pc_per_unit: 1_000,
+        max_fees_share_bps: 100,
+        max_native_pc_per_call: 30,
+        max_native_pc_per_epoch: 50,
+    };
+    State::process(
+        accounts.market.owner,
+        &[accounts.market.clone(), accounts.authority.clone()],
+        &MarketInstruction::SetCrankReward(crank_reward).pack(),
+    )
+    .unwrap();
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    let cranker = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&alice, limit_order(Side::Ask, 10_000, 500, 1), &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 10_000, 500, 2), &bump);
+    accounts.match_orders(10, &bump);
+    let next_epoch = new_clock_sysvar_account(
+        100000,
+        Clock {
+            epoch: 1,
+            ..Clock::default()
+        },
+        &bump,
+    );
+
+    let pc_fees_accrued = || {
+        MarketState::load(&accounts.market, accounts.market.owner)
+            .unwrap()
+            .pc_fees_accrued
+    };
+    assert!(pc_fees_accrued() * 100 / 10_000 > 30);
+    // each call matches one new order and is rewarded for it, until the
+    // budget for the epoch runs out
+    let calls = [
+        (&accounts.clock_sysvar, 30),
+        (&accounts.clock_sysvar, 20),
+        (&accounts.clock_sysvar, 0),
+        (&next_epoch, 30),
+    ];
+    for (client_id, &(clock, reward)) in (3..).zip(&calls) {
+        accounts.new_order(&alice, limit_order(Side::Ask, 20_000, 1, client_id), &bump);
+        let fees_before = pc_fees_accrued();
+        accounts.match_orders_with_reward(10, clock, &cranker.pc_wallet, &bump);
+        assert_eq!(fees_before - pc_fees_accrued(), reward);
+    }
+}
+
+#[test]
+fn test_migrate_books() {
+    let mut rng = StdRng::seed_from_u64(15);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let program_id = accounts.market.owner;
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&alice, limit_order(Side::Ask, 101, 3, 1), &bump);
+    accounts.new_order(&alice, limit_order(Side::Ask, 102, 4, 2), &bump);
+    accounts.new_order(&alice, limit_order(Side::Bid, 99, 5, 3), &bump);
+    accounts.match_orders(10, &bump);
+    // still queued when the books move
+    accounts.new_order(&alice, limit_order(Side::Ask, 103, 1, 4), &bump);
+
+    let new_accounts = [
+        new_dex_owned_account(&mut rng, (1 << 23) + 1024, program_id, &bump),
+        new_dex_owned_account(&mut rng, (1 << 23) + 1024, program_id, &bump),
+        new_dex_owned_account(&mut rng, 1280, program_id, &bump),
+        new_dex_owned_account(&mut rng, 65536 * 2, program_id, &bump),
+    ];
+    let result = accounts.migrate_books(&new_accounts, &bob.owner, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongAuthority))
+    ));
+    let small_event_q = new_dex_owned_account(&mut rng, 1024, program_id, &bump);
+    let result = accounts.migrate_books(
+        &[
+            new_accounts[0].clone(),
+            new_accounts[1].clone(),
+            new_accounts[2].clone(),
+            small_event_q,
+        ],
+        &accounts.authority,
+        &bump,
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::MigrationTargetTooSmall))
+    ));
+
+    let open_orders_before = accounts.open_orders(&alice);
+    accounts
+        .migrate_books(&new_accounts, &accounts.authority, &bump)
+        .unwrap();
+    let old_bids_flags = u64::from_le_bytes(accounts.bids.data.borrow()[5..13].try_into().unwrap());
+    assert_ne!(old_bids_flags & AccountFlag::Disabled as u64, 0);
+    let [bids, asks, req_q, event_q] = new_accounts;
+    let accounts = MarketAccounts {
+        bids,
+        asks,
+        req_q,
+        event_q,
+        ..accounts
+    };
+    let open_orders = accounts.open_orders(&alice);
+    assert_eq!(open_orders.orders, open_orders_before.orders);
+
+    // the queued ask is matched from the new request queue and the resting
+    // asks fill a bid as before
+    accounts.match_orders(10, &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 102, 5, 5), &bump);
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&alice, &bob], &bump);
+    assert_eq!(accounts.open_orders(&bob).native_coin_free, 5_000);
+
+    // and new orders keep drawing ids from the same sequence
+    let open_orders = accounts.open_orders(&alice);
+    let order_seq_num = |client_id| {
+        let slot = open_orders
+            .client_order_ids
+            .iter()
+            .position(|&id| id == client_id)
+            .unwrap();
+        open_orders.orders[slot] as u64
+    };
+    assert!(order_seq_num(4) > order_seq_num(2));
+}
+
+#[test]
+fn test_authority_handover() {
+    let mut rng = StdRng::seed_from_u64(16);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let program_id = accounts.market.owner;
+    let new_authority = new_sol_account(&mut rng, 0, &bump);
+    let stranger = new_sol_account(&mut rng, 0, &bump);
+    let set_authority = MarketInstruction::SetAuthority.pack();
+    let accept_authority = MarketInstruction::AcceptAuthority.pack();
+
+    let result = State::process(
+        program_id,
+        &[
+            accounts.market.clone(),
+            new_authority.clone(),
+            new_authority.clone(),
+        ],
+        &set_authority,
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongAuthority))
+    ));
+    State::process(
+        program_id,
+        &[
+            accounts.market.clone(),
+            accounts.authority.clone(),
+            new_authority.clone(),
+        ],
+        &set_authority,
+    )
+    .unwrap();
+
+    // only the offered key can take over, and until it does the old
+    // authority stays in charge
+    let result = State::process(
+        program_id,
+        &[accounts.market.clone(), stranger.clone()],
+        &accept_authority,
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongAuthority))
+    ));
+    accounts
+        .set_market_mode(MarketMode::Active, &accounts.authority)
+        .unwrap();
+    let result = accounts.set_market_mode(MarketMode::Active, &new_authority);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongAuthority))
+    ));
+
+    State::process(
+        program_id,
+        &[accounts.market.clone(), new_authority.clone()],
+        &accept_authority,
+    )
+    .unwrap();
+    accounts
+        .set_market_mode(MarketMode::Active, &new_authority)
+        .unwrap();
+    let result = accounts.set_market_mode(MarketMode::Active, &accounts.authority);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongAuthority))
+    ));
+    // the offer is used up
+    let result = State::process(
+        program_id,
+        &[accounts.market.clone(), new_authority.clone()],
+        &accept_authority,
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongAuthority))
+    ));
+
+    // renouncing leaves no one in charge
+    State::process(
+        program_id,
+        &[accounts.market.clone(), new_authority.clone()],
+        &set_authority,
+    )
+    .unwrap();
+    let result = accounts.set_market_mode(MarketMode::Active, &new_authority);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::WrongAuthority))
+    ));
+}
+
+#[test]
+fn test_market_modes() {
+    let mut rng = StdRng::seed_from_u64(17);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let alice = new_trader(&mut rng, &accounts, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&alice, limit_order(Side::Ask, 100, 5, 1), &bump);
+    accounts.match_orders(10, &bump);
+
+    // post-only: a crossing bid is dropped instead of taking, and one that
+    // doesn't cross still rests
+    accounts
+        .set_market_mode(MarketMode::PostOnly, &accounts.authority)
+        .unwrap();
+    accounts.new_order(&bob, limit_order(Side::Bid, 100, 2, 2), &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 99, 2, 3), &bump);
+    accounts.match_orders(10, &bump);
+    accounts.consume_events(&[&alice, &bob], &bump);
+    let open_orders = accounts.open_orders(&bob);
+    assert_eq!(open_orders.native_coin_total, 0);
+    assert!(!open_orders.client_order_ids.contains(&2));
+    assert!(open_orders.client_order_ids.contains(&3));
+
+    // cancel-only: new orders are refused, queued ones are dropped when
+    // they come up for matching, and cancels still go through
+    accounts
+        .set_market_mode(MarketMode::Active, &accounts.authority)
+        .unwrap();
+    accounts.new_order(&bob, limit_order(Side::Bid, 100, 2, 4), &bump);
+    accounts
+        .set_market_mode(MarketMode::CancelOnly, &accounts.authority)
+        .unwrap();
+    let result = accounts.send_order(
+        &bob,
+        Side::Bid,
+        MarketInstruction::NewOrderV2(limit_order(Side::Bid, 100, 2, 5)),
+        &[],
+        &bump,
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::MarketIsCancelOnly))
+    ));
+    accounts.match_orders(10, &bump);
+    accounts.cancel_all_orders(&bob, None, 10, &bump).unwrap();
+    accounts.consume_events(&[&alice, &bob], &bump);
+    let open_orders = accounts.open_orders(&bob);
+    assert_eq!(open_orders.native_coin_total, 0);
+    assert_eq!(open_orders.native_pc_free, open_orders.native_pc_total);
+    assert_eq!(open_orders.free_slot_bits, !0);
+
+    // halted: nothing but settlement
+    accounts
+        .set_market_mode(MarketMode::Halted, &accounts.authority)
+        .unwrap();
+    let result = accounts.send_order(
+        &bob,
+        Side::Bid,
+        MarketInstruction::NewOrderV2(limit_order(Side::Bid, 100, 2, 6)),
+        &[],
+        &bump,
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::MarketIsHalted))
+    ));
+    let result = State::process(
+        accounts.market.owner,
+        &[
+            accounts.market.clone(),
+            accounts.req_q.clone(),
+            accounts.event_q.clone(),
+            accounts.bids.clone(),
+            accounts.asks.clone(),
+            accounts.coin_vault.clone(),
+            accounts.pc_vault.clone(),
+            accounts.clock_sysvar.clone(),
+            accounts.trigger_book.clone(),
+        ],
+        &MarketInstruction::MatchOrders(10).pack(),
+    );
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::MarketIsHalted))
+    ));
+    let result = accounts.cancel_all_orders(&alice, None, 10, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::MarketIsHalted))
+    ));
+    accounts.settle_funds(&bob, None, &bump).unwrap();
+}
+
+#[test]
+fn test_close_open_orders() {
+    let mut rng = StdRng::seed_from_u64(18);
+    let bump = Bump::new();
+
+    let accounts = setup_market(&mut rng, &bump);
+    let bob = new_trader(&mut rng, &accounts, &bump);
+    accounts.new_order(&bob, limit_order(Side::Bid, 99, 5, 1), &bump);
+    accounts.match_orders(10, &bump);
+    let result = accounts.close_open_orders(&bob, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::OrdersNotEmpty))
+    ));
+    accounts.cancel_all_orders(&bob, None, 10, &bump).unwrap();
+    accounts.consume_events(&[&bob], &bump);
+    let result = accounts.close_open_orders(&bob, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::OrdersNotEmpty))
+    ));
+    accounts.settle_funds(&bob, None, &bump).unwrap();
+
+    **bob.owner.lamports.borrow_mut() = u64::MAX;
+    let result = accounts.close_open_orders(&bob, &bump);
+    assert!(matches!(
+        result,
+        Err(DexError::ErrorCode(DexErrorCode::LamportsOverflow))
+    ));
+
+    **bob.owner.lamports.borrow_mut() = 1;
+    let open_orders_lamports = bob.open_orders.lamports();
+    accounts.close_open_orders(&bob, &bump).unwrap();
+    assert_eq!(bob.owner.lamports(), open_orders_lamports + 1);
+    assert_eq!(bob.open_orders.lamports(), 0);
+    let data = bob.open_orders.data.borrow();
+    assert!(data[5..data.len() - 7].iter().all(|&b| b == 0));
+}